@@ -1,9 +1,13 @@
+use std::collections::HashMap;
+
 #[derive(Debug, Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct Config {
     pub rpc: Rpc,
     pub verification: Verification,
     pub nodes: Nodes,
+    #[serde(default)]
+    pub submitter: Submitter,
 }
 
 #[derive(Debug, Deserialize)]
@@ -15,19 +19,62 @@ pub struct Rpc {
     pub processing_threads: usize,
     pub db_path: String,
     pub submit_earlier: u64,
+    pub admin_token: Option<String>,
+    pub replica_refresh_interval: Option<u64>,
 }
 
 #[derive(Debug, Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct Verification {
-    pub chain_id: u64,
+    pub chain_id: Option<u64>,
+    #[serde(default)]
+    pub chain_id_from_node: bool,
     pub max_gas: u64,
+    pub max_transaction_size: Option<usize>,
     pub min_gas_price: u64,
+    #[serde(default)]
+    pub gas_price_exempt: Vec<String>,
+    #[serde(default)]
+    pub min_gas_per_target: HashMap<String, u64>,
+    pub tx_gas: Option<u64>,
+    pub tx_create_gas: Option<u64>,
+    pub tx_data_zero_gas: Option<u64>,
+    pub tx_data_non_zero_gas: Option<u64>,
+    pub min_gas_price_percentile: Option<u8>,
+    pub min_tip: Option<u64>,
     pub max_schedule_block: u64,
     pub max_schedule_seconds: u64,
+    #[serde(default)]
+    pub min_block_slack: u64,
+    #[serde(default)]
+    pub min_lead_blocks: u64,
+    #[serde(default)]
+    pub min_lead_seconds: u64,
+    #[serde(default)]
+    pub balance_margin_wei: u64,
+    #[serde(default)]
+    pub balance_margin_percent: u64,
     pub strict_nonce: bool,
+    pub max_nonce_ahead: Option<u64>,
+    #[serde(default)]
+    pub reject_underpriced: bool,
+    #[serde(default)]
+    pub warn_underpriced: bool,
+    #[serde(default)]
+    pub reject_zero_address: bool,
+    #[serde(default)]
+    pub reject_precompiles: bool,
+    #[serde(default)]
+    pub reject_contract_senders: bool,
+    #[serde(default)]
+    pub database_fail_open: bool,
+    pub soft_queue_limit: Option<usize>,
+    pub retry_after: Option<u64>,
+    pub max_requests_per_second: Option<u32>,
     pub max_txs_per_sender: usize,
     pub certifier: Option<String>,
+    #[serde(default)]
+    pub certification_warmup: Vec<String>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -35,6 +82,73 @@ pub struct Verification {
 pub struct Nodes {
     pub blockchain: String,
     pub transactions: Vec<String>,
+    #[serde(default)]
+    pub weights: Vec<u32>,
+    pub rpc_attempts: Option<u32>,
+    pub rpc_retry_backoff_ms: Option<u64>,
+    pub block_tag: Option<BlockTag>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub enum BlockTag {
+    #[serde(rename = "latest")]
+    Latest,
+    #[serde(rename = "pending")]
+    Pending,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Submitter {
+    #[serde(default)]
+    pub try_send: bool,
+    #[serde(default)]
+    pub dry_run: bool,
+    #[serde(default)]
+    pub prioritize: bool,
+    #[serde(default)]
+    pub preferred: bool,
+    pub quorum: Option<usize>,
+    #[serde(default)]
+    pub fifo: bool,
+    #[serde(default)]
+    pub reverify: bool,
+    #[serde(default)]
+    pub fail_on_listener_close: bool,
+    pub reverify_failure: Option<ReverifyFailure>,
+    pub batch_concurrency: Option<usize>,
+    pub max_submit_attempts: Option<u32>,
+    pub dead_letters_capacity: Option<usize>,
+    pub confirmations: Option<u64>,
+    pub receipt_poll_interval: Option<u64>,
+    pub max_tracked: Option<usize>,
+    pub drop_grace_blocks: Option<u64>,
+    pub drop_grace_seconds: Option<u64>,
+    pub max_queue_age: Option<u64>,
+    pub breaker_threshold: Option<u32>,
+    pub breaker_cooldown: Option<u64>,
+    pub max_in_flight: Option<usize>,
+    pub max_drain_batch: Option<usize>,
+    pub reconnect_delay: Option<u64>,
+    pub submission_window: Option<u64>,
+    pub submitted_journal: Option<String>,
+    pub max_transports: Option<usize>,
+    pub timestamp_window: Option<u64>,
+    pub conditional_blocks: Option<u64>,
+    pub send_method: Option<String>,
+    pub metrics_path: Option<String>,
+    pub metrics_persist_interval: Option<u64>,
+    pub audit_interval: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub enum ReverifyFailure {
+    #[serde(rename = "drop")]
+    Drop,
+    #[serde(rename = "log")]
+    Log,
+    #[serde(rename = "dead_letter")]
+    DeadLetter,
 }
 
 #[cfg(test)]
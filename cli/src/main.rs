@@ -5,17 +5,21 @@ extern crate serde_derive;
 
 extern crate docopt;
 extern crate env_logger;
+extern crate parking_lot;
 extern crate toml;
 extern crate transaction_scheduler;
 
 mod config;
 
-use std::{env, thread, path, fs};
+use std::collections::HashMap;
+use std::{env, thread, path, fs, time};
 use std::io::Read;
 use std::sync::Arc;
 
 use docopt::Docopt;
-use transaction_scheduler::{blockchain, database, server, submitter, TransportType, Options};
+use parking_lot::Mutex;
+use transaction_scheduler::clock::Clock;
+use transaction_scheduler::{blockchain, clock, database, dead_letters, metrics, scheduler, server, submitter, tracker, BalanceMargin, GasSchedule, TransportType, Options};
 
 const USAGE: &str = r#"
 Signed Transaction Scheduler
@@ -82,18 +86,15 @@ fn execute<S, I>(command: I) -> Result<String, String> where
     let config: config::Config = toml::from_str(&content)
         .map_err(|e| format!("Invalid config: {}", e))?;
 
-    // Construct options
-    let options = Options {
-        chain_id: config.verification.chain_id,
-        max_gas: config.verification.max_gas,
-        min_gas_price: config.verification.min_gas_price,
-        max_schedule_block: config.verification.max_schedule_block,
-        max_schedule_seconds: config.verification.max_schedule_seconds,
-        strict_nonce: config.verification.strict_nonce,
-        rpc_listen_address: format!("{}:{}", config.rpc.interface, config.rpc.port).parse().map_err(|e| format!("Invalid interface or port: {}", e))?,
-        rpc_server_threads: config.rpc.server_threads,
-        processing_threads: config.rpc.processing_threads,
-    };
+    let gas_price_exempt = config.verification.gas_price_exempt.iter()
+        .map(|address| address.parse().map_err(|e| format!("Unable to parse exempt address {}: {}", address, e)))
+        .collect::<Result<Vec<_>, _>>()?;
+    let min_gas_per_target = config.verification.min_gas_per_target.iter()
+        .map(|(address, gas)| match address.parse() {
+            Ok(address) => Ok((address, *gas)),
+            Err(e) => Err(format!("Unable to parse target address {}: {}", address, e)),
+        })
+        .collect::<Result<HashMap<_, _>, _>>()?;
 
     let blockchain_node_address = config.nodes.blockchain.clone();
     // A certifier contract query interface.
@@ -102,58 +103,269 @@ fn execute<S, I>(command: I) -> Result<String, String> where
         Some(Ok(address)) => Some(address),
         Some(Err(err)) => return Err(format!("Unable to parse certifier address: {}", err)),
     };
+    let certification_warmup = config.verification.certification_warmup.iter()
+        .map(|address| address.parse().map_err(|e| format!("Unable to parse warm-up address {}: {}", address, e)))
+        .collect::<Result<Vec<_>, _>>()?;
     // A cached state of blockchain.
     let blockchain = Arc::new(blockchain::Blockchain::new(&blockchain_node_address, certifier)
         .map_err(|e| format!("Error starting blockchain cache: {:?}", e))?
+        .with_certification_warmup(certification_warmup)
+        .with_retry(blockchain::RetryPolicy {
+            attempts: config.nodes.rpc_attempts.unwrap_or(1),
+            backoff: time::Duration::from_millis(config.nodes.rpc_retry_backoff_ms.unwrap_or(100)),
+        })
+        .with_block_tag(match config.nodes.block_tag {
+            None | Some(config::BlockTag::Latest) => blockchain::BlockTag::Latest,
+            Some(config::BlockTag::Pending) => blockchain::BlockTag::Pending,
+        })
     );
 
+    // Chain id is either configured or read from the node (which has to agree with the configured one).
+    let chain_id = match (config.verification.chain_id_from_node, config.verification.chain_id) {
+        (true, configured) => blockchain.resolve_chain_id(configured)?,
+        (false, Some(chain_id)) => chain_id,
+        (false, None) => return Err("Chain id is not configured (set `chain_id` or `chain_id_from_node`).".into()),
+    };
+
+    // Construct options
+    let options = Options {
+        chain_id,
+        max_gas: config.verification.max_gas,
+        max_transaction_size: config.verification.max_transaction_size.unwrap_or(128 * 1024),
+        min_gas_price: config.verification.min_gas_price,
+        gas_price_exempt,
+        min_gas_per_target,
+        gas_schedule: {
+            let default = GasSchedule::default();
+            GasSchedule {
+                tx: config.verification.tx_gas.unwrap_or(default.tx),
+                tx_create: config.verification.tx_create_gas.unwrap_or(default.tx_create),
+                tx_data_zero: config.verification.tx_data_zero_gas.unwrap_or(default.tx_data_zero),
+                tx_data_non_zero: config.verification.tx_data_non_zero_gas.unwrap_or(default.tx_data_non_zero),
+            }
+        },
+        min_gas_price_percentile: config.verification.min_gas_price_percentile,
+        min_tip: config.verification.min_tip,
+        max_schedule_block: config.verification.max_schedule_block,
+        max_schedule_seconds: config.verification.max_schedule_seconds,
+        min_block_slack: config.verification.min_block_slack,
+        min_lead_blocks: config.verification.min_lead_blocks,
+        min_lead_seconds: config.verification.min_lead_seconds,
+        submit_earlier: config.rpc.submit_earlier,
+        balance_margin: BalanceMargin {
+            wei: config.verification.balance_margin_wei,
+            percent: config.verification.balance_margin_percent,
+        },
+        strict_nonce: config.verification.strict_nonce,
+        max_nonce_ahead: config.verification.max_nonce_ahead,
+        reject_underpriced: config.verification.reject_underpriced,
+        warn_underpriced: config.verification.warn_underpriced,
+        reject_zero_address: config.verification.reject_zero_address,
+        reject_precompiles: config.verification.reject_precompiles,
+        reject_contract_senders: config.verification.reject_contract_senders,
+        database_fail_open: config.verification.database_fail_open,
+        soft_queue_limit: config.verification.soft_queue_limit,
+        retry_after: config.verification.retry_after.unwrap_or(30),
+        max_requests_per_second: config.verification.max_requests_per_second,
+        rpc_listen_address: format!("{}:{}", config.rpc.interface, config.rpc.port).parse().map_err(|e| format!("Invalid interface or port: {}", e))?,
+        rpc_server_threads: config.rpc.server_threads,
+        processing_threads: config.rpc.processing_threads,
+        admin_token: config.rpc.admin_token.clone(),
+    };
+
+
     // TODO [ToDr] The limit is not shared between dbs.
     let max_txs_per_sender= config.verification.max_txs_per_sender;
+    let fifo = config.submitter.fifo;
+    let max_drain_batch = config.submitter.max_drain_batch;
     let block_database = Arc::new(database::Database::open(&config.rpc.db_path, max_txs_per_sender)
-        .map_err(|e| format!("Error opening database: {:?}", e))?);
+        .map_err(|e| format!("Error opening database: {:?}", e))?
+        .with_fifo(fifo)
+        .with_max_drain_batch(max_drain_batch));
     let timestamp_database = Arc::new(database::Database::open(&format!("{}/time/", config.rpc.db_path), max_txs_per_sender)
-        .map_err(|e| format!("Error opening database: {:?}", e))?);
+        .map_err(|e| format!("Error opening database: {:?}", e))?
+        .with_fifo(fifo)
+        .with_max_drain_batch(max_drain_batch));
 
     // Updater is responsible for notifying about latest block.
     let (updater, listener) = blockchain::Updater::new(
         blockchain.clone(),
     );
 
+    // Read-only replicas answering status queries (refreshed periodically).
+    let replicas = config.rpc.replica_refresh_interval.map(|interval| {
+        let interval = time::Duration::from_secs(interval);
+        let replica = |db: &Arc<database::Database>| {
+            let replica = Arc::new(database::Replica::default());
+            let (db, replica2) = (db.clone(), replica.clone());
+            thread::spawn(move || database::run_replica(&db, &replica2, interval));
+            replica
+        };
+        (replica(&block_database), replica(&timestamp_database))
+    });
+
+    // Transactions that failed permanently (kept for inspection via the admin API).
+    let dead_letters = match (config.submitter.dead_letters_capacity, config.submitter.reverify_failure) {
+        (Some(capacity), _) => Some(Arc::new(dead_letters::DeadLetters::new(capacity))),
+        (None, Some(config::ReverifyFailure::DeadLetter)) => Some(Arc::new(dead_letters::DeadLetters::default())),
+        (None, _) => None,
+    };
+
     // A JSON-RPC server verifying and accepting requests.
     let server = server::start(
-        block_database.clone(),
-        timestamp_database.clone(),
-        blockchain.clone(),
+        vec![server::Chain {
+            block_db: block_database.clone(),
+            timestamp_db: timestamp_database.clone(),
+            blockchain: blockchain.clone(),
+            options: options.clone(),
+            rejections: None,
+            decoders: Default::default(),
+            policy: None,
+            replicas: replicas.clone(),
+            dead_letters: dead_letters.clone(),
+        }],
         options,
     )
     .map_err(|e| e.to_string())?;
 
+    // Tracks submitted transactions until they are confirmed.
+    // By default polls for receipts roughly every block.
+    let receipt_poll_interval = time::Duration::from_secs(config.submitter.receipt_poll_interval.unwrap_or(15));
+    let max_tracked = config.submitter.max_tracked;
+    let drop_grace = tracker::DropGrace {
+        blocks: config.submitter.drop_grace_blocks.unwrap_or(0),
+        seconds: config.submitter.drop_grace_seconds.unwrap_or(0),
+    };
+    let tracker = config.submitter.confirmations.map(|depth| {
+        let tracker = tracker::Tracker::new(depth).with_drop_grace(drop_grace);
+        let tracker = Arc::new(Mutex::new(match max_tracked {
+            Some(limit) => tracker.with_max_tracked(limit),
+            None => tracker,
+        }));
+        let (tracker2, blockchain) = (tracker.clone(), blockchain.clone());
+        thread::spawn(move || tracker::run(&tracker2, &blockchain, receipt_poll_interval));
+        tracker
+    });
+
+    // Restores metrics persisted by previous runs and keeps persisting them.
+    let metrics_path = config.submitter.metrics_path.clone();
+    let metrics = match metrics_path {
+        Some(ref path) => {
+            let metrics = Arc::new(metrics::Metrics::load(path).map_err(|e| format!("Unable to load metrics: {}", e))?);
+            let (metrics2, path) = (metrics.clone(), path.clone());
+            let interval = time::Duration::from_secs(config.submitter.metrics_persist_interval.unwrap_or(60));
+            thread::spawn(move || metrics::run_persist(&metrics2, path, interval));
+            metrics
+        },
+        None => Default::default(),
+    };
+
+    // Periodically reports transactions that should have been submitted already.
+    if let Some(interval) = config.submitter.audit_interval {
+        let mut scheduler = scheduler::Scheduler::new(block_database.clone(), timestamp_database.clone());
+        if let Some(ref replicas) = replicas {
+            scheduler = scheduler.with_replicas(replicas.clone());
+        }
+        let (blockchain, metrics) = (blockchain.clone(), metrics.clone());
+        let interval = time::Duration::from_secs(interval);
+        thread::spawn(move || scheduler::run_audit(&scheduler, &blockchain, &clock::SystemClock, &metrics, interval));
+    }
+
+    // Transactions submitted recently (optionally remembered across restarts).
+    let recent = submitter::RecentlySubmitted::new(
+        time::Duration::from_secs(config.submitter.submission_window.unwrap_or(60)),
+        16 * 1024,
+    );
+    let recent = match config.submitter.submitted_journal {
+        Some(ref path) => recent.with_journal(path, clock::SystemClock.now_secs())
+            .map_err(|e| format!("Unable to open submitted transactions journal: {}", e))?,
+        None => recent,
+    };
+
+    let reverify_failure = match config.submitter.reverify_failure {
+        None | Some(config::ReverifyFailure::Drop) => submitter::ReverifyFailure::Drop,
+        Some(config::ReverifyFailure::Log) => submitter::ReverifyFailure::Log,
+        Some(config::ReverifyFailure::DeadLetter) => {
+            let dead_letters = dead_letters.clone().expect("Dead letters are always enabled for dead_letter failures; qed");
+            submitter::ReverifyFailure::DeadLetter(dead_letters)
+        },
+    };
+
     // spawn submitters
-    let transactions = config.nodes.transactions.clone();
-    let submit_earlier = config.rpc.submit_earlier;
+    let submitter_options = submitter::SubmitterOptions {
+        metrics: metrics.clone(),
+        mode: if config.submitter.preferred {
+            submitter::SendMode::Preferred
+        } else if config.submitter.prioritize {
+            submitter::SendMode::Priority
+        } else if config.submitter.try_send {
+            submitter::SendMode::TrySend
+        } else if let Some(quorum) = config.submitter.quorum {
+            submitter::SendMode::Quorum(quorum)
+        } else {
+            submitter::SendMode::Wait
+        },
+        dry_run: config.submitter.dry_run,
+        reverify: if config.submitter.reverify { Some(blockchain.clone()) } else { None },
+        reverify_failure,
+        batch_concurrency: config.submitter.batch_concurrency.unwrap_or(8),
+        fail_on_listener_close: config.submitter.fail_on_listener_close,
+        max_submit_attempts: config.submitter.max_submit_attempts,
+        dead_letters,
+        tracker,
+        max_queue_age: config.submitter.max_queue_age.map(time::Duration::from_secs),
+        breaker_threshold: config.submitter.breaker_threshold,
+        breaker_cooldown: time::Duration::from_secs(config.submitter.breaker_cooldown.unwrap_or(30)),
+        in_flight: config.submitter.max_in_flight.map(|limit| Arc::new(submitter::InFlight::new(limit))),
+        reconnect_delay: time::Duration::from_secs(config.submitter.reconnect_delay.unwrap_or(1)),
+        conditional_blocks: config.submitter.conditional_blocks,
+        send_method: config.submitter.send_method.clone().unwrap_or_else(|| "eth_sendRawTransaction".into()),
+        max_transports: config.submitter.max_transports.unwrap_or(64),
+        timestamp_window: time::Duration::from_secs(config.submitter.timestamp_window.unwrap_or(1)),
+        recent: Arc::new(Mutex::new(recent)),
+        ..Default::default()
+    };
+    // Block and timestamp submitters share recently submitted transactions,
+    // so a transaction due by both triggers is submitted only once.
+    let weights = config.nodes.weights;
+    let transactions = config.nodes.transactions.into_iter().enumerate().map(|(idx, url)| {
+        TransportType::Http(url).with_weight(weights.get(idx).cloned().unwrap_or(0))
+    }).collect::<Vec<_>>();
+    let chain = submitter::ChainSubmitter {
+        chain_id,
+        transports: transactions,
+        listener,
+        block_db: block_database,
+        timestamp_db: Some(timestamp_database),
+        options: submitter_options,
+    };
     let handle = thread::spawn(move || {
-        submitter::run_block(
-            transactions.into_iter().map(TransportType::Http),
-            listener,
-            block_database,
-            submit_earlier,
-        ).map_err(|e| error!("Error starting submitters: {:?}", e))
-    });
-    let _handle = thread::spawn(move || {
-        submitter::run_timestamp(
-            config.nodes.transactions.into_iter().map(TransportType::Http),
-            timestamp_database,
-        ).map_err(|e| error!("Error starting submitters: {:?}", e))
+        for (chain_id, result) in submitter::run_chains(vec![chain]) {
+            if let Err(e) = result {
+                error!("Error starting submitters of chain {}: {:?}", chain_id, e);
+            }
+        }
     });
 
 
-    // Blockchain updater uses the main thread.
-    updater.run(TransportType::Http(blockchain_node_address))
-        .map_err(|e| format!("Error Starting blockchain updater: {:?}", e))?;
+    // Block submitters stop once the blockchain updater is gone.
+    let updater = thread::spawn(move || updater.run(TransportType::Http(blockchain_node_address)));
+
+    // wait for submitters to finish
+    let _ = handle.join();
+    // Persist counts since the last periodic write.
+    if let Some(path) = metrics_path {
+        if let Err(err) = metrics.persist(path) {
+            warn!("Unable to persist metrics: {:?}", err);
+        }
+    }
+    match updater.join() {
+        Ok(result) => result.map_err(|e| format!("Error Starting blockchain updater: {:?}", e))?,
+        Err(_) => return Err("Blockchain updater panicked.".into()),
+    }
 
     // wait for server to finish
     server.wait();
-    let _ = handle.join();
 
     Ok("done".into())
 }
@@ -1,6 +1,7 @@
 //! Blockchain state
 
 use std::collections::HashMap;
+use std::str::FromStr;
 use std::sync::Arc;
 use std::{fmt, thread, time};
 
@@ -11,10 +12,108 @@ use web3::{self, Web3, Transport, contract, transports};
 use web3::api::{Eth, Namespace};
 use web3::transports::http::Http;
 
-use types::{Address, BlockNumber, U256};
+use types::{Address, BlockNumber, H256, U256};
 use TransportType;
 
 type BN = (U256, U256);
+
+/// Block time (in seconds) assumed until enough blocks are observed.
+const DEFAULT_BLOCK_TIME: u64 = 15;
+
+/// Retry policy of RPC calls to the blockchain node.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetryPolicy {
+    /// Max number of attempts (including the first one).
+    pub attempts: u32,
+    /// Delay before the first retry (doubled after every failed attempt).
+    pub backoff: time::Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            attempts: 1,
+            backoff: time::Duration::from_millis(100),
+        }
+    }
+}
+
+/// Block the state of senders is read at.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockTag {
+    /// The latest mined block.
+    Latest,
+    /// The block currently being mined (includes transactions already in the node's queue).
+    Pending,
+}
+
+impl Default for BlockTag {
+    fn default() -> Self {
+        BlockTag::Latest
+    }
+}
+
+impl BlockTag {
+    /// Returns the tag as used in JSON-RPC calls.
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            BlockTag::Latest => "latest",
+            BlockTag::Pending => "pending",
+        }
+    }
+}
+
+impl From<BlockTag> for web3::types::BlockNumber {
+    fn from(tag: BlockTag) -> Self {
+        match tag {
+            BlockTag::Latest => web3::types::BlockNumber::Latest,
+            BlockTag::Pending => web3::types::BlockNumber::Pending,
+        }
+    }
+}
+
+/// First and latest block seen by the updater together with the time they were seen at.
+#[derive(Debug, Default)]
+struct BlockTimes {
+    first: Option<(BlockNumber, time::SystemTime)>,
+    latest: Option<(BlockNumber, time::SystemTime)>,
+}
+
+impl BlockTimes {
+    fn observe(&mut self, block: BlockNumber, at: time::SystemTime) {
+        if self.latest.map_or(false, |(latest, _)| block <= latest) {
+            return;
+        }
+        if self.first.is_none() {
+            self.first = Some((block, at));
+        }
+        self.latest = Some((block, at));
+    }
+
+    /// Returns average time between the blocks seen so far (`None` if less than two blocks were seen).
+    fn average(&self) -> Option<time::Duration> {
+        let ((first, first_at), (latest, latest_at)) = (self.first?, self.latest?);
+        let elapsed = latest_at.duration_since(first_at).ok()?;
+        let blocks = latest - first;
+        if blocks == 0 {
+            return None;
+        }
+        Some(time::Duration::from_millis(millis(elapsed) / blocks))
+    }
+}
+
+fn millis(duration: time::Duration) -> u64 {
+    duration.as_secs().saturating_mul(1_000) + duration.subsec_nanos() as u64 / 1_000_000
+}
+
+/// Returns the time `target` block is expected to be mined at,
+/// given the `latest` block (and the time it was seen at) and average `block_time`.
+fn estimate_block_time(target: BlockNumber, latest: (BlockNumber, time::SystemTime), block_time: time::Duration) -> time::SystemTime {
+    let (latest, latest_at) = latest;
+    let blocks = target.saturating_sub(latest);
+    latest_at + time::Duration::from_millis(millis(block_time).saturating_mul(blocks))
+}
+
 /// A structure responsible for maintaining and caching latest blockchain state, like:
 /// - latest block number
 /// - nonce for particular sender
@@ -22,10 +121,17 @@ pub struct Blockchain<T: Transport = Arc<Http>> {
     web3: Web3<T>,
     _eloop: transports::EventLoopHandle,
     latest_block: RwLock<BlockNumber>,
+    block_times: RwLock<BlockTimes>,
     // TODO [ToDr] Caching can lead to OOM. Might be worth to introduce some eviction.
     cached_balance_and_nonce: Arc<RwLock<HashMap<Address, BN>>>,
     cached_certification: Arc<RwLock<HashMap<Address, bool>>>,
+    cached_is_contract: Arc<RwLock<HashMap<Address, bool>>>,
+    cached_base_fee: Arc<RwLock<Option<U256>>>,
+    cached_gas_prices: Arc<RwLock<Option<Vec<U256>>>>,
     certifier: Option<contract::Contract<T>>,
+    certification_warmup: Vec<Address>,
+    retry: RetryPolicy,
+    block_tag: BlockTag,
 }
 
 impl<T: Transport> fmt::Debug for Blockchain<T> {
@@ -52,27 +158,87 @@ impl Blockchain {
             web3: Web3::new(http),
             _eloop,
             latest_block: Default::default(),
+            block_times: Default::default(),
             cached_balance_and_nonce: Default::default(),
             cached_certification: Default::default(),
+            cached_is_contract: Default::default(),
+            cached_base_fee: Default::default(),
+            cached_gas_prices: Default::default(),
             certifier,
+            certification_warmup: Vec::new(),
+            retry: RetryPolicy::default(),
+            block_tag: BlockTag::default(),
         })
     }
+
+    /// Retries failed RPC calls according to given policy.
+    pub fn with_retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Read balance, nonce and the latest block number at given block.
+    pub fn with_block_tag(mut self, block_tag: BlockTag) -> Self {
+        self.block_tag = block_tag;
+        self
+    }
+
+    /// Pre-load certification status of given addresses (see `warm_up_certification`).
+    /// Their status is kept across blocks, so it's not queried again for every block.
+    pub fn with_certification_warmup(mut self, addresses: Vec<Address>) -> Self {
+        self.certification_warmup = addresses;
+        self
+    }
 }
 
-impl<T: Transport> Blockchain<T> where
+impl<T: Transport + Send + 'static> Blockchain<T> where
     T::Out: Send + 'static,
 {
+    /// Runs RPC `call` using a fresh `Eth` namespace for every attempt (retried according to the retry policy).
+    fn call<F, I>(&self, call: F) -> Box<Future<Item=I, Error=web3::Error> + Send> where
+        F: Fn(Eth<T>) -> Box<Future<Item=I, Error=web3::Error> + Send> + Send + 'static,
+        I: Send + 'static,
+    {
+        let transport = self.web3.eth().transport().clone();
+        retry(self.retry, move || call(Eth::new(transport.clone())))
+    }
+
     fn update_latest_block(&self, new: BlockNumber) {
         *self.latest_block.write() = new;
+        self.block_times.write().observe(new, time::SystemTime::now());
         self.cached_balance_and_nonce.write().clear();
-        self.cached_certification.write().clear();
+        {
+            let warmup = &self.certification_warmup;
+            self.cached_certification.write().retain(|address, _| warmup.contains(address));
+        }
+        self.cached_is_contract.write().clear();
+        *self.cached_base_fee.write() = None;
+        *self.cached_gas_prices.write() = None;
     }
 
-    /// Returns current latest block.
+    /// Returns current latest block (or the pending one, depending on the block tag).
     pub fn latest_block(&self) -> BlockNumber {
         *self.latest_block.read()
     }
 
+    /// Returns the block balance, nonce and the latest block number are read at.
+    pub fn block_tag(&self) -> BlockTag {
+        self.block_tag
+    }
+
+    /// Returns average time between blocks seen so far (or a default of 15 seconds if not known yet).
+    pub fn average_block_time(&self) -> time::Duration {
+        self.block_times.read().average().unwrap_or_else(|| time::Duration::from_secs(DEFAULT_BLOCK_TIME))
+    }
+
+    /// Estimates the wall-clock time a transaction stored for `target` block is going to be submitted at
+    /// (when the block is mined, based on the average block time).
+    pub fn estimated_submit_time(&self, target: BlockNumber) -> time::SystemTime {
+        let latest = self.block_times.read().latest
+            .unwrap_or_else(|| (self.latest_block(), time::SystemTime::now()));
+        estimate_block_time(target, latest, self.average_block_time())
+    }
+
     /// Queries the blockchain for given sender's balance and nonce.
     pub fn balance_and_nonce(&self, sender: Address) -> Box<Future<Item=BN, Error=web3::Error> + Send> {
         trace!("Fetching balance and nonce for {:?}", sender);
@@ -81,18 +247,104 @@ impl<T: Transport> Blockchain<T> where
             return Box::new(future::ok(bn.clone()));
         }
 
-        let address = (*sender).into();
-        let balance = self.web3.eth().balance(address, None).map(|balance| (*balance).into());
-        let nonce = self.web3.eth().transaction_count(address, None).map(|nonce| (*nonce).into());
+        let address: web3::types::Address = (*sender).into();
+        let tag = self.block_tag;
+        let balance_and_nonce: Box<Future<Item=BN, Error=web3::Error> + Send> = self.call(move |eth| {
+            let balance = eth.balance(address, Some(tag.into())).map(|balance| (*balance).into());
+            let nonce = eth.transaction_count(address, Some(tag.into())).map(|nonce| (*nonce).into());
+            Box::new(balance.join(nonce))
+        });
 
         let cbn = self.cached_balance_and_nonce.clone();
-        Box::new(balance.join(nonce).map(move |res| {
+        Box::new(balance_and_nonce.map(move |res| {
             trace!("Got balance and nonce for {:?} = {:?}", sender, res);
             cbn.write().insert(sender, res.clone());
             res
         }))
     }
 
+    /// Checks whether there is code deployed at given address.
+    pub fn is_contract(&self, address: Address) -> Box<Future<Item=bool, Error=web3::Error> + Send> {
+        trace!("Fetching code of {:?}", address);
+        if let Some(is_contract) = self.cached_is_contract.read().get(&address) {
+            trace!("Returning cached result for {:?} = {:?}", address, is_contract);
+            return Box::new(future::ok(*is_contract));
+        }
+
+        let cache = self.cached_is_contract.clone();
+        let code = self.call(move |eth| Box::new(eth.code((*address).into(), None)));
+        Box::new(code.map(move |code| {
+            let is_contract = !code.0.is_empty();
+            trace!("Got code of {:?}, is contract: {:?}", address, is_contract);
+            cache.write().insert(address, is_contract);
+            is_contract
+        }))
+    }
+
+    /// Queries the blockchain for base fee of the latest block.
+    /// Returns `None` if the chain doesn't support EIP-1559.
+    pub fn base_fee(&self) -> Box<Future<Item=Option<U256>, Error=web3::Error> + Send> {
+        if let Some(base_fee) = *self.cached_base_fee.read() {
+            trace!("Returning cached base fee = {:?}", base_fee);
+            return Box::new(future::ok(Some(base_fee)));
+        }
+
+        let cache = self.cached_base_fee.clone();
+        let block = self.call(|eth| Box::new(eth.transport().execute("eth_getBlockByNumber", vec!["latest".into(), false.into()])));
+        Box::new(block.map(move |block| {
+            let base_fee = block.get("baseFeePerGas").and_then(parse_u256);
+            trace!("Got base fee = {:?}", base_fee);
+            *cache.write() = base_fee;
+            base_fee
+        }))
+    }
+
+    /// Returns given percentile of gas prices of transactions in the latest block.
+    /// Returns `None` if the block has no transactions.
+    pub fn gas_price_percentile(&self, n: u8) -> Box<Future<Item=Option<U256>, Error=web3::Error> + Send> {
+        if let Some(ref gas_prices) = *self.cached_gas_prices.read() {
+            trace!("Returning cached gas prices.");
+            return Box::new(future::ok(percentile(gas_prices, n)));
+        }
+
+        let cache = self.cached_gas_prices.clone();
+        let block = self.call(|eth| Box::new(eth.transport().execute("eth_getBlockByNumber", vec!["latest".into(), true.into()])));
+        Box::new(block.map(move |block| {
+            let mut gas_prices = block.get("transactions")
+                .and_then(|txs| txs.as_array())
+                .map(|txs| txs.iter().filter_map(|tx| tx.get("gasPrice").and_then(parse_u256)).collect::<Vec<_>>())
+                .unwrap_or_default();
+            gas_prices.sort();
+            trace!("Got {} gas prices.", gas_prices.len());
+            let floor = percentile(&gas_prices, n);
+            *cache.write() = Some(gas_prices);
+            floor
+        }))
+    }
+
+    /// Queries the node for the chain id (`eth_chainId`).
+    /// Returns `None` if the response is not a valid quantity.
+    pub fn chain_id(&self) -> Box<Future<Item=Option<u64>, Error=web3::Error> + Send> {
+        let id = self.web3.eth().transport().execute("eth_chainId", vec![]);
+        Box::new(id.map(|id| parse_chain_id(&id)))
+    }
+
+    /// Reads the chain id from the node (blocking) and checks it against `configured` one (if any).
+    pub fn resolve_chain_id(&self, configured: Option<u64>) -> Result<u64, String> {
+        let node = self.chain_id().wait().map_err(|e| format!("Unable to read chain id from the node: {:?}", e))?;
+        check_chain_id(configured, node)
+    }
+
+    /// Queries the blockchain for the block given transaction is included in.
+    /// Returns `None` if the transaction is not included yet.
+    pub fn transaction_block(&self, hash: H256) -> Box<Future<Item=Option<BlockNumber>, Error=web3::Error> + Send> {
+        trace!("Fetching receipt for {:?}", hash);
+        let hash: web3::types::H256 = (*hash).into();
+        Box::new(self.call(move |eth| Box::new(eth.transaction_receipt(hash))).map(|receipt| {
+            receipt.map(|receipt| receipt.block_number.low_u64())
+        }))
+    }
+
     /// Checks whether address is certified on blockchain.
     pub fn is_certified(&self, sender: Address) -> Box<Future<Item=bool, Error=contract::Error> + Send> {
         trace!("Checking certification status for {:?}", sender);
@@ -106,16 +358,134 @@ impl<T: Transport> Blockchain<T> where
             return Box::new(future::ok(*is_certified));
         }
 
-        let address: web3::types::Address = (*sender).into();
         let cc = self.cached_certification.clone();
         Box::new(
-            certifier.query("certified", (address, ), None, Default::default(), None).map(move |res: bool| {
-            trace!("Got certification status for {:?} = {:?}", sender, res);
+            query_certification(certifier, sender).map(move |res| {
               cc.write().insert(sender, res);
               res
             })
         )
     }
+
+    /// Fetches certification status of all configured addresses to the cache.
+    pub fn warm_up_certification(&self) -> Box<Future<Item=(), Error=contract::Error> + Send> {
+        let certifier = match self.certifier {
+            Some(ref certifier) if !self.certification_warmup.is_empty() => certifier,
+            _ => return Box::new(future::ok(())),
+        };
+
+        debug!("Warming up certification status of {} addresses.", self.certification_warmup.len());
+        warm_up(self.cached_certification.clone(), &self.certification_warmup, |address| query_certification(certifier, address))
+    }
+}
+
+/// Runs `call` until it succeeds or the `policy` runs out of attempts (returning the last error).
+///
+/// There is no timer, so the backoff blocks the thread polling the future.
+/// That's either a `CpuPool` thread (verification) or a thread waiting for the result anyway
+/// (tracker, re-verification, certification warm-up), never the event loop of the transport.
+fn retry<F, I>(policy: RetryPolicy, call: F) -> Box<Future<Item=I, Error=web3::Error> + Send> where
+    F: FnMut() -> Box<Future<Item=I, Error=web3::Error> + Send> + Send + 'static,
+    I: Send + 'static,
+{
+    Box::new(future::loop_fn((call, 1, policy.backoff), move |(mut call, attempt, backoff)| {
+        call().then(move |result| match result {
+            Ok(item) => Ok(future::Loop::Break(item)),
+            Err(err) => if wait_for_retry(policy, attempt, backoff, &err) {
+                Ok(future::Loop::Continue((call, attempt + 1, backoff * 2)))
+            } else {
+                Err(err)
+            },
+        })
+    }))
+}
+
+/// Blocking counterpart of `retry`.
+fn retry_wait<F, I>(policy: RetryPolicy, mut call: F) -> Result<I, web3::Error> where
+    F: FnMut() -> Result<I, web3::Error>,
+{
+    let (mut attempt, mut backoff) = (1, policy.backoff);
+    loop {
+        match call() {
+            Ok(item) => return Ok(item),
+            Err(err) => if !wait_for_retry(policy, attempt, backoff, &err) {
+                return Err(err);
+            },
+        }
+        attempt += 1;
+        backoff = backoff * 2;
+    }
+}
+
+/// Sleeps for `backoff` if the `policy` allows another attempt after the failed one.
+/// Returns `false` if there are no attempts left.
+fn wait_for_retry(policy: RetryPolicy, attempt: u32, backoff: time::Duration, err: &web3::Error) -> bool {
+    if attempt >= policy.attempts {
+        return false;
+    }
+
+    warn!("RPC call failed (attempt {}/{}): {:?}. Retrying in {:?}.", attempt, policy.attempts, err, backoff);
+    thread::sleep(backoff);
+    true
+}
+
+/// Queries the certifier contract for certification status of given address.
+fn query_certification<T: Transport>(certifier: &contract::Contract<T>, sender: Address)
+    -> Box<Future<Item=bool, Error=contract::Error> + Send> where
+    T::Out: Send + 'static,
+{
+    let address: web3::types::Address = (*sender).into();
+    Box::new(certifier.query("certified", (address, ), None, Default::default(), None).map(move |res: bool| {
+        trace!("Got certification status for {:?} = {:?}", sender, res);
+        res
+    }))
+}
+
+/// Fetches certification status of given addresses (using `fetch`) and stores them in the `cache`.
+fn warm_up<F>(cache: Arc<RwLock<HashMap<Address, bool>>>, addresses: &[Address], mut fetch: F)
+    -> Box<Future<Item=(), Error=contract::Error> + Send> where
+    F: FnMut(Address) -> Box<Future<Item=bool, Error=contract::Error> + Send>,
+{
+    let statuses = addresses.iter()
+        .map(|&address| fetch(address).map(move |is_certified| (address, is_certified)))
+        .collect::<Vec<_>>();
+    Box::new(future::join_all(statuses).map(move |statuses| {
+        cache.write().extend(statuses);
+    }))
+}
+
+/// Parses a hex-encoded quantity.
+fn parse_u256(value: &::serde_json::Value) -> Option<U256> {
+    let value = value.as_str()?.trim_left_matches("0x");
+    let value = if value.len() % 2 == 1 { format!("0{}", value) } else { value.to_owned() };
+    U256::from_str(&value).ok()
+}
+
+/// Parses the chain id (has to fit in `u64`).
+fn parse_chain_id(value: &::serde_json::Value) -> Option<u64> {
+    parse_u256(value).and_then(|id| if id > U256::from(u64::max_value()) { None } else { Some(id.low_u64()) })
+}
+
+/// Returns the chain id reported by the node, making sure it matches the configured one (if any).
+fn check_chain_id(configured: Option<u64>, node: Option<u64>) -> Result<u64, String> {
+    match (configured, node) {
+        (_, None) => Err("Node returned invalid chain id.".into()),
+        (Some(configured), Some(node)) if configured != node => {
+            Err(format!("Configured chain id ({}) doesn't match the node ({}).", configured, node))
+        },
+        (_, Some(node)) => Ok(node),
+    }
+}
+
+/// Returns `n`-th percentile (nearest-rank) of sorted values.
+fn percentile(sorted: &[U256], n: u8) -> Option<U256> {
+    if sorted.is_empty() {
+        return None;
+    }
+
+    let rank = (n as usize * sorted.len() + 99) / 100;
+    let index = ::std::cmp::min(rank.saturating_sub(1), sorted.len() - 1);
+    Some(sorted[index])
 }
 
 /// Blockchain updater.
@@ -144,7 +514,8 @@ impl Updater {
             TransportType::Http(url) => {
                 let (_eloop, http) = transports::http::Http::new(&url)?;
                 self.run_internal(http)
-            }
+            },
+            TransportType::Weighted(transport, _) => return self.run(*transport),
         }
         Ok(())
     }
@@ -152,7 +523,9 @@ impl Updater {
     fn run_internal<T: Transport>(mut self, transport: T) {
         info!("Starting blockchain updater.");
         let web3 = Web3::new(transport);
-        
+        let tag = self.blockchain.block_tag();
+        let retry_policy = self.blockchain.retry;
+
         let mut last_block = None;
         let mut update = |block_number, last_block: &mut Option<BlockNumber>| {
             trace!("Updating latest block number: {}", block_number);
@@ -163,13 +536,17 @@ impl Updater {
             }
         };
 
+        // Warmed up entries are kept across blocks, so they are fetched only once.
+        if let Err(err) = self.blockchain.warm_up_certification().wait() {
+            warn!("Unable to warm up certification cache: {:?}", err);
+        }
+
         loop {
-            match web3.eth().block_number().wait() {
+            match retry_wait(retry_policy, || block_number(&web3, tag)) {
                 Err(err) => {
                     warn!("Cannot fetch latest block: {:?}", err);
                 },
                 Ok(block_number) => {
-                    let block_number = block_number.low_u64();
                     match last_block {
                         Some(block) if block != block_number => update(block_number, &mut last_block),
                         None => update(block_number, &mut last_block),
@@ -181,3 +558,330 @@ impl Updater {
         }
     }
 }
+
+/// Fetches number of the block of given tag.
+fn block_number<T: Transport>(web3: &Web3<T>, tag: BlockTag) -> Result<BlockNumber, web3::Error> {
+    match tag {
+        BlockTag::Latest => web3.eth().block_number().wait().map(|block| block.low_u64()),
+        BlockTag::Pending => {
+            let block = web3.eth().transport().execute("eth_getBlockByNumber", block_params(tag)).wait()?;
+            block.get("number").and_then(parse_u256).map(|number| number.low_u64())
+                .ok_or_else(|| web3::Error::from("Pending block without a number."))
+        },
+    }
+}
+
+/// Parameters of `eth_getBlockByNumber` call fetching given block (without transactions).
+fn block_params(tag: BlockTag) -> Vec<::serde_json::Value> {
+    vec![tag.as_str().into(), false.into()]
+}
+
+/// Spawns a thread polling given transport for the latest block number every `interval`.
+/// New (increasing) block numbers are pushed to the `listener`, duplicates are skipped.
+/// Useful for nodes that don't support subscriptions.
+///
+/// The thread stops when the listener is closed.
+/// Event loop of the transport needs to be kept alive by the caller.
+pub fn spawn_block_poller<T>(
+    transport: T,
+    interval: time::Duration,
+    listener: mpsc::Sender<BlockNumber>,
+) -> thread::JoinHandle<()> where
+    T: Transport + Send + 'static,
+{
+    let web3 = Web3::new(transport);
+    spawn_poller(move || web3.eth().block_number().wait().map(|block| block.low_u64()), interval, listener)
+}
+
+fn spawn_poller<F, E>(
+    mut fetch: F,
+    interval: time::Duration,
+    listener: mpsc::Sender<BlockNumber>,
+) -> thread::JoinHandle<()> where
+    F: FnMut() -> Result<BlockNumber, E> + Send + 'static,
+    E: fmt::Debug,
+{
+    thread::spawn(move || {
+        let mut listener = listener.wait();
+        let mut last_block = None;
+        loop {
+            match fetch() {
+                Err(err) => {
+                    warn!("Cannot fetch latest block: {:?}", err);
+                },
+                Ok(block_number) if last_block.map_or(true, |last| block_number > last) => {
+                    trace!("New block: {}", block_number);
+                    last_block = Some(block_number);
+                    if listener.send(block_number).is_err() {
+                        debug!("Listener closed, stopping block poller.");
+                        return;
+                    }
+                },
+                Ok(_) => {},
+            }
+            thread::sleep(interval);
+        }
+    })
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::cmp;
+    use std::io::{self, BufRead, BufReader, Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use futures::Stream;
+    use parking_lot::Mutex;
+    use serde_json::{self, Value};
+    use super::*;
+
+    type Respond = Fn(&str, &Value) -> Option<Value> + Send + Sync;
+
+    /// Calls of a single method received by the node.
+    #[derive(Debug, Default)]
+    struct Calls {
+        total: usize,
+        in_flight: usize,
+        max_in_flight: usize,
+    }
+
+    /// A JSON-RPC node listening on a local port that counts the calls it receives.
+    pub struct MockNode {
+        url: String,
+        calls: Arc<Mutex<HashMap<String, Calls>>>,
+    }
+
+    impl MockNode {
+        /// Starts a node answering calls with `respond` (given method and params).
+        /// Calls it returns `None` for fail with an error.
+        pub fn start<F>(respond: F) -> Self where
+            F: Fn(&str, &Value) -> Option<Value> + Send + Sync + 'static,
+        {
+            let listener = TcpListener::bind("127.0.0.1:0").expect("Binding to a random port works; qed");
+            let url = format!("http://{}", listener.local_addr().expect("Listener is bound; qed"));
+            let calls = Arc::new(Mutex::new(HashMap::new()));
+            let respond: Arc<Respond> = Arc::new(respond);
+            {
+                let calls = calls.clone();
+                thread::spawn(move || for stream in listener.incoming() {
+                    let (calls, respond) = (calls.clone(), respond.clone());
+                    match stream {
+                        Ok(stream) => { thread::spawn(move || serve(stream, &*respond, &calls)); },
+                        Err(_) => return,
+                    }
+                });
+            }
+            MockNode { url, calls }
+        }
+
+        /// Returns the URL of the node.
+        pub fn url(&self) -> &str {
+            &self.url
+        }
+
+        /// Returns number of calls of given method received so far.
+        pub fn calls(&self, method: &str) -> usize {
+            self.calls.lock().get(method).map_or(0, |calls| calls.total)
+        }
+
+        /// Returns the highest number of calls of given method that were answered at the same time.
+        pub fn max_in_flight(&self, method: &str) -> usize {
+            self.calls.lock().get(method).map_or(0, |calls| calls.max_in_flight)
+        }
+    }
+
+    /// Answers HTTP requests of a single (keep-alive) connection until it's closed.
+    fn serve(stream: TcpStream, respond: &Respond, calls: &Mutex<HashMap<String, Calls>>) -> io::Result<()> {
+        let mut writer = stream.try_clone()?;
+        let mut reader = BufReader::new(stream);
+        loop {
+            let mut content_length = 0;
+            loop {
+                let mut line = String::new();
+                if reader.read_line(&mut line)? == 0 {
+                    return Ok(());
+                }
+                let line = line.trim().to_lowercase();
+                if line.is_empty() {
+                    break;
+                }
+                if line.starts_with("content-length:") {
+                    content_length = line["content-length:".len()..].trim().parse().unwrap_or(0);
+                }
+            }
+
+            let mut body = vec![0; content_length];
+            reader.read_exact(&mut body)?;
+            let request: Value = serde_json::from_slice(&body)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+            let method = request["method"].as_str().unwrap_or("").to_owned();
+            {
+                let mut calls = calls.lock();
+                let calls = calls.entry(method.clone()).or_insert_with(Calls::default);
+                calls.total += 1;
+                calls.in_flight += 1;
+                calls.max_in_flight = cmp::max(calls.max_in_flight, calls.in_flight);
+            }
+            let result = respond(&method, &request["params"]);
+            calls.lock().get_mut(&method).expect("Entry inserted above; qed").in_flight -= 1;
+            let response = match result {
+                Some(result) => format!(r#"{{"jsonrpc":"2.0","id":{},"result":{}}}"#, request["id"], result),
+                None => format!(r#"{{"jsonrpc":"2.0","id":{},"error":{{"code":-32000,"message":"Mocked failure."}}}}"#, request["id"]),
+            };
+            write!(writer, "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}", response.len(), response)?;
+            writer.flush()?;
+        }
+    }
+
+    impl<T: Transport> Blockchain<T> {
+        /// Pretends that given address has (or doesn't have) code deployed.
+        pub fn mock_code(&self, address: Address, is_contract: bool) {
+            self.cached_is_contract.write().insert(address, is_contract);
+        }
+
+        /// Pretends that given address has given balance and nonce.
+        pub fn mock_balance_and_nonce(&self, address: Address, balance: U256, nonce: U256) {
+            self.cached_balance_and_nonce.write().insert(address, (balance, nonce));
+        }
+
+        /// Pretends that the latest block has given base fee.
+        pub fn mock_base_fee(&self, base_fee: U256) {
+            *self.cached_base_fee.write() = Some(base_fee);
+        }
+
+        /// Pretends that a new block was imported.
+        pub fn mock_latest_block(&self, block_number: BlockNumber) {
+            self.update_latest_block(block_number);
+        }
+    }
+
+    #[test]
+    fn should_compute_percentile() {
+        let gwei = U256::from(1_000_000_000);
+        let prices = (1..11).map(|x| gwei * U256::from(x)).collect::<Vec<_>>();
+
+        assert_eq!(percentile(&prices, 0), Some(gwei));
+        assert_eq!(percentile(&prices, 25), Some(gwei * U256::from(3)));
+        assert_eq!(percentile(&prices, 50), Some(gwei * U256::from(5)));
+        assert_eq!(percentile(&prices, 90), Some(gwei * U256::from(9)));
+        assert_eq!(percentile(&prices, 100), Some(gwei * U256::from(10)));
+        assert_eq!(percentile(&prices, 255), Some(gwei * U256::from(10)));
+        assert_eq!(percentile(&[], 50), None);
+    }
+
+    #[test]
+    fn should_parse_quantities() {
+        assert_eq!(parse_u256(&"0x3b9aca00".into()), Some(1_000_000_000.into()));
+        assert_eq!(parse_u256(&"0x7".into()), Some(7.into()));
+        assert_eq!(parse_u256(&1.into()), None);
+    }
+
+    #[test]
+    fn should_estimate_submit_time_using_average_block_time() {
+        let start = time::UNIX_EPOCH + time::Duration::from_secs(1_000_000);
+        let mut times = BlockTimes::default();
+        assert_eq!(times.average(), None);
+        times.observe(100, start);
+        assert_eq!(times.average(), None);
+        times.observe(110, start + time::Duration::from_secs(120));
+        // Duplicates and older blocks are ignored.
+        times.observe(105, start + time::Duration::from_secs(500));
+        assert_eq!(times.average(), Some(time::Duration::from_secs(12)));
+
+        let latest = times.latest.unwrap();
+        assert_eq!(
+            estimate_block_time(160, latest, time::Duration::from_secs(12)),
+            start + time::Duration::from_secs(120 + 50 * 12)
+        );
+        // Past blocks are expected now.
+        assert_eq!(estimate_block_time(100, latest, time::Duration::from_secs(12)), latest.1);
+    }
+
+    #[test]
+    fn should_retry_failed_calls() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        let fail_once = |calls: Arc<AtomicUsize>| move || -> Box<Future<Item=BN, Error=web3::Error> + Send> {
+            if calls.fetch_add(1, Ordering::SeqCst) == 0 {
+                Box::new(future::err(web3::ErrorKind::Unreachable.into()))
+            } else {
+                Box::new(future::ok((1_000.into(), 5.into())))
+            }
+        };
+        let policy = RetryPolicy { attempts: 2, backoff: time::Duration::from_millis(1) };
+
+        let (balance, nonce) = retry(policy, fail_once(calls.clone())).wait().unwrap();
+        assert_eq!((balance, nonce), (1_000.into(), 5.into()));
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        // Fails without retries.
+        calls.store(0, Ordering::SeqCst);
+        assert!(retry(RetryPolicy::default(), fail_once(calls.clone())).wait().is_err());
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn should_retry_blocking_calls() {
+        let policy = RetryPolicy { attempts: 3, backoff: time::Duration::from_millis(1) };
+
+        let mut calls = 0;
+        let block = retry_wait(policy, || {
+            calls += 1;
+            if calls < 3 { Err(web3::ErrorKind::Unreachable.into()) } else { Ok(5) }
+        });
+        assert_eq!(block.unwrap(), 5);
+        assert_eq!(calls, 3);
+
+        let mut calls = 0;
+        let block: Result<BlockNumber, _> = retry_wait(policy, || {
+            calls += 1;
+            Err(web3::ErrorKind::Unreachable.into())
+        });
+        assert!(block.is_err());
+        assert_eq!(calls, 3);
+    }
+
+    #[test]
+    fn should_adopt_chain_id_of_the_node() {
+        let response = "0x2a".into();
+
+        assert_eq!(check_chain_id(None, parse_chain_id(&response)), Ok(42));
+        assert_eq!(check_chain_id(Some(42), parse_chain_id(&response)), Ok(42));
+        assert_eq!(
+            check_chain_id(Some(1), parse_chain_id(&response)),
+            Err("Configured chain id (1) doesn't match the node (42).".into())
+        );
+        assert!(check_chain_id(None, parse_chain_id(&"0x10000000000000000".into())).is_err());
+    }
+
+    #[test]
+    fn should_only_emit_new_blocks() {
+        let (tx, rx) = mpsc::channel(1);
+        let mut blocks = vec![1, 1, 2, 3, 3, 2, 4].into_iter().chain(5..);
+        let poller = spawn_poller(
+            move || blocks.next().ok_or(()),
+            time::Duration::from_millis(1),
+            tx,
+        );
+
+        assert_eq!(rx.take(4).collect().wait().unwrap(), vec![1, 2, 3, 4]);
+        poller.join().unwrap();
+    }
+
+    #[test]
+    fn should_pass_block_tag_to_rpc() {
+        let blockchain = Blockchain::new("http://127.0.0.1:8545", None).unwrap();
+        assert_eq!(blockchain.block_tag(), BlockTag::Latest);
+        let blockchain = blockchain.with_block_tag(BlockTag::Pending);
+        assert_eq!(blockchain.block_tag(), BlockTag::Pending);
+
+        for &tag in &[BlockTag::Latest, BlockTag::Pending] {
+            // Used by balance and nonce queries.
+            let block: web3::types::BlockNumber = tag.into();
+            assert_eq!(::serde_json::to_value(&block).unwrap(), ::serde_json::Value::from(tag.as_str()));
+            // Used to fetch the pending block number.
+            assert_eq!(block_params(tag), vec![::serde_json::Value::from(tag.as_str()), false.into()]);
+        }
+        assert_eq!(block_params(BlockTag::Pending)[0], "pending");
+    }
+}
@@ -0,0 +1,47 @@
+//! Time sources
+
+use std::fmt;
+
+/// A source of current time.
+pub trait Clock: fmt::Debug + Send + Sync {
+    /// Returns current unix timestamp in seconds.
+    fn now_secs(&self) -> u64;
+}
+
+/// A clock reading the system time.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now_secs(&self) -> u64 {
+        ::time::now_utc().to_timespec().sec as u64
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use super::Clock;
+
+    /// A clock that only advances under test control.
+    #[derive(Debug, Default)]
+    pub struct MockClock(AtomicUsize);
+
+    impl MockClock {
+        /// Creates a clock showing given time.
+        pub fn new(now: u64) -> Self {
+            MockClock(AtomicUsize::new(now as usize))
+        }
+
+        /// Moves the clock forward by given number of seconds.
+        pub fn advance(&self, secs: u64) {
+            self.0.fetch_add(secs as usize, Ordering::SeqCst);
+        }
+    }
+
+    impl Clock for MockClock {
+        fn now_secs(&self) -> u64 {
+            self.0.load(Ordering::SeqCst) as u64
+        }
+    }
+}
@@ -2,16 +2,21 @@
 
 use std::collections::btree_map::Entry;
 use std::collections::hash_map;
-use std::collections::{HashMap, BTreeMap};
+use std::collections::{HashMap, HashSet, BTreeMap};
 use std::io::{Read, Write, Seek};
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use std::{io, fs, mem};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use std::{io, fs, mem, thread};
 
 use byteorder::{LittleEndian, WriteBytesExt, ReadBytesExt};
-use parking_lot::RwLock;
+use parking_lot::{Mutex, RwLock};
+use serde_json;
 
-use types::{BlockNumber, Transaction, Address, H256};
+use clock::{Clock, SystemClock};
+use types::{BlockNumber, Priority, Transaction, Address, H256, U256};
+use verifier;
 
 mod error {
     #![allow(unknown_lints)]
@@ -31,6 +36,155 @@ mod error {
 
 pub use self::error::*;
 
+/// Depth of the submission queue.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct QueueDepth {
+    /// Total number of transactions waiting for submission.
+    pub pending: usize,
+    /// Number of transactions that will be submitted in the next block.
+    pub next: usize,
+    /// Furthest block (or timestamp) with transactions scheduled.
+    pub furthest: Option<BlockNumber>,
+}
+
+/// Queue depth gauges, updated as transactions are inserted and drained.
+#[derive(Debug, Default)]
+struct Gauges {
+    depth: QueueDepth,
+    /// Block (or timestamp) following the last drained one.
+    next_block: BlockNumber,
+    /// Number of inserts and removals so far (see `Replica::refresh`).
+    changes: usize,
+}
+
+impl Gauges {
+    /// Records `count` transactions scheduled for given block.
+    fn added(&mut self, block_number: BlockNumber, count: usize) {
+        if count > 0 {
+            self.changes += 1;
+        }
+        self.depth.pending += count;
+        if block_number <= self.next_block {
+            self.depth.next += count;
+        }
+        self.depth.furthest = ::std::cmp::max(self.depth.furthest, Some(block_number));
+    }
+
+    /// Records `count` transactions removed from given block.
+    fn removed(&mut self, block_number: BlockNumber, count: usize) {
+        if count > 0 {
+            self.changes += 1;
+        }
+        self.depth.pending = self.depth.pending.saturating_sub(count);
+        if block_number <= self.next_block {
+            self.depth.next = self.depth.next.saturating_sub(count);
+        }
+    }
+
+    /// Records blocks removed from the database.
+    fn update_furthest(&mut self, blocks: &BTreeMap<BlockNumber, BlockDatabase>) {
+        self.depth.furthest = blocks.keys().next_back().cloned();
+    }
+
+    /// Records a drain up to given block, transactions up to the following block are submitted next.
+    fn drained(&mut self, block_number: BlockNumber, blocks: &BTreeMap<BlockNumber, BlockDatabase>) {
+        self.next_block = block_number + 1;
+        self.depth.next = blocks.range(..(self.next_block + 1)).map(|(_, block)| block.len).sum();
+        self.update_furthest(blocks);
+    }
+}
+
+/// A scheduled transaction in human-readable form (see `Database::export_json`).
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ExportedTransaction {
+    /// Sender of the transaction.
+    pub sender: String,
+    /// Nonce (`None` if the transaction can't be decoded).
+    pub nonce: Option<String>,
+    /// Block (or timestamp) the transaction is stored for.
+    pub target_block_or_time: BlockNumber,
+    /// Gas limit (`None` if the transaction can't be decoded).
+    pub gas: Option<String>,
+    /// Gas price (`None` if the transaction can't be decoded).
+    pub gas_price: Option<String>,
+    /// Hash of the transaction.
+    pub hash: String,
+}
+
+impl ExportedTransaction {
+    fn new(num: BlockNumber, transaction: &Transaction) -> Self {
+        let decoded = verifier::decode(transaction).ok();
+        ExportedTransaction {
+            sender: format!("{:?}", transaction.sender()),
+            nonce: decoded.as_ref().map(|tx| tx.nonce.to_string()),
+            target_block_or_time: num,
+            gas: decoded.as_ref().map(|tx| tx.gas.to_string()),
+            gas_price: decoded.as_ref().map(|tx| tx.gas_price.to_string()),
+            hash: format!("{:?}", transaction.hash()),
+        }
+    }
+}
+
+/// Set in the length prefix of records that carry metadata.
+const EXTENDED: u32 = 1 << 31;
+
+/// Scheduler metadata stored together with a transaction.
+///
+/// Encoded as a sequence of fixed-size fields, new fields can only be appended.
+/// Missing fields are decoded with default values, unknown fields are ignored.
+#[derive(Debug, Default, Clone, PartialEq)]
+struct Metadata {
+    /// Insertion sequence number.
+    sequence: u64,
+    /// Insertion time (unix timestamp in seconds, `0` if unknown).
+    inserted_at: u64,
+    /// Transaction that has to be confirmed before this one is submitted (encoded as zero hash if none).
+    depends_on: Option<H256>,
+    /// Submission priority (encoded as a single byte).
+    priority: Priority,
+    /// Last block the transaction can be included in (encoded as `0` if none).
+    deadline: Option<BlockNumber>,
+}
+
+impl Metadata {
+    fn encode(&self) -> Vec<u8> {
+        let mut vec = Vec::with_capacity(57);
+        vec.write_u64::<LittleEndian>(self.sequence).expect("Writing to Vec is infallible; qed");
+        vec.write_u64::<LittleEndian>(self.inserted_at).expect("Writing to Vec is infallible; qed");
+        vec.extend_from_slice(&*self.depends_on.unwrap_or_else(H256::zero));
+        vec.push(self.priority as u8);
+        vec.write_u64::<LittleEndian>(self.deadline.unwrap_or(0)).expect("Writing to Vec is infallible; qed");
+        vec
+    }
+
+    fn decode(mut bytes: &[u8]) -> Self {
+        let mut metadata = Metadata::default();
+        if let Ok(sequence) = bytes.read_u64::<LittleEndian>() {
+            metadata.sequence = sequence;
+        }
+        if let Ok(inserted_at) = bytes.read_u64::<LittleEndian>() {
+            metadata.inserted_at = inserted_at;
+        }
+        let mut depends_on = [0u8; 32];
+        if bytes.read_exact(&mut depends_on).is_ok() && depends_on != [0u8; 32] {
+            metadata.depends_on = Some(depends_on.into());
+        }
+        if let Ok(priority) = bytes.read_u8() {
+            metadata.priority = Priority::from_u8(priority).unwrap_or_default();
+        }
+        if let Ok(deadline) = bytes.read_u64::<LittleEndian>() {
+            metadata.deadline = if deadline == 0 { None } else { Some(deadline) };
+        }
+        metadata
+    }
+
+    /// Returns true if the transaction was inserted more than `max_age` seconds before `now`.
+    /// Transactions with unknown insertion time are never stale.
+    fn is_stale(&self, now: u64, max_age: u64) -> bool {
+        self.inserted_at != 0 && now.saturating_sub(self.inserted_at) > max_age
+    }
+}
+
 /// A storage for scheduled transactions.
 /// Each block has a separate instance of `BlockDatabase`.
 ///
@@ -40,7 +194,12 @@ pub struct Database {
     path: PathBuf,
     senders: Arc<RwLock<HashMap<Address, usize>>>,
     blocks: RwLock<BTreeMap<BlockNumber, BlockDatabase>>,
+    gauges: Mutex<Gauges>,
     max_txs_per_sender: usize,
+    sequence: AtomicUsize,
+    fifo: bool,
+    max_drain_batch: Option<usize>,
+    clock: Arc<Clock>,
 }
 
 impl Database {
@@ -51,6 +210,7 @@ impl Database {
         fs::create_dir_all(&path)?;
         let mut blocks = BTreeMap::new();
         let mut senders = HashMap::new();
+        let mut sequence = 0;
 
         // Re-open all existing block database that are found
         for entry in fs::read_dir(&path)? {
@@ -63,7 +223,7 @@ impl Database {
             if let Some(Self::EXT) = extension {
                 let file_stem = path.file_stem().and_then(|s| s.to_str()).and_then(|s| s.parse().ok());
                 if let Some(number) = file_stem {
-                    match BlockDatabase::open(&path, &mut senders) {
+                    match BlockDatabase::open(&path, &mut senders, &mut sequence) {
                         Ok(block) => {
                             blocks.insert(number, block);
                         },
@@ -75,19 +235,75 @@ impl Database {
             }
         }
 
+        let mut gauges = Gauges::default();
+        for (num, block) in &blocks {
+            gauges.added(*num, block.len);
+        }
+
         Ok(Database {
             path: path.as_ref().to_owned(),
             senders: Arc::new(RwLock::new(senders)),
             blocks: RwLock::new(blocks),
+            gauges: Mutex::new(gauges),
             max_txs_per_sender,
+            sequence: AtomicUsize::new(sequence as usize),
+            fifo: false,
+            max_drain_batch: None,
+            clock: Arc::new(SystemClock),
         })
     }
 
+    /// Drain transactions in the order they were inserted (instead of ordering by block first).
+    pub fn with_fifo(mut self, fifo: bool) -> Self {
+        self.fifo = fifo;
+        self
+    }
+
+    /// Drain at most `limit` transactions at once, the rest is left for subsequent drains.
+    pub fn with_max_drain_batch(mut self, limit: Option<usize>) -> Self {
+        self.max_drain_batch = limit;
+        self
+    }
+
+    /// Use given clock to record the insertion time of transactions (see `prune`).
+    pub fn with_clock(mut self, clock: Arc<Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
     /// Returns number of transactions already scheduled from given sender.
     pub fn sender_allowed(&self, sender: &Address) -> bool {
         *self.senders.read().get(sender).unwrap_or(&0) < self.max_txs_per_sender
     }
 
+    /// Returns number of transactions waiting for submission.
+    pub fn pending(&self) -> usize {
+        self.senders.read().values().sum()
+    }
+
+    /// Returns all senders with transactions waiting for submission.
+    pub fn senders(&self) -> HashSet<Address> {
+        self.senders.read().keys().cloned().collect()
+    }
+
+    /// Returns number of modifications of the queue so far (inserts and removals).
+    fn changes(&self) -> usize {
+        self.gauges.lock().changes
+    }
+
+    /// Returns current depth of the queue.
+    /// Transactions scheduled up to the block following the last drained one are considered to be submitted next.
+    ///
+    /// The gauges are maintained on every insert and drain, so it's cheap to call.
+    pub fn queue_depth(&self) -> QueueDepth {
+        self.gauges.lock().depth.clone()
+    }
+
+    /// Returns number of transactions scheduled for blocks (or timestamps) before `now`.
+    pub fn overdue(&self, now: BlockNumber) -> usize {
+        self.blocks.read().range(..now).map(|(_, block)| block.len).sum()
+    }
+
     /// Inserts new transactions to the store.
     pub fn insert(&self, block_number: BlockNumber, transaction: Transaction) -> Result<()> {
         if !self.sender_allowed(transaction.sender()) {
@@ -97,15 +313,24 @@ impl Database {
 
         Senders::increment(&mut self.senders.write(), transaction.sender());
         let mut blocks = self.blocks.write();
+        let metadata = Metadata {
+            sequence: self.sequence.fetch_add(1, Ordering::SeqCst) as u64,
+            inserted_at: self.clock.now_secs(),
+            depends_on: transaction.depends_on().cloned(),
+            priority: transaction.priority(),
+            deadline: transaction.deadline(),
+        };
 
         match blocks.entry(block_number) {
             Entry::Vacant(vacant) => {
                 let path = self.path.join(format!("{}.{}", block_number, Self::EXT));
                 let db = BlockDatabase::new(&path)?;
-                vacant.insert(db).insert(transaction)
+                vacant.insert(db).insert(transaction, &metadata)?;
             },
-            Entry::Occupied(ref mut db) => db.get_mut().insert(transaction),
+            Entry::Occupied(ref mut db) => db.get_mut().insert(transaction, &metadata)?,
         }
+        self.gauges.lock().added(block_number, 1);
+        Ok(())
     }
 
     /// Removes a transaction from the store.
@@ -115,12 +340,110 @@ impl Database {
             if let Some(transaction) = block.remove(hash)? {
                 debug!("[:?] Removed from: {}", block_number);
                 Senders::decrement(&mut self.senders.write(), transaction.sender());
+                self.gauges.lock().removed(*block_number, 1);
                 return Ok(Some(transaction))
             }
         }
         Ok(None)
     }
 
+    /// Removes all transactions scheduled for given block.
+    /// Returns the number of removed transactions.
+    pub fn flush(&self, block_number: &BlockNumber) -> Result<usize> {
+        trace!("Attempting to flush: {}", block_number);
+        let block = {
+            let mut blocks = self.blocks.write();
+            let block = blocks.remove(block_number);
+            if let Some(ref block) = block {
+                let mut gauges = self.gauges.lock();
+                gauges.removed(*block_number, block.len);
+                gauges.update_furthest(&blocks);
+            }
+            block
+        };
+        match block {
+            Some(block) => {
+                let removed = block.drain(self.senders.clone())?.count();
+                debug!("Flushed {} transactions from: {}", removed, block_number);
+                Ok(removed)
+            },
+            None => Ok(0),
+        }
+    }
+
+    /// Removes all transactions inserted more than `max_age` seconds before `now`
+    /// regardless of the block they are scheduled for.
+    /// Returns the number of removed transactions.
+    pub fn prune(&self, now: u64, max_age: u64) -> Result<usize> {
+        let mut blocks = self.blocks.write();
+        let mut gauges = self.gauges.lock();
+        let mut removed = 0;
+        let mut empty = Vec::new();
+        for (num, block) in blocks.iter_mut() {
+            let pruned = block.retain(|metadata| !metadata.is_stale(now, max_age), &self.senders)?;
+            if pruned > 0 {
+                debug!("Pruned {} stale transactions from: {}", pruned, num);
+                gauges.removed(*num, pruned);
+            }
+            if block.len == 0 {
+                empty.push(*num);
+            }
+            removed += pruned;
+        }
+        for num in empty {
+            if let Some(block) = blocks.remove(&num) {
+                if let Err(err) = fs::remove_file(&block.path) {
+                    warn!("Unable to remove empty db file at {}: {:?}", block.path.display(), err);
+                }
+            }
+        }
+        gauges.update_furthest(&blocks);
+        Ok(removed)
+    }
+
+    /// Returns first scheduled transaction matching given predicate
+    /// together with the block it's scheduled for.
+    pub fn find<F: FnMut(&Transaction) -> bool>(&self, mut predicate: F) -> Result<Option<(BlockNumber, Transaction)>> {
+        for (num, block) in self.blocks.read().iter() {
+            if let Some(transaction) = block.iter()?.find(|tx| predicate(tx)) {
+                return Ok(Some((*num, transaction)));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Writes all scheduled transactions as a JSON array of `ExportedTransaction` records.
+    /// The export is meant for inspection only, it can't be imported back.
+    pub fn export_json<W: io::Write>(&self, writer: W) -> Result<()> {
+        let records = self.snapshot()?.iter()
+            .map(|&(num, ref transaction)| ExportedTransaction::new(num, transaction))
+            .collect::<Vec<_>>();
+        serde_json::to_writer_pretty(writer, &records)
+            .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+        Ok(())
+    }
+
+    /// Returns all scheduled transactions together with the block (or timestamp) they are scheduled for.
+    pub fn snapshot(&self) -> Result<Vec<(BlockNumber, Transaction)>> {
+        let mut transactions = Vec::new();
+        for (num, block) in self.blocks.read().iter() {
+            transactions.extend(block.iter()?.map(|transaction| (*num, transaction)));
+        }
+        Ok(transactions)
+    }
+
+    /// Returns the target of a scheduled transaction of given sender and nonce (if any).
+    pub fn has_sender_nonce(&self, sender: &Address, nonce: U256) -> Result<Option<BlockNumber>> {
+        if !self.senders.read().contains_key(sender) {
+            return Ok(None);
+        }
+
+        let scheduled = self.find(|scheduled| {
+            scheduled.sender() == sender && verifier::decode(scheduled).map(|tx| tx.nonce == nonce).unwrap_or(false)
+        })?;
+        Ok(scheduled.map(|(num, _)| num))
+    }
+
     /// Returns true if there are any transactions scheduled for given block.
     pub fn has(&self, block_number: &BlockNumber) -> bool {
         match self.blocks.read().keys().next() {
@@ -130,28 +453,152 @@ impl Database {
     }
 
     /// Drains transactions scheduled for submission up to given block number.
+    ///
+    /// Returns `None` if nothing was ever scheduled up to that block
+    /// and an empty iterator if transactions were scheduled, but are not there anymore (e.g. were removed).
     pub fn drain(&self, block_number: BlockNumber) -> Result<Option<TransactionsIterator>> {
-        let blocks = {
-            let mut blocks = self.blocks.write();
-            let mut new = blocks.split_off(&(block_number + 1));
-            mem::swap(&mut *blocks, &mut new);
-            new
+        let drained = match self.max_drain_batch {
+            Some(limit) => self.drain_batch(block_number, limit)?,
+            None => {
+                let blocks = {
+                    let mut blocks = self.blocks.write();
+                    let mut new = blocks.split_off(&(block_number + 1));
+                    mem::swap(&mut *blocks, &mut new);
+                    let mut gauges = self.gauges.lock();
+                    for (num, block) in &new {
+                        gauges.removed(*num, block.len);
+                    }
+                    gauges.drained(block_number, &blocks);
+                    new
+                };
+                let mut drained = Vec::with_capacity(blocks.len());
+                for (num, block) in blocks {
+                    debug!("Draining transactions for block: {}", num);
+                    drained.push(block.drain(self.senders.clone())?);
+                }
+                drained
+            },
         };
-        let mut it = blocks.into_iter();
+
+        let mut it = drained.into_iter();
         let mut tx_it = match it.next() {
             None => return Ok(None),
-            Some((num, block)) => {
-                debug!("Draining transactions for block: {}", num);
-                block.drain(self.senders.clone())?
-            }
+            Some(tx_it) => tx_it,
         };
-        for (num, block) in it {
-            debug!("Draining transactions for block: {}", num);
-            tx_it.append(block.drain(self.senders.clone())?);
+        for other in it {
+            tx_it.append(other);
+        }
+        if self.fifo {
+            tx_it.sort_by_sequence()?;
         }
 
         Ok(Some(tx_it))
     }
+
+    /// Drains up to `limit` transactions scheduled up to given block number (lowest blocks first).
+    /// Partially drained block keeps the remaining transactions.
+    fn drain_batch(&self, block_number: BlockNumber, limit: usize) -> Result<Vec<TransactionsIterator>> {
+        let mut blocks = self.blocks.write();
+        let mut gauges = self.gauges.lock();
+        let numbers = blocks.range(..(block_number + 1)).map(|(num, _)| *num).collect::<Vec<_>>();
+        let mut drained = Vec::new();
+        let mut left = limit;
+        for num in numbers {
+            if left == 0 {
+                debug!("Drained {} transactions. Leaving the rest for later.", limit);
+                break;
+            }
+
+            let len = blocks.get(&num).map(|block| block.len).unwrap_or(0);
+            if len <= left {
+                debug!("Draining transactions for block: {}", num);
+                let block = blocks.remove(&num).expect("Block number was just read from the map; qed");
+                drained.push(block.drain(self.senders.clone())?);
+                gauges.removed(num, len);
+                left -= len;
+            } else {
+                debug!("Draining {} of {} transactions for block: {}", left, len, num);
+                let block = blocks.get_mut(&num).expect("Block number was just read from the map; qed");
+                drained.push(block.take(left, self.senders.clone())?);
+                gauges.removed(num, left);
+                left = 0;
+            }
+        }
+        gauges.drained(block_number, &blocks);
+        Ok(drained)
+    }
+}
+
+/// A read-only copy of the database for status queries, refreshed periodically (see `run_replica`).
+///
+/// Queries never touch the database, so heavy polling doesn't slow down inserts and drains,
+/// but the results may be stale by up to the refresh interval.
+#[derive(Debug, Default)]
+pub struct Replica {
+    transactions: RwLock<Vec<(BlockNumber, Transaction)>>,
+    /// Number of modifications of the database at the time of the last refresh.
+    refreshed_at: Mutex<Option<usize>>,
+}
+
+impl Replica {
+    /// Replaces the content of the replica with current content of the database.
+    /// The database is not read if it didn't change since the last refresh.
+    pub fn refresh(&self, db: &Database) -> Result<()> {
+        let changes = db.changes();
+        if *self.refreshed_at.lock() == Some(changes) {
+            return Ok(());
+        }
+
+        let transactions = db.snapshot()?;
+        *self.transactions.write() = transactions;
+        *self.refreshed_at.lock() = Some(changes);
+        Ok(())
+    }
+
+    /// Returns number of transactions waiting for submission.
+    pub fn pending(&self) -> usize {
+        self.transactions.read().len()
+    }
+
+    /// Returns all senders with transactions waiting for submission.
+    pub fn senders(&self) -> HashSet<Address> {
+        self.transactions.read().iter().map(|&(_, ref transaction)| *transaction.sender()).collect()
+    }
+
+    /// Returns furthest block (or timestamp) with transactions scheduled.
+    pub fn furthest(&self) -> Option<BlockNumber> {
+        self.transactions.read().iter().map(|&(num, _)| num).max()
+    }
+
+    /// Returns number of transactions scheduled for blocks (or timestamps) before `now`.
+    pub fn overdue(&self, now: BlockNumber) -> usize {
+        self.transactions.read().iter().filter(|&&(num, _)| num < now).count()
+    }
+
+    /// Returns the target of a scheduled transaction of given sender and nonce (if any).
+    pub fn has_sender_nonce(&self, sender: &Address, nonce: U256) -> Option<BlockNumber> {
+        self.transactions.read().iter()
+            .find(|&&(_, ref scheduled)| {
+                scheduled.sender() == sender && verifier::decode(scheduled).map(|tx| tx.nonce == nonce).unwrap_or(false)
+            })
+            .map(|&(num, _)| num)
+    }
+}
+
+/// Refreshes the `replica` from the database every `interval`.
+///
+/// This method blocks until the replica is not referenced anywhere else.
+pub fn run_replica(db: &Database, replica: &Arc<Replica>, interval: Duration) {
+    loop {
+        if Arc::strong_count(replica) == 1 {
+            debug!("Replica is not used anymore. Stopping refreshes.");
+            return;
+        }
+        if let Err(err) = replica.refresh(db) {
+            warn!("Unable to refresh replica: {:?}", err);
+        }
+        thread::sleep(interval);
+    }
 }
 
 /// A set of transactions to execute at particular block.
@@ -159,11 +606,13 @@ impl Database {
 struct BlockDatabase {
     path: PathBuf,
     file: fs::File,
+    len: usize,
 }
 
 impl BlockDatabase {
     /// Open existing transactions store and load senders to given `HashMap`.
-    pub fn open<T: AsRef<Path>>(path: T, senders: &mut HashMap<Address, usize>) -> Result<Self> {
+    /// `next_sequence` is bumped above sequence numbers of all loaded transactions.
+    pub fn open<T: AsRef<Path>>(path: T, senders: &mut HashMap<Address, usize>, next_sequence: &mut u64) -> Result<Self> {
         let mut file = fs::OpenOptions::new()
             .read(true)
             .write(true)
@@ -171,15 +620,19 @@ impl BlockDatabase {
 
         trace!("Reading transactions from: {}", path.as_ref().display());
         let mut it = TransactionsIterator::new(&mut file, IteratorMode::ReadOnly)?;
-        while let Some(tx) = it.next() {
+        let mut len = 0;
+        while let Some((metadata, tx)) = it.next_record() {
             trace!("Populating sender: {}", tx.sender());
             Senders::increment(senders, tx.sender());
+            *next_sequence = ::std::cmp::max(*next_sequence, metadata.sequence + 1);
+            len += 1;
         }
         file.seek(io::SeekFrom::Start(0))?;
 
         Ok(BlockDatabase {
             path: path.as_ref().to_owned(),
             file,
+            len,
         })
     }
 
@@ -194,21 +647,27 @@ impl BlockDatabase {
         Ok(BlockDatabase {
             path: path.as_ref().to_owned(),
             file,
+            len: 0,
         })
     }
 
     /// Inserts new transaction to the store.
-    pub fn insert(&mut self, transaction: Transaction) -> Result<()> {
+    fn insert(&mut self, transaction: Transaction, metadata: &Metadata) -> Result<()> {
         trace!("[{:?}] Inserting to db.", transaction.hash());
         let rlp_len = transaction.rlp().len();
-        let mut vec = Vec::with_capacity(4 + 20 + 32 + rlp_len);
-        vec.write_u32::<LittleEndian>(rlp_len as u32)?;
+        let metadata = metadata.encode();
+        let mut vec = Vec::with_capacity(4 + 2 + metadata.len() + 20 + 32 + rlp_len);
+        vec.write_u32::<LittleEndian>(rlp_len as u32 | EXTENDED)?;
+        vec.write_u16::<LittleEndian>(metadata.len() as u16)?;
+        vec.extend_from_slice(&metadata);
         vec.extend_from_slice(&**transaction.sender());
         vec.extend_from_slice(&**transaction.hash());
         vec.extend_from_slice(transaction.rlp());
 
+        self.file.seek(io::SeekFrom::End(0))?;
         self.file.write_all(&vec)?;
         self.file.flush()?;
+        self.len += 1;
         Ok(())
     }
 
@@ -235,9 +694,85 @@ impl BlockDatabase {
         self.file.write_all(&mut content[position_after_tx.. ])?;
         self.file.set_len(new_length as u64)?;
         self.file.flush()?;
+        self.len -= 1;
         Ok(Some(tx))
     }
 
+    /// Removes all transactions which metadata doesn't satisfy the predicate.
+    /// Returns the number of removed transactions.
+    fn retain<F: Fn(&Metadata) -> bool>(&mut self, predicate: F, senders: &RwLock<HashMap<Address, usize>>) -> Result<usize> {
+        let mut it = TransactionsIterator::new(&mut self.file, IteratorMode::ReadOnly)?;
+        let mut kept = Vec::new();
+        let mut removed = 0;
+        while let Some((metadata, transaction)) = it.next_record() {
+            let record = &it.content.get_ref()[it.position_before_tx as usize..it.content.position() as usize];
+            if predicate(&metadata) {
+                kept.extend_from_slice(record);
+            } else {
+                trace!("[{:?}] Removing from db.", transaction.hash());
+                Senders::decrement(&mut senders.write(), transaction.sender());
+                removed += 1;
+            }
+        }
+
+        if removed > 0 {
+            self.file.seek(io::SeekFrom::Start(0))?;
+            self.file.write_all(&kept)?;
+            self.file.set_len(kept.len() as u64)?;
+            self.file.flush()?;
+            self.len -= removed;
+        }
+        Ok(removed)
+    }
+
+    /// Removes `count` transactions of the highest priority (first inserted ones within the same priority)
+    /// from the store and returns them.
+    fn take(&mut self, count: usize, senders: Arc<RwLock<HashMap<Address, usize>>>) -> Result<TransactionsIterator> {
+        let mut it = TransactionsIterator::new(&mut self.file, IteratorMode::ReadOnly)?;
+        let mut records = Vec::new();
+        while let Some((metadata, _)) = it.next_record() {
+            records.push((metadata.priority, it.position_before_tx as usize, it.content.position() as usize));
+        }
+
+        let mut order = (0..records.len()).collect::<Vec<_>>();
+        order.sort_by(|a, b| records[*b].0.cmp(&records[*a].0));
+        let mut is_taken = vec![false; records.len()];
+        for &index in order.iter().take(count) {
+            is_taken[index] = true;
+        }
+        let taken = ::std::cmp::min(count, records.len());
+
+        let (content, rest) = {
+            let all = it.content.get_ref();
+            let (mut content, mut rest) = (Vec::new(), Vec::new());
+            for (&(_, from, to), is_taken) in records.iter().zip(is_taken) {
+                if is_taken {
+                    content.extend_from_slice(&all[from..to]);
+                } else {
+                    rest.extend_from_slice(&all[from..to]);
+                }
+            }
+            (content, rest)
+        };
+        self.file.seek(io::SeekFrom::Start(0))?;
+        self.file.write_all(&rest)?;
+        self.file.set_len(rest.len() as u64)?;
+        self.file.flush()?;
+        self.len -= taken;
+
+        Ok(TransactionsIterator {
+            content: io::Cursor::new(content),
+            position_before_tx: 0,
+            mode: IteratorMode::Drain(senders, Vec::new()),
+        })
+    }
+
+    /// Reads all transactions using a separate file handle, so readers don't need exclusive access.
+    fn iter(&self) -> Result<TransactionsIterator> {
+        let mut file = fs::File::open(&self.path)?;
+        Ok(TransactionsIterator::new(&mut file, IteratorMode::ReadOnly)?)
+    }
+
     fn drain(mut self, senders: Arc<RwLock<HashMap<Address, usize>>>) -> Result<TransactionsIterator> {
         trace!("Draining transactions from: {}", self.path.display());
         Ok(TransactionsIterator::new(&mut self.file, IteratorMode::Drain(senders, vec![self.path]))?)
@@ -274,6 +809,11 @@ impl TransactionsIterator {
         })
     }
 
+    /// Returns true if there are no more transactions to read.
+    pub fn is_empty(&self) -> bool {
+        self.content.position() as usize >= self.content.get_ref().len()
+    }
+
     /// Join two iterators together.
     /// Both files will be removed if both iterators are in `Drain` mode.
     pub fn append(&mut self, other: Self) {
@@ -286,29 +826,43 @@ impl TransactionsIterator {
     }
 }
 
-impl Iterator for TransactionsIterator {
-    type Item = Transaction;
+impl TransactionsIterator {
+    /// Reorders remaining transactions by their insertion sequence.
+    fn sort_by_sequence(&mut self) -> io::Result<()> {
+        let start = self.content.position() as usize;
+        let mut records = Vec::new();
+        loop {
+            let from = self.content.position() as usize;
+            match read_record(&mut self.content) {
+                Ok((metadata, _)) => records.push((metadata.sequence, from, self.content.position() as usize)),
+                Err(ref err) if err.kind() == io::ErrorKind::UnexpectedEof => break,
+                Err(err) => return Err(err),
+            }
+        }
+        records.sort_by_key(|&(sequence, _, _)| sequence);
 
-    fn next(&mut self) -> Option<Self::Item> {
-        let read_transaction = |content: &mut io::Cursor<_>| -> io::Result<_> {
-            let mut sender = [0u8; 20];
-            let mut hash = [0u8; 32];
-            let rlp_len = content.read_u32::<LittleEndian>()? as usize;
-            let mut rlp = Vec::with_capacity(rlp_len);
-            rlp.resize(rlp_len, 0);
-            content.read_exact(&mut sender)?;
-            content.read_exact(&mut hash)?;
-            content.read_exact(&mut rlp)?;
-            Ok(Transaction::new(sender.into(), hash.into(), rlp))
+        let sorted = {
+            let content = self.content.get_ref();
+            let mut sorted = Vec::with_capacity(content.len());
+            sorted.extend_from_slice(&content[..start]);
+            for (_, from, to) in records {
+                sorted.extend_from_slice(&content[from..to]);
+            }
+            sorted
         };
+        self.content = io::Cursor::new(sorted);
+        self.content.set_position(start as u64);
+        Ok(())
+    }
 
+    fn next_record(&mut self) -> Option<(Metadata, Transaction)> {
         self.position_before_tx = self.content.position();
-        match read_transaction(&mut self.content) {
-            Ok(transaction) => {
+        match read_record(&mut self.content) {
+            Ok((metadata, transaction)) => {
                 if let IteratorMode::Drain(ref senders, _) = self.mode {
                     Senders::decrement(&mut senders.write(), transaction.sender());
                 }
-                Some(transaction)
+                Some((metadata, transaction))
             },
             // Cursor is drained
             Err(ref err) if err.kind() == io::ErrorKind::UnexpectedEof => {
@@ -333,6 +887,44 @@ impl Iterator for TransactionsIterator {
     }
 }
 
+impl Iterator for TransactionsIterator {
+    type Item = Transaction;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_record().map(|(_, transaction)| transaction)
+    }
+}
+
+/// Reads a single record (with optional metadata) from the content.
+fn read_record(content: &mut io::Cursor<Vec<u8>>) -> io::Result<(Metadata, Transaction)> {
+    let mut sender = [0u8; 20];
+    let mut hash = [0u8; 32];
+    let len = content.read_u32::<LittleEndian>()?;
+    let metadata = if len & EXTENDED != 0 {
+        let mut metadata = vec![0u8; content.read_u16::<LittleEndian>()? as usize];
+        content.read_exact(&mut metadata)?;
+        Metadata::decode(&metadata)
+    } else {
+        Metadata::default()
+    };
+    let rlp_len = (len & !EXTENDED) as usize;
+    let mut rlp = Vec::with_capacity(rlp_len);
+    rlp.resize(rlp_len, 0);
+    content.read_exact(&mut sender)?;
+    content.read_exact(&mut hash)?;
+    content.read_exact(&mut rlp)?;
+    let transaction = Transaction::new(sender.into(), hash.into(), rlp).with_priority(metadata.priority);
+    let transaction = match metadata.depends_on {
+        Some(depends_on) => transaction.with_dependency(depends_on),
+        None => transaction,
+    };
+    let transaction = match metadata.deadline {
+        Some(deadline) => transaction.with_deadline(deadline),
+        None => transaction,
+    };
+    Ok((metadata, transaction))
+}
+
 struct Senders;
 
 impl Senders {
@@ -360,33 +952,18 @@ impl Senders {
 
 #[cfg(test)]
 mod tests {
-    use ethcore::transaction::SignedTransaction;
-    use rlp::UntrustedRlp;
-    use rustc_hex::FromHex;
     use tempdir::TempDir;
+    use types::tests::tx;
     use super::*;
 
-    fn tx(id: u64) -> Transaction {
-        let tx = match id {
-            0 => "f864808504a817c800825208943535353535353535353535353535353535353535808025a0044852b2a670ade5407e78fb2863c51de9fcb96542a07186fe3aeda6bb8a116da0044852b2a670ade5407e78fb2863c51de9fcb96542a07186fe3aeda6bb8a116d",
-            1 => "f864018504a817c80182a410943535353535353535353535353535353535353535018025a0489efdaa54c0f20c7adf612882df0950f5a951637e0307cdcb4c672f298b8bcaa0489efdaa54c0f20c7adf612882df0950f5a951637e0307cdcb4c672f298b8bc6",
-            2 => "f864028504a817c80282f618943535353535353535353535353535353535353535088025a02d7c5bef027816a800da1736444fb58a807ef4c9603b7848673f7e3a68eb14a5a02d7c5bef027816a800da1736444fb58a807ef4c9603b7848673f7e3a68eb14a5",
-            3 => "f865038504a817c803830148209435353535353535353535353535353535353535351b8025a02a80e1ef1d7842f27f2e6be0972bb708b9a135c38860dbe73c27c3486c34f4e0a02a80e1ef1d7842f27f2e6be0972bb708b9a135c38860dbe73c27c3486c34f4de",
-            _ => panic!("Unknown id."),
-        };
-        let transaction = FromHex::from_hex(tx).unwrap();
-        let rlp = UntrustedRlp::new(&transaction).as_val().unwrap();
-        SignedTransaction::new(rlp).unwrap().into()
-    }
-
     #[test]
     fn should_save_transactions_to_disk() {
         let _ = ::env_logger::init();
         let dir = TempDir::new("db1").unwrap();
         let mut db = BlockDatabase::new(dir.path().join("test.txs")).unwrap();
-        db.insert(tx(0)).unwrap();
-        db.insert(tx(1)).unwrap();
-        db.insert(tx(2)).unwrap();
+        db.insert(tx(0), &Default::default()).unwrap();
+        db.insert(tx(1), &Default::default()).unwrap();
+        db.insert(tx(2), &Default::default()).unwrap();
 
         let mut iter = db.drain(Default::default()).unwrap();
         assert_eq!(iter.next(), Some(tx(0)));
@@ -454,4 +1031,202 @@ mod tests {
         assert_eq!(iter.next(), Some(tx(0)));
         assert_eq!(iter.next(), None);
     }
+
+    #[test]
+    fn should_flush_block() {
+        let dir = TempDir::new("db1").unwrap();
+        let db = Database::open(dir.path(), 1).unwrap();
+        db.insert(5, tx(0)).unwrap();
+        db.insert(5, tx(1)).unwrap();
+        db.insert(5, tx(2)).unwrap();
+
+        assert_eq!(db.pending(), 3);
+        assert_eq!(db.flush(&5).unwrap(), 3);
+        assert_eq!(db.pending(), 0);
+        assert_eq!(db.flush(&5).unwrap(), 0);
+        assert_eq!(db.has(&5), false);
+        // Senders should be allowed again.
+        db.insert(6, tx(0)).unwrap();
+    }
+
+    #[test]
+    fn should_find_scheduled_sender_nonce() {
+        use types::tests::signed;
+
+        let dir = TempDir::new("db1").unwrap();
+        let db = Database::open(dir.path(), 2).unwrap();
+        let (a, b) = (Address::from(1), Address::from(2));
+        db.insert(5, signed(a, 0)).unwrap();
+        db.insert(7, signed(a, 1)).unwrap();
+
+        assert_eq!(db.has_sender_nonce(&a, 0.into()).unwrap(), Some(5));
+        assert_eq!(db.has_sender_nonce(&a, 1.into()).unwrap(), Some(7));
+        assert_eq!(db.has_sender_nonce(&a, 2.into()).unwrap(), None);
+        assert_eq!(db.has_sender_nonce(&b, 0.into()).unwrap(), None);
+    }
+
+    #[test]
+    fn should_export_scheduled_transactions_as_json() {
+        let dir = TempDir::new("db1").unwrap();
+        let db = Database::open(dir.path(), 1).unwrap();
+        db.insert(5, tx(0)).unwrap();
+
+        let mut out = Vec::new();
+        db.export_json(&mut out).unwrap();
+        let exported: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        let decoded = verifier::decode(&tx(0)).unwrap();
+
+        assert_eq!(exported.as_array().map(Vec::len), Some(1));
+        let record = &exported[0];
+        assert_eq!(record["sender"], serde_json::Value::String(format!("{:?}", tx(0).sender())));
+        assert_eq!(record["nonce"], serde_json::Value::String(decoded.nonce.to_string()));
+        assert_eq!(record["target_block_or_time"], serde_json::Value::from(5));
+        assert_eq!(record["gas"], serde_json::Value::String(decoded.gas.to_string()));
+        assert_eq!(record["gas_price"], serde_json::Value::String(decoded.gas_price.to_string()));
+        assert_eq!(record["hash"], serde_json::Value::String(format!("{:?}", tx(0).hash())));
+    }
+
+    #[test]
+    fn should_report_queue_depth() {
+        let dir = TempDir::new("db1").unwrap();
+        let db = Database::open(dir.path(), 1).unwrap();
+        assert_eq!(db.queue_depth(), QueueDepth::default());
+
+        db.insert(5, tx(0)).unwrap();
+        db.insert(5, tx(1)).unwrap();
+        db.insert(7, tx(2)).unwrap();
+        assert_eq!(db.queue_depth(), QueueDepth { pending: 3, next: 0, furthest: Some(7) });
+
+        // Block 5 follows the drained one.
+        assert!(db.drain(4).unwrap().is_none());
+        assert_eq!(db.queue_depth(), QueueDepth { pending: 3, next: 2, furthest: Some(7) });
+
+        assert_eq!(db.drain(5).unwrap().unwrap().count(), 2);
+        assert_eq!(db.queue_depth(), QueueDepth { pending: 1, next: 0, furthest: Some(7) });
+
+        db.insert(6, tx(3)).unwrap();
+        assert_eq!(db.queue_depth(), QueueDepth { pending: 2, next: 1, furthest: Some(7) });
+
+        assert_eq!(db.flush(&7).unwrap(), 1);
+        assert_eq!(db.queue_depth(), QueueDepth { pending: 1, next: 1, furthest: Some(6) });
+
+        // Restart
+        drop(db);
+        let db = Database::open(dir.path(), 1).unwrap();
+        assert_eq!(db.queue_depth(), QueueDepth { pending: 1, next: 0, furthest: Some(6) });
+    }
+
+    #[test]
+    fn should_drain_in_insertion_order() {
+        let dir = TempDir::new("db1").unwrap();
+        {
+            let db = Database::open(dir.path(), 1).unwrap();
+            db.insert(5, tx(0)).unwrap();
+            db.insert(3, tx(1)).unwrap();
+        }
+
+        // Sequence should continue after re-opening.
+        let db = Database::open(dir.path(), 1).unwrap().with_fifo(true);
+        db.insert(4, tx(2)).unwrap();
+
+        let mut iter = db.drain(5).unwrap().unwrap();
+        assert_eq!(iter.next(), Some(tx(0)));
+        assert_eq!(iter.next(), Some(tx(1)));
+        assert_eq!(iter.next(), Some(tx(2)));
+        assert_eq!(iter.next(), None);
+        assert_eq!(db.pending(), 0);
+    }
+
+    #[test]
+    fn should_distinguish_empty_block_from_never_scheduled() {
+        let dir = TempDir::new("db1").unwrap();
+        let db = Database::open(dir.path(), 1).unwrap();
+        assert!(db.drain(3).unwrap().is_none());
+
+        db.insert(5, tx(0)).unwrap();
+        db.remove(&5, tx(0).hash()).unwrap();
+        let iter = db.drain(5).unwrap().unwrap();
+        assert!(iter.is_empty());
+        assert_eq!(iter.count(), 0);
+    }
+
+    #[test]
+    fn should_drain_in_batches() {
+        use types::tests::signed;
+
+        let dir = TempDir::new("db1").unwrap();
+        let db = Database::open(dir.path(), 1).unwrap().with_max_drain_batch(Some(40));
+        for i in 0..100 {
+            db.insert(5 + i % 3, signed(Address::from(i + 1), 0)).unwrap();
+        }
+
+        assert_eq!(db.drain(7).unwrap().unwrap().count(), 40);
+        assert_eq!(db.pending(), 60);
+        assert_eq!(db.drain(7).unwrap().unwrap().count(), 40);
+        assert_eq!(db.drain(7).unwrap().unwrap().count(), 20);
+        assert!(db.drain(7).unwrap().is_none());
+        assert_eq!(db.pending(), 0);
+    }
+
+    #[test]
+    fn should_keep_highest_priority_transactions_in_batch() {
+        let dir = TempDir::new("db1").unwrap();
+        let db = Database::open(dir.path(), 1).unwrap().with_max_drain_batch(Some(2));
+        db.insert(5, tx(0).with_priority(Priority::Low)).unwrap();
+        db.insert(5, tx(1)).unwrap();
+        db.insert(5, tx(2).with_priority(Priority::High)).unwrap();
+
+        assert_eq!(db.drain(5).unwrap().unwrap().collect::<Vec<_>>(), vec![tx(1), tx(2).with_priority(Priority::High)]);
+        assert_eq!(db.drain(5).unwrap().unwrap().collect::<Vec<_>>(), vec![tx(0).with_priority(Priority::Low)]);
+    }
+
+    #[test]
+    fn should_reflect_inserts_in_replica_after_refresh() {
+        let dir = TempDir::new("db1").unwrap();
+        let db = Arc::new(Database::open(dir.path(), 2).unwrap());
+        let replica = Arc::new(Replica::default());
+        let interval = Duration::from_millis(10);
+        let handle = {
+            let (db, replica) = (db.clone(), replica.clone());
+            thread::spawn(move || run_replica(&db, &replica, interval))
+        };
+        db.insert(5, tx(0)).unwrap();
+        db.insert(7, tx(1)).unwrap();
+
+        let mut waited = Duration::from_secs(0);
+        while replica.pending() < 2 && waited < interval * 100 {
+            thread::sleep(interval);
+            waited += interval;
+        }
+
+        assert_eq!(replica.pending(), 2);
+        assert_eq!(replica.has_sender_nonce(tx(1).sender(), 1.into()), Some(7));
+        assert_eq!(replica.has_sender_nonce(tx(1).sender(), 2.into()), None);
+
+        // Refreshes stop once the replica is dropped.
+        drop(replica);
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn should_read_records_without_metadata() {
+        let dir = TempDir::new("db1").unwrap();
+        {
+            let transaction = tx(0);
+            let mut record = Vec::new();
+            record.write_u32::<LittleEndian>(transaction.rlp().len() as u32).unwrap();
+            record.extend_from_slice(&**transaction.sender());
+            record.extend_from_slice(&**transaction.hash());
+            record.extend_from_slice(transaction.rlp());
+            fs::File::create(dir.path().join("5.txs")).unwrap().write_all(&record).unwrap();
+        }
+
+        let db = Database::open(dir.path(), 1).unwrap();
+        db.insert(5, tx(1)).unwrap();
+
+        let mut iter = db.drain(5).unwrap().unwrap();
+        assert_eq!(iter.next(), Some(tx(0)));
+        assert_eq!(iter.next(), Some(tx(1)));
+        assert_eq!(iter.next(), None);
+    }
 }
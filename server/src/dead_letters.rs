@@ -0,0 +1,117 @@
+//! Transactions that failed permanently
+
+use std::collections::VecDeque;
+
+use parking_lot::Mutex;
+
+use types::{Bytes, Transaction, H256};
+
+/// A transaction that failed permanently together with the reason.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeadLetter {
+    /// The failed transaction.
+    pub transaction: Transaction,
+    /// Reason of the failure.
+    pub reason: String,
+}
+
+/// A dead letter in human-readable form (see `DeadLetters::export`).
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ExportedDeadLetter {
+    /// Hash of the transaction.
+    pub hash: String,
+    /// Sender of the transaction.
+    pub sender: String,
+    /// Reason of the failure.
+    pub reason: String,
+    /// Raw transaction (can be scheduled again as-is).
+    pub transaction: Bytes,
+}
+
+/// Keeps transactions that failed permanently for inspection (instead of dropping them).
+///
+/// Holds at most `capacity` transactions, the oldest ones are evicted first.
+#[derive(Debug)]
+pub struct DeadLetters {
+    capacity: usize,
+    letters: Mutex<VecDeque<DeadLetter>>,
+}
+
+impl Default for DeadLetters {
+    fn default() -> Self {
+        DeadLetters::new(1024)
+    }
+}
+
+impl DeadLetters {
+    /// Creates a store holding at most `capacity` transactions.
+    pub fn new(capacity: usize) -> Self {
+        DeadLetters {
+            capacity,
+            letters: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Stores given failed transaction (evicting the oldest one if the store is full).
+    pub fn insert(&self, transaction: Transaction, reason: String) {
+        warn!("[{:?}] Moving to dead letters: {}", transaction.hash(), reason);
+        let mut letters = self.letters.lock();
+        if letters.len() >= self.capacity {
+            if let Some(evicted) = letters.pop_front() {
+                debug!("[{:?}] Evicted from dead letters.", evicted.transaction.hash());
+            }
+        }
+        if self.capacity > 0 {
+            letters.push_back(DeadLetter { transaction, reason });
+        }
+    }
+
+    /// Returns the failed transaction of given hash (if stored).
+    pub fn get(&self, hash: &H256) -> Option<DeadLetter> {
+        self.letters.lock().iter().find(|letter| letter.transaction.hash() == hash).cloned()
+    }
+
+    /// Returns all stored transactions (oldest first).
+    pub fn all(&self) -> Vec<DeadLetter> {
+        self.letters.lock().iter().cloned().collect()
+    }
+
+    /// Returns all stored transactions in human-readable form (oldest first).
+    pub fn export(&self) -> Vec<ExportedDeadLetter> {
+        self.letters.lock().iter().map(|letter| ExportedDeadLetter {
+            hash: format!("{:?}", letter.transaction.hash()),
+            sender: format!("{:?}", letter.transaction.sender()),
+            reason: letter.reason.clone(),
+            transaction: Bytes::new(letter.transaction.rlp().to_vec()),
+        }).collect()
+    }
+
+    /// Returns number of stored transactions.
+    pub fn len(&self) -> usize {
+        self.letters.lock().len()
+    }
+
+    /// Returns true if there are no stored transactions.
+    pub fn is_empty(&self) -> bool {
+        self.letters.lock().is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use types::tests::tx;
+    use super::*;
+
+    #[test]
+    fn should_evict_oldest_dead_letters() {
+        let dead_letters = DeadLetters::new(2);
+        dead_letters.insert(tx(0), "first".into());
+        dead_letters.insert(tx(1), "second".into());
+        dead_letters.insert(tx(2), "third".into());
+
+        assert_eq!(dead_letters.len(), 2);
+        assert_eq!(dead_letters.get(tx(0).hash()), None);
+        assert_eq!(dead_letters.all().into_iter().map(|letter| letter.reason).collect::<Vec<_>>(), vec!["second", "third"]);
+        assert_eq!(dead_letters.export()[1].transaction, Bytes::new(tx(2).rlp().to_vec()));
+    }
+}
@@ -35,3 +35,26 @@ pub fn internal<T: ::std::fmt::Display>(error: T) -> Error {
 		data: Some(Value::String(format!("{}", error))),
 	}
 }
+pub fn retry_later<T: ::std::fmt::Display>(reason: T, retry_after: u64) -> Error {
+	let mut data = ::serde_json::Map::new();
+	data.insert("retryAfter".into(), retry_after.into());
+	Error {
+		code: ErrorCode::ServerError(-32011),
+		message: format!("{} Retry later.", reason),
+		data: Some(Value::Object(data)),
+	}
+}
+pub fn unavailable<T: ::std::fmt::Display>(error: T) -> Error {
+	Error {
+		code: ErrorCode::ServerError(-32012),
+		message: "Scheduler unavailable.".into(),
+		data: Some(Value::String(format!("{}", error))),
+	}
+}
+pub fn unauthorized() -> Error {
+	Error {
+		code: ErrorCode::ServerError(-32010),
+		message: "Unauthorized.".into(),
+		data: None,
+	}
+}
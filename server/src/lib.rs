@@ -34,16 +34,22 @@ extern crate tempdir;
 extern crate env_logger;
 
 pub mod blockchain;
+pub mod clock;
 pub mod database;
+pub mod dead_letters;
+pub mod metrics;
+pub mod scheduler;
 pub mod server;
 pub mod submitter;
+pub mod tracker;
 
 mod errors;
 mod options;
 mod types;
 mod verifier;
 
-pub use options::Options;
+pub use options::{BalanceMargin, GasSchedule, Options};
+pub use verifier::{DecodedTransaction, Decoder, Decoders, Policy, Rejection};
 
 /// Type of the transport to instantiate.
 #[derive(Debug, Clone)]
@@ -52,4 +58,24 @@ pub enum TransportType {
     Ipc(String),
     /// HTTP transport (can be remote)
     Http(String),
+    /// A transport with given weight (endpoints with higher weight are preferred)
+    Weighted(Box<TransportType>, u32),
+}
+
+impl TransportType {
+    /// Assigns given weight to the transport.
+    pub fn with_weight(self, weight: u32) -> Self {
+        match self {
+            TransportType::Weighted(transport, _) => TransportType::Weighted(transport, weight),
+            transport => TransportType::Weighted(Box::new(transport), weight),
+        }
+    }
+
+    /// Returns weight of the transport (`0` if not set).
+    pub fn weight(&self) -> u32 {
+        match *self {
+            TransportType::Weighted(_, weight) => weight,
+            _ => 0,
+        }
+    }
 }
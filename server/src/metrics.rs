@@ -0,0 +1,278 @@
+//! Submission metrics
+
+use std::fmt::Write;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use std::{fs, io, thread};
+
+use parking_lot::Mutex;
+use serde_json;
+
+use database::QueueDepth;
+
+/// Counters describing the activity of the submitter.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    submitted: AtomicUsize,
+    errors: AtomicUsize,
+    skipped: AtomicUsize,
+    overdue: AtomicUsize,
+    dropped_per_endpoint: Mutex<Vec<u64>>,
+}
+
+/// Values of the counters that can be persisted across restarts.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Snapshot {
+    /// Transactions submitted to endpoints.
+    pub submitted: u64,
+    /// Errors while submitting transactions.
+    pub errors: u64,
+    /// Transactions skipped at the time of submission.
+    pub skipped: u64,
+    /// Transactions dropped for each endpoint.
+    pub dropped_per_endpoint: Vec<u64>,
+}
+
+impl From<Snapshot> for Metrics {
+    fn from(snapshot: Snapshot) -> Self {
+        Metrics {
+            submitted: AtomicUsize::new(snapshot.submitted as usize),
+            errors: AtomicUsize::new(snapshot.errors as usize),
+            skipped: AtomicUsize::new(snapshot.skipped as usize),
+            overdue: AtomicUsize::new(0),
+            dropped_per_endpoint: Mutex::new(snapshot.dropped_per_endpoint),
+        }
+    }
+}
+
+impl Metrics {
+    /// Restores metrics persisted at given path (starting from zero if the file doesn't exist).
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let file = match fs::File::open(path) {
+            Ok(file) => file,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(Default::default()),
+            Err(err) => return Err(err),
+        };
+        let snapshot: Snapshot = serde_json::from_reader(file)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        Ok(snapshot.into())
+    }
+
+    /// Persists current values of the counters at given path.
+    /// The file is replaced atomically, so a crash never leaves it corrupted.
+    pub fn persist<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let path = path.as_ref();
+        let tmp = path.with_extension("tmp");
+        {
+            let file = fs::File::create(&tmp)?;
+            serde_json::to_writer(&file, &self.snapshot())
+                .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+            file.sync_all()?;
+        }
+        fs::rename(tmp, path)
+    }
+
+    /// Returns current values of the counters.
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            submitted: self.submitted.load(Ordering::Relaxed) as u64,
+            errors: self.errors.load(Ordering::Relaxed) as u64,
+            skipped: self.skipped.load(Ordering::Relaxed) as u64,
+            dropped_per_endpoint: self.dropped_per_endpoint(),
+        }
+    }
+
+    /// Records a transaction successfuly submitted to an endpoint.
+    pub fn submitted(&self) {
+        self.submitted.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records an error while submitting a transaction to an endpoint.
+    pub fn error(&self) {
+        self.errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records a transaction skipped because it would fail at the time of submission.
+    pub fn skipped(&self) {
+        self.skipped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Returns number of transactions submitted to endpoints.
+    pub fn total_submitted(&self) -> usize {
+        self.submitted.load(Ordering::Relaxed)
+    }
+
+    /// Returns number of errors while submitting transactions.
+    pub fn total_errors(&self) -> usize {
+        self.errors.load(Ordering::Relaxed)
+    }
+
+    /// Returns number of transactions skipped at the time of submission.
+    pub fn total_skipped(&self) -> usize {
+        self.skipped.load(Ordering::Relaxed)
+    }
+
+    /// Records number of transactions found overdue by the last audit.
+    pub fn set_overdue(&self, overdue: usize) {
+        self.overdue.store(overdue, Ordering::Relaxed);
+    }
+
+    /// Returns number of transactions found overdue by the last audit.
+    pub fn overdue(&self) -> usize {
+        self.overdue.load(Ordering::Relaxed)
+    }
+
+    /// Records a transaction dropped for given endpoint (because it's channel was full).
+    pub fn dropped(&self, endpoint: usize) {
+        let mut dropped = self.dropped_per_endpoint.lock();
+        if dropped.len() <= endpoint {
+            dropped.resize(endpoint + 1, 0);
+        }
+        dropped[endpoint] += 1;
+    }
+
+    /// Returns number of dropped transactions for each endpoint.
+    pub fn dropped_per_endpoint(&self) -> Vec<u64> {
+        self.dropped_per_endpoint.lock().clone()
+    }
+
+    /// Renders the metrics in Prometheus text exposition format.
+    ///
+    /// `queues` is a list of scheduling modes (like `block` or `timestamp`)
+    /// together with the depth of their queue (see `Database::queue_depth`).
+    /// `confirmed` is the number of submitted transactions the tracker saw
+    /// confirmed on chain (see `Tracker::confirmed`).
+    pub fn prometheus(&self, queues: &[(&str, QueueDepth)], confirmed: usize) -> String {
+        let mut out = String::new();
+        {
+            let mut metric = |name: &str, kind: &str, help: &str, values: Vec<(String, u64)>| {
+                let _ = writeln!(out, "# HELP {} {}", name, help);
+                let _ = writeln!(out, "# TYPE {} {}", name, kind);
+                for (labels, value) in values {
+                    let _ = writeln!(out, "{}{} {}", name, labels, value);
+                }
+            };
+
+            metric(
+                "txsched_submitted_total", "counter", "Transactions submitted to endpoints.",
+                vec![(String::new(), self.submitted.load(Ordering::Relaxed) as u64)],
+            );
+            metric(
+                "txsched_errors_total", "counter", "Errors while submitting transactions to endpoints.",
+                vec![(String::new(), self.errors.load(Ordering::Relaxed) as u64)],
+            );
+            metric(
+                "txsched_skipped_total", "counter", "Transactions skipped because they would fail at the time of submission.",
+                vec![(String::new(), self.skipped.load(Ordering::Relaxed) as u64)],
+            );
+            metric(
+                "txsched_dropped_total", "counter", "Transactions dropped because endpoint was busy.",
+                self.dropped_per_endpoint().into_iter().enumerate()
+                    .map(|(endpoint, dropped)| (format!("{{endpoint=\"{}\"}}", endpoint), dropped))
+                    .collect(),
+            );
+            metric(
+                "txsched_overdue", "gauge", "Transactions scheduled in the past that were not submitted yet.",
+                vec![(String::new(), self.overdue.load(Ordering::Relaxed) as u64)],
+            );
+            metric(
+                "txsched_confirmed_total", "counter", "Submitted transactions confirmed on chain.",
+                vec![(String::new(), confirmed as u64)],
+            );
+            metric(
+                "txsched_queue_depth", "gauge", "Transactions waiting for submission.",
+                queues.iter()
+                    .map(|&(mode, ref depth)| (format!("{{mode=\"{}\"}}", mode), depth.pending as u64))
+                    .collect(),
+            );
+            metric(
+                "txsched_queue_next", "gauge", "Transactions to be submitted in the next block (or second).",
+                queues.iter()
+                    .map(|&(mode, ref depth)| (format!("{{mode=\"{}\"}}", mode), depth.next as u64))
+                    .collect(),
+            );
+            metric(
+                "txsched_queue_furthest", "gauge", "Furthest block (or timestamp) with transactions scheduled.",
+                queues.iter()
+                    .filter_map(|&(mode, ref depth)| {
+                        depth.furthest.map(|furthest| (format!("{{mode=\"{}\"}}", mode), furthest))
+                    })
+                    .collect(),
+            );
+        }
+        out
+    }
+}
+
+/// Persists the metrics at given path every `interval`.
+///
+/// This method blocks indefinitely.
+pub fn run_persist<P: AsRef<Path>>(metrics: &Metrics, path: P, interval: Duration) {
+    loop {
+        thread::sleep(interval);
+        if let Err(err) = metrics.persist(path.as_ref()) {
+            warn!("Unable to persist metrics: {:?}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempdir::TempDir;
+    use super::*;
+
+    #[test]
+    fn should_render_prometheus_metrics() {
+        let metrics = Metrics::default();
+        metrics.submitted();
+        metrics.submitted();
+        metrics.error();
+        metrics.skipped();
+        metrics.dropped(1);
+        metrics.set_overdue(2);
+
+        let block = QueueDepth { pending: 3, next: 1, furthest: Some(7) };
+        let out = metrics.prometheus(&[("block", block), ("timestamp", QueueDepth::default())], 4);
+
+        assert!(out.contains("# TYPE txsched_submitted_total counter\ntxsched_submitted_total 2\n"));
+        assert!(out.contains("\ntxsched_errors_total 1\n"));
+        assert!(out.contains("\ntxsched_skipped_total 1\n"));
+        assert!(out.contains("\ntxsched_dropped_total{endpoint=\"0\"} 0\n"));
+        assert!(out.contains("\ntxsched_dropped_total{endpoint=\"1\"} 1\n"));
+        assert!(out.contains("# TYPE txsched_overdue gauge\ntxsched_overdue 2\n"));
+        assert!(out.contains("# TYPE txsched_confirmed_total counter\ntxsched_confirmed_total 4\n"));
+        assert!(out.contains("# TYPE txsched_queue_depth gauge\n"));
+        assert!(out.contains("\ntxsched_queue_depth{mode=\"block\"} 3\n"));
+        assert!(out.contains("\ntxsched_queue_depth{mode=\"timestamp\"} 0\n"));
+        assert!(out.contains("\ntxsched_queue_next{mode=\"block\"} 1\n"));
+        assert!(out.contains("\ntxsched_queue_furthest{mode=\"block\"} 7\n"));
+        assert!(!out.contains("txsched_queue_furthest{mode=\"timestamp\"}"));
+    }
+
+    #[test]
+    fn should_restore_persisted_metrics() {
+        let dir = TempDir::new("metrics").unwrap();
+        let path = dir.path().join("metrics.json");
+        assert_eq!(Metrics::load(&path).unwrap().snapshot(), Snapshot::default());
+
+        let metrics = Metrics::default();
+        metrics.submitted();
+        metrics.submitted();
+        metrics.error();
+        metrics.dropped(1);
+        metrics.persist(&path).unwrap();
+        drop(metrics);
+
+        // Restart
+        let metrics = Metrics::load(&path).unwrap();
+        assert_eq!(metrics.snapshot(), Snapshot {
+            submitted: 2,
+            errors: 1,
+            skipped: 0,
+            dropped_per_endpoint: vec![0, 1],
+        });
+        metrics.submitted();
+        assert_eq!(metrics.total_submitted(), 3);
+    }
+}
@@ -1,3 +1,48 @@
+use std::collections::HashMap;
+
+use types::{Address, U256};
+
+/// Balance required on top of the transaction cost.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct BalanceMargin {
+    /// Absolute margin in wei.
+    pub wei: u64,
+    /// Margin as a percentage of the transaction cost.
+    pub percent: u64,
+}
+
+impl BalanceMargin {
+    /// Returns the balance required to cover given `cost` including the margin.
+    pub fn required(&self, cost: U256) -> U256 {
+        let percent = cost.saturating_mul(self.percent.into()) / U256::from(100);
+        cost.saturating_add(percent).saturating_add(self.wei.into())
+    }
+}
+
+/// Intrinsic gas costs used to compute the minimal gas of a transaction.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GasSchedule {
+    /// Base gas of a call.
+    pub tx: u64,
+    /// Base gas of a contract creation.
+    pub tx_create: u64,
+    /// Gas of every zero byte of data.
+    pub tx_data_zero: u64,
+    /// Gas of every non-zero byte of data.
+    pub tx_data_non_zero: u64,
+}
+
+impl Default for GasSchedule {
+    fn default() -> Self {
+        GasSchedule {
+            tx: 21_000,
+            tx_create: 53_000,
+            tx_data_zero: 4,
+            tx_data_non_zero: 68,
+        }
+    }
+}
+
 /// Transaction Scheduler Server options.
 #[derive(Debug, Clone, PartialEq)]
 pub struct Options {
@@ -5,18 +50,72 @@ pub struct Options {
     pub chain_id: u64,
     /// Maximal gas of a transaction
     pub max_gas: u64,
+    /// Maximal size of a raw transaction (in bytes)
+    pub max_transaction_size: usize,
     /// Minimal gas price
     pub min_gas_price: u64,
+    /// Senders exempt from the minimal gas price (they still need a non-zero gas price)
+    pub gas_price_exempt: Vec<Address>,
+    /// Intrinsic gas costs of the chain.
+    pub gas_schedule: GasSchedule,
+    /// Minimal gas of calls to given contracts (on top of the intrinsic gas).
+    pub min_gas_per_target: HashMap<Address, u64>,
+    /// Use given percentile of gas prices in the latest block as minimal gas price
+    /// (`min_gas_price` is used if it can't be computed).
+    pub min_gas_price_percentile: Option<u8>,
+    /// Require gas price to cover the base fee of the latest block plus given tip (in wei).
+    pub min_tip: Option<u64>,
     /// Max number of blocks in future to schedule for.
     pub max_schedule_block: u64,
     /// Max number of seconds in future to schedule for.
     pub max_schedule_seconds: u64,
+    /// Number of blocks before the latest one that are still accepted for scheduling
+    /// (the latest block itself is always accepted). Such requests are scheduled for the next block.
+    pub min_block_slack: u64,
+    /// Min number of blocks ahead of the latest block to schedule for.
+    pub min_lead_blocks: u64,
+    /// Min number of seconds ahead of current time to schedule for.
+    pub min_lead_seconds: u64,
+    /// Submit transactions N blocks earlier than requested.
+    pub submit_earlier: u64,
+    /// Balance required on top of the transaction cost.
+    pub balance_margin: BalanceMargin,
     /// Require nonce to be equal to current nonce (and not only greater).
     pub strict_nonce: bool,
+    /// Max number of nonces above the current nonce that are still accepted.
+    pub max_nonce_ahead: Option<u64>,
+    /// Reject transactions which gas price may not cover the base fee at target block.
+    pub reject_underpriced: bool,
+    /// Log a warning about transactions which gas price may not cover the base fee at target block.
+    /// The base fee is not queried at all if neither warnings nor rejections are enabled.
+    pub warn_underpriced: bool,
+    /// Reject calls to the zero address.
+    pub reject_zero_address: bool,
+    /// Reject calls to precompiled contracts (`0x1..0x9`).
+    pub reject_precompiles: bool,
+    /// Reject senders with code deployed (contracts can't sign transactions, so it's most likely a mistake).
+    pub reject_contract_senders: bool,
+    /// Treat failed database reads during verification as empty results instead of rejecting the request.
+    pub database_fail_open: bool,
+    /// Ask clients to retry later once that many transactions are scheduled.
+    pub soft_queue_limit: Option<usize>,
+    /// Number of seconds clients are asked to wait before retrying.
+    pub retry_after: u64,
+    /// Max number of requests accepted per second (across all senders and chains).
+    pub max_requests_per_second: Option<u32>,
     /// JSON-RPC Listening address
     pub rpc_listen_address: ::std::net::SocketAddr,
     /// JSON-RPC Server threads
     pub rpc_server_threads: usize,
     /// Transactions processing threads
     pub processing_threads: usize,
+    /// Token required to call admin methods (admin methods are disabled if not set).
+    pub admin_token: Option<String>,
+}
+
+impl Options {
+    /// Returns how far above the current nonce the nonce of a transaction can be (`None` if unbounded).
+    pub fn nonce_window(&self) -> Option<u64> {
+        if self.strict_nonce { Some(0) } else { self.max_nonce_ahead }
+    }
 }
@@ -0,0 +1,245 @@
+//! Scheduler-wide state
+
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use parking_lot::Mutex;
+
+use blockchain::Blockchain;
+use clock::Clock;
+use database::{Database, Replica};
+use metrics::Metrics;
+use tracker::Tracker;
+use types::BlockNumber;
+
+/// Statistics of the whole scheduler.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Stats {
+    /// Total number of transactions waiting for submission.
+    pub pending: usize,
+    /// Number of distinct senders with transactions waiting for submission.
+    pub senders: usize,
+    /// Furthest block with transactions scheduled.
+    pub furthest_block: Option<BlockNumber>,
+    /// Furthest timestamp with transactions scheduled.
+    pub furthest_timestamp: Option<u64>,
+    /// Number of submitted transactions that are not confirmed yet (if tracking is enabled).
+    pub in_flight: Option<usize>,
+    /// Number of confirmed transactions (if tracking is enabled).
+    pub confirmed: Option<usize>,
+}
+
+/// Transactions that should have been submitted already, but are still waiting.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Audit {
+    /// Transactions scheduled for blocks before the latest one.
+    pub overdue_blocks: usize,
+    /// Transactions scheduled for timestamps before current time.
+    pub overdue_timestamps: usize,
+}
+
+impl Audit {
+    /// Returns total number of overdue transactions.
+    pub fn overdue(&self) -> usize {
+        self.overdue_blocks + self.overdue_timestamps
+    }
+}
+
+/// Databases and submission state of a single chain.
+#[derive(Debug)]
+pub struct Scheduler {
+    block_db: Arc<Database>,
+    timestamp_db: Arc<Database>,
+    replicas: Option<(Arc<Replica>, Arc<Replica>)>,
+    tracker: Option<Arc<Mutex<Tracker>>>,
+}
+
+impl Scheduler {
+    /// Creates new scheduler state from block and timestamp databases.
+    pub fn new(block_db: Arc<Database>, timestamp_db: Arc<Database>) -> Self {
+        Scheduler {
+            block_db,
+            timestamp_db,
+            replicas: None,
+            tracker: None,
+        }
+    }
+
+    /// Answer stats and audits using replicas of the block and timestamp databases
+    /// (instead of the databases themselves).
+    pub fn with_replicas(mut self, replicas: (Arc<Replica>, Arc<Replica>)) -> Self {
+        self.replicas = Some(replicas);
+        self
+    }
+
+    /// Include confirmation status of submitted transactions in the stats.
+    pub fn with_tracker(mut self, tracker: Arc<Mutex<Tracker>>) -> Self {
+        self.tracker = Some(tracker);
+        self
+    }
+
+    /// Returns current statistics.
+    pub fn stats(&self) -> Stats {
+        let tracker = self.tracker.as_ref().map(|tracker| tracker.lock());
+        let in_flight = tracker.as_ref().map(|tracker| tracker.in_flight());
+        let confirmed = tracker.as_ref().map(|tracker| tracker.confirmed());
+
+        if let Some((ref block, ref timestamp)) = self.replicas {
+            let mut senders = block.senders();
+            senders.extend(timestamp.senders());
+            return Stats {
+                pending: block.pending() + timestamp.pending(),
+                senders: senders.len(),
+                furthest_block: block.furthest(),
+                furthest_timestamp: timestamp.furthest(),
+                in_flight,
+                confirmed,
+            };
+        }
+
+        let mut senders = self.block_db.senders();
+        senders.extend(self.timestamp_db.senders());
+        Stats {
+            pending: self.block_db.pending() + self.timestamp_db.pending(),
+            senders: senders.len(),
+            furthest_block: self.block_db.queue_depth().furthest,
+            furthest_timestamp: self.timestamp_db.queue_depth().furthest,
+            in_flight,
+            confirmed,
+        }
+    }
+
+    /// Counts transactions left behind given latest block and current time.
+    ///
+    /// Overdue transactions indicate a stuck submitter (or a bug), they are only reported, never drained.
+    pub fn audit(&self, latest_block: BlockNumber, now: u64) -> Audit {
+        if let Some((ref block, ref timestamp)) = self.replicas {
+            return Audit {
+                overdue_blocks: block.overdue(latest_block),
+                overdue_timestamps: timestamp.overdue(now),
+            };
+        }
+
+        Audit {
+            overdue_blocks: self.block_db.overdue(latest_block),
+            overdue_timestamps: self.timestamp_db.overdue(now),
+        }
+    }
+}
+
+/// Audits the scheduler every `interval` and reports overdue transactions.
+///
+/// This method blocks indefinitely.
+pub fn run_audit(scheduler: &Scheduler, blockchain: &Blockchain, clock: &Clock, metrics: &Metrics, interval: Duration) {
+    loop {
+        thread::sleep(interval);
+        report(&scheduler.audit(blockchain.latest_block(), clock.now_secs()), metrics);
+    }
+}
+
+fn report(audit: &Audit, metrics: &Metrics) {
+    if audit.overdue() > 0 {
+        warn!(
+            "Found {} overdue transactions ({} for past blocks, {} for past timestamps). Is the submitter stuck?",
+            audit.overdue(), audit.overdue_blocks, audit.overdue_timestamps
+        );
+    }
+    metrics.set_overdue(audit.overdue());
+}
+
+#[cfg(test)]
+mod tests {
+    use tempdir::TempDir;
+    use types::tests::tx;
+    use super::*;
+
+    #[test]
+    fn should_aggregate_stats() {
+        let dir = TempDir::new("scheduler").unwrap();
+        let block_db = Arc::new(Database::open(dir.path(), 1).unwrap());
+        let timestamp_db = Arc::new(Database::open(dir.path().join("time"), 1).unwrap());
+        block_db.insert(5, tx(0)).unwrap();
+        block_db.insert(7, tx(1)).unwrap();
+        timestamp_db.insert(1_000, tx(0)).unwrap();
+        let tracker = Arc::new(Mutex::new(Tracker::new(1)));
+        tracker.lock().track(tx(2));
+        tracker.lock().track(tx(3));
+        tracker.lock().update(tx(3).hash(), Some(4), 5);
+
+        let scheduler = Scheduler::new(block_db.clone(), timestamp_db.clone());
+        assert_eq!(scheduler.stats(), Stats {
+            pending: 3,
+            senders: 2,
+            furthest_block: Some(7),
+            furthest_timestamp: Some(1_000),
+            in_flight: None,
+            confirmed: None,
+        });
+
+        let scheduler = scheduler.with_tracker(tracker);
+        assert_eq!(scheduler.stats().in_flight, Some(1));
+        assert_eq!(scheduler.stats().confirmed, Some(1));
+    }
+
+    #[test]
+    fn should_answer_from_replicas() {
+        let dir = TempDir::new("scheduler").unwrap();
+        let block_db = Arc::new(Database::open(dir.path(), 1).unwrap());
+        let timestamp_db = Arc::new(Database::open(dir.path().join("time"), 1).unwrap());
+        block_db.insert(5, tx(0)).unwrap();
+        block_db.insert(7, tx(1)).unwrap();
+        timestamp_db.insert(1_000, tx(2)).unwrap();
+        let replicas = (Arc::new(Replica::default()), Arc::new(Replica::default()));
+        let refresh = || {
+            replicas.0.refresh(&block_db).unwrap();
+            replicas.1.refresh(&timestamp_db).unwrap();
+        };
+        let scheduler = Scheduler::new(block_db.clone(), timestamp_db.clone()).with_replicas(replicas.clone());
+
+        // Nothing is visible until the replicas are refreshed.
+        assert_eq!(scheduler.stats(), Stats::default());
+        refresh();
+        assert_eq!(scheduler.stats(), Stats {
+            pending: 3,
+            senders: 3,
+            furthest_block: Some(7),
+            furthest_timestamp: Some(1_000),
+            in_flight: None,
+            confirmed: None,
+        });
+        assert_eq!(scheduler.audit(6, 1_500), Audit { overdue_blocks: 1, overdue_timestamps: 1 });
+
+        assert_eq!(block_db.drain(5).unwrap().unwrap().collect::<Vec<_>>(), vec![tx(0)]);
+        refresh();
+        assert_eq!(scheduler.stats().pending, 2);
+        assert_eq!(scheduler.audit(6, 1_500).overdue_blocks, 0);
+    }
+
+    #[test]
+    fn should_report_overdue_transactions() {
+        let dir = TempDir::new("scheduler").unwrap();
+        let block_db = Arc::new(Database::open(dir.path(), 2).unwrap());
+        let timestamp_db = Arc::new(Database::open(dir.path().join("time"), 2).unwrap());
+        block_db.insert(5, tx(0)).unwrap();
+        block_db.insert(7, tx(1)).unwrap();
+        block_db.insert(10, tx(2)).unwrap();
+        timestamp_db.insert(1_000, tx(0)).unwrap();
+        timestamp_db.insert(2_000, tx(1)).unwrap();
+
+        let scheduler = Scheduler::new(block_db.clone(), timestamp_db.clone());
+        let audit = scheduler.audit(8, 1_500);
+        assert_eq!(audit, Audit {
+            overdue_blocks: 2,
+            overdue_timestamps: 1,
+        });
+
+        let metrics = Metrics::default();
+        report(&audit, &metrics);
+        assert_eq!(metrics.overdue(), 3);
+
+        // Nothing is drained by the audit.
+        assert_eq!(scheduler.stats().pending, 5);
+        assert_eq!(scheduler.audit(5, 1_000), Audit::default());
+    }
+}
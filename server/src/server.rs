@@ -1,35 +1,193 @@
 //! JSON-RPC server
 
+use std::collections::HashMap;
 use std::sync::Arc;
 
 use futures::Future;
+use futures::sync::mpsc;
 use futures::future::{self, Either};
 use futures_cpupool::CpuPool;
-use jsonrpc_core::{Value, IoHandler, Params};
+use jsonrpc_core::{self, Value, IoHandler, Params};
 use jsonrpc_http_server::{Server, Error, ServerBuilder};
 use serde_json;
 
 use blockchain::Blockchain;
-use database::{self, Database};
+use database::{self, Database, Replica};
+use dead_letters::DeadLetters;
 use errors;
 use options::Options;
-use types::{Bytes, Condition, TransactionId};
-use verifier::Verifier;
+use types::{self, Address, BlockNumber, Bytes, Condition, Priority, ScheduleKey, ScheduledTransaction, TransactionId, TransactionKind, H256};
+use verifier::{self, Decoders, Policy, Rejection, TokenBucket, Verifier};
 
-/// Starts the JSON-RPC server.
-pub fn start(
+/// Scheduling state of a single chain.
+pub struct Chain {
+    /// Transactions scheduled for blocks.
+    pub block_db: Arc<Database>,
+    /// Transactions scheduled for timestamps.
+    pub timestamp_db: Arc<Database>,
+    /// Cached state of the chain.
+    pub blockchain: Arc<Blockchain>,
+    /// Verification options of the chain (`chain_id` is used to route requests).
+    pub options: Options,
+    /// A sink for records of rejected transactions.
+    pub rejections: Option<mpsc::Sender<Rejection>>,
+    /// Decoders of supported transaction types.
+    pub decoders: Arc<Decoders>,
+    /// Custom acceptance policy (if any).
+    pub policy: Option<Policy>,
+    /// Read-only replicas of the block and timestamp databases answering status queries
+    /// (the databases are queried directly if not set).
+    pub replicas: Option<(Arc<Replica>, Arc<Replica>)>,
+    /// Transactions that failed permanently (exposed via the admin API).
+    pub dead_letters: Option<Arc<DeadLetters>>,
+}
+
+/// Verifiers and databases of a single chain.
+struct Handler {
+    block_verifier: Arc<Verifier>,
+    timestamp_verifier: Arc<Verifier>,
     block_db: Arc<Database>,
     timestamp_db: Arc<Database>,
-    blockchain: Arc<Blockchain>,
+    replicas: Option<(Arc<Replica>, Arc<Replica>)>,
+    dead_letters: Option<Arc<DeadLetters>>,
+    /// Decoders of typed transactions (used to read their chain id).
+    decoders: Arc<Decoders>,
+    /// Number of blocks block-scheduled transactions are submitted before their target block.
+    submit_earlier: u64,
+}
+
+impl Handler {
+    /// Creates verifiers of given chain, limiting the rate of requests with `rate_limit` (if any).
+    fn new(chain: Chain, rate_limit: Option<Arc<TokenBucket>>) -> Self {
+        let Chain { block_db, timestamp_db, blockchain, options, rejections, decoders, policy, replicas, dead_letters } = chain;
+        let submit_earlier = options.submit_earlier;
+        let mut block_verifier = Verifier::new_block(blockchain.clone(), block_db.clone(), options.clone())
+            .with_decoders(decoders.clone());
+        let mut timestamp_verifier = Verifier::new_timestamp(blockchain, timestamp_db.clone(), options)
+            .with_decoders(decoders.clone());
+        if let Some(rejections) = rejections {
+            block_verifier = block_verifier.with_rejections(rejections.clone());
+            timestamp_verifier = timestamp_verifier.with_rejections(rejections);
+        }
+        if let Some(rate_limit) = rate_limit {
+            block_verifier = block_verifier.with_rate_limit(rate_limit.clone());
+            timestamp_verifier = timestamp_verifier.with_rate_limit(rate_limit);
+        }
+        if let Some(policy) = policy {
+            block_verifier = block_verifier.with_policy(policy.clone());
+            timestamp_verifier = timestamp_verifier.with_policy(policy);
+        }
+
+        Handler {
+            block_verifier: Arc::new(block_verifier),
+            timestamp_verifier: Arc::new(timestamp_verifier),
+            block_db,
+            timestamp_db,
+            replicas,
+            dead_letters,
+            decoders,
+            submit_earlier,
+        }
+    }
+}
+
+/// Checks if the deadline (if any) leaves room to submit the transaction.
+///
+/// Transactions are dropped once the deadline block is not after the block they are submitted at,
+/// so the deadline has to be after the effective submission block of the target.
+fn check_deadline(deadline: Option<BlockNumber>, condition: &Condition, submit_earlier: u64) -> Result<(), jsonrpc_core::Error> {
+    match (deadline, condition) {
+        (Some(deadline), &Condition::Number(block_number)) => {
+            let submit_block = types::effective_submit_block(block_number, submit_earlier, 0);
+            if deadline <= submit_block {
+                return Err(errors::transaction(format!("Deadline has to be after the submission block: {}", submit_block)));
+            }
+            Ok(())
+        },
+        (Some(_), &Condition::Timestamp(_)) => Err(errors::transaction("Deadline is only supported for block scheduling.")),
+        (None, _) => Ok(()),
+    }
+}
+
+/// Routes requests to chains by chain id.
+struct Router<T> {
+    chains: HashMap<u64, T>,
+}
+
+impl<T> Router<T> {
+    fn new<I: IntoIterator<Item=(u64, T)>>(chains: I) -> Self {
+        Router {
+            chains: chains.into_iter().collect(),
+        }
+    }
+
+    /// Returns a chain for given chain id.
+    /// Transactions without chain id are only accepted if there is a single chain.
+    fn route(&self, chain_id: Option<u64>) -> Result<&T, jsonrpc_core::Error> {
+        match chain_id {
+            Some(id) => self.chains.get(&id).ok_or_else(|| errors::transaction(format!("Unsupported chain: {}", id))),
+            None if self.chains.len() == 1 => Ok(self.chains.values().next().expect("There is exactly one chain; qed")),
+            None => Err(errors::transaction("Transaction without chain id can't be routed.")),
+        }
+    }
+
+    fn all(&self) -> ::std::collections::hash_map::Values<u64, T> {
+        self.chains.values()
+    }
+}
+
+/// Returns a chain given raw transaction is signed for.
+///
+/// The chain id of typed transactions is read by the decoders registered for the chains,
+/// unless there is a single chain (which gets the transaction without decoding it here).
+fn route_transaction<'a>(chains: &'a Router<Arc<Handler>>, transaction: &Bytes) -> Result<&'a Arc<Handler>, jsonrpc_core::Error> {
+    match TransactionKind::classify(&transaction.0) {
+        Some(TransactionKind::Legacy) | None => chains.route(verifier::chain_id(transaction)?),
+        Some(_) if chains.all().len() == 1 => chains.route(None),
+        Some(kind) => {
+            let chain_id = chains.all()
+                .filter_map(|chain| chain.decoders.decode(&transaction.0).ok())
+                .filter_map(|decoded| decoded.chain_id())
+                .next();
+            match chain_id {
+                Some(chain_id) => chains.route(Some(chain_id)),
+                None => {
+                    debug!("Unable to read chain id of {:?} transaction.", kind);
+                    Err(errors::transaction("Typed transaction can't be routed."))
+                },
+            }
+        },
+    }
+}
+
+/// Starts the JSON-RPC server.
+///
+/// Requests are routed to one of the `chains` depending on the chain id of the transaction.
+pub fn start(
+    chains: Vec<Chain>,
     options: Options,
 ) -> Result<Server, Error> {
+    let io = handler(chains, &options);
+
+    ServerBuilder::new(io)
+        // don't keep alive, since we're usually doing only one request
+        .keep_alive(false)
+        // enable cors for all domains
+        .cors(None.into())
+        .threads(options.rpc_server_threads)
+        .start_http(&options.rpc_listen_address)
+}
+
+/// Creates a handler of the JSON-RPC methods.
+fn handler(chains: Vec<Chain>, options: &Options) -> IoHandler {
     let pool = CpuPool::new(options.processing_threads);
-    let block_verifier = Arc::new(Verifier::new_block(blockchain.clone(), block_db.clone(), options.clone()));
-    let timestamp_verifier = Arc::new(Verifier::new_timestamp(blockchain, timestamp_db.clone(), options.clone()));
+    let rate_limit = options.max_requests_per_second.map(|rate| Arc::new(TokenBucket::new(rate)));
+    let chains = Arc::new(Router::new(chains.into_iter().map(|chain| {
+        (chain.options.chain_id, Arc::new(Handler::new(chain, rate_limit.clone())))
+    })));
 
     let mut io = IoHandler::default();
-    let block_db1 = block_db.clone();
-    let timestamp_db1 = timestamp_db.clone();
+    let chains1 = chains.clone();
     io.add_method("cancel", move |params: Params| {
         trace!("Incoming cancel request: {:?}", params);
         let (id, ) = match params.parse::<(Bytes, )>() {
@@ -42,51 +200,221 @@ pub fn start(
             None => return future::err(errors::transaction("Invalid id")),
         };
 
-        let result = if id.is_timestamp {
-            timestamp_db1.remove(&id.num, &id.hash)
-        } else {
-            block_db1.remove(&id.num, &id.hash)
+        for chain in chains1.all() {
+            let result = if id.is_timestamp {
+                chain.timestamp_db.remove(&id.num, &id.hash)
+            } else {
+                chain.block_db.remove(&id.num, &id.hash)
+            };
+
+            match result {
+                Err(err) => return future::err(errors::transaction(err)),
+                Ok(None) => continue,
+                Ok(Some(_)) => return future::ok(Value::String("ok".into())),
+            }
+        }
+
+        future::err(errors::transaction("Not found"))
+    });
+    if let Some(token) = options.admin_token.clone() {
+        let (chains2, token2) = (chains.clone(), token.clone());
+        let (chains3, token3) = (chains.clone(), token.clone());
+        let chains = chains.clone();
+        io.add_method("flush_block", move |params: Params| {
+            trace!("Incoming flush_block request: {:?}", params);
+            let (auth, chain_id, block) = match params.parse::<(String, u64, BlockNumber)>() {
+                Ok(res) => res,
+                Err(err) => return future::err(err),
+            };
+            if auth != token {
+                warn!("Unauthorized flush_block request.");
+                return future::err(errors::unauthorized());
+            }
+            let chain = match chains.route(Some(chain_id)) {
+                Ok(chain) => chain,
+                Err(err) => return future::err(err),
+            };
+
+            match chain.block_db.flush(&block) {
+                Err(err) => future::err(errors::internal(err)),
+                Ok(removed) => {
+                    info!("Flushed {} transactions scheduled for {} (chain: {})", removed, block, chain_id);
+                    future::ok(Value::from(removed))
+                },
+            }
+        });
+
+        io.add_method("reschedule", move |params: Params| {
+            trace!("Incoming reschedule request: {:?}", params);
+            let (auth, chain_id, sender, nonce, block) = match params.parse::<(String, u64, Bytes, u64, BlockNumber)>() {
+                Ok(res) => res,
+                Err(err) => return future::err(err),
+            };
+            if auth != token2 {
+                warn!("Unauthorized reschedule request.");
+                return future::err(errors::unauthorized());
+            }
+            let sender = sender.into_vec();
+            if sender.len() != 20 {
+                return future::err(errors::transaction("Invalid sender"));
+            }
+            let chain = match chains2.route(Some(chain_id)) {
+                Ok(chain) => chain,
+                Err(err) => return future::err(err),
+            };
+
+            match chain.block_verifier.reschedule(&Address::from_slice(&sender), nonce.into(), block) {
+                Err(err) => future::err(err),
+                Ok((num, hash)) => future::ok(serde_json::to_value(&TransactionId {
+                    is_timestamp: false,
+                    num,
+                    hash,
+                }.to_bytes()).expect("Bytes serialization is infallible.")),
+            }
+        });
+
+        io.add_method("dead_letters", move |params: Params| {
+            trace!("Incoming dead_letters request: {:?}", params);
+            let (auth, chain_id) = match params.parse::<(String, u64)>() {
+                Ok(res) => res,
+                Err(err) => return future::err(err),
+            };
+            if auth != token3 {
+                warn!("Unauthorized dead_letters request.");
+                return future::err(errors::unauthorized());
+            }
+            let chain = match chains3.route(Some(chain_id)) {
+                Ok(chain) => chain,
+                Err(err) => return future::err(err),
+            };
+
+            match chain.dead_letters {
+                None => future::err(errors::transaction("Dead letters are not enabled.")),
+                Some(ref dead_letters) => future::ok(serde_json::to_value(&dead_letters.export())
+                    .expect("Dead letters serialization is infallible.")),
+            }
+        });
+    }
+    let chains4 = chains.clone();
+    io.add_method("is_scheduled", move |params: Params| {
+        trace!("Incoming is_scheduled request: {:?}", params);
+        let (sender, nonce) = match params.parse::<(Bytes, u64)>() {
+            Ok(res) => res,
+            Err(err) => return future::err(err),
         };
+        let sender = sender.into_vec();
+        if sender.len() != 20 {
+            return future::err(errors::transaction("Invalid sender"));
+        }
+        let sender = Address::from_slice(&sender);
 
-        match result {
-            Err(err) => future::err(errors::transaction(err)),
-            Ok(None) => future::err(errors::transaction("Not found")),
-            Ok(Some(_)) => future::ok(Value::String("ok".into())),
+        for chain in chains4.all() {
+            if let Some((ref block, ref timestamp)) = chain.replicas {
+                let num = block.has_sender_nonce(&sender, nonce.into())
+                    .or_else(|| timestamp.has_sender_nonce(&sender, nonce.into()));
+                match num {
+                    None => continue,
+                    Some(num) => return future::ok(Value::from(num)),
+                }
+            }
+            for db in &[&chain.block_db, &chain.timestamp_db] {
+                match db.has_sender_nonce(&sender, nonce.into()) {
+                    Err(err) => return future::err(errors::internal(err)),
+                    Ok(None) => continue,
+                    Ok(Some(num)) => return future::ok(Value::from(num)),
+                }
+            }
+        }
+
+        future::ok(Value::Null)
+    });
+    let chains3 = chains.clone();
+    io.add_method("verifyTransaction", move |params: Params| {
+        trace!("Incoming verifyTransaction request: {:?}", params);
+        let (transaction, ) = match params.parse::<(Bytes, )>() {
+            Ok(res) => res,
+            Err(err) => return future::err(err),
+        };
+        let chain = match route_transaction(&chains3, &transaction) {
+            Ok(chain) => chain,
+            Err(err) => return future::err(err),
+        };
+
+        match chain.block_verifier.verify_encoding(transaction) {
+            Ok(transaction) => future::ok(serde_json::to_value(&Bytes::new(transaction.hash().to_vec())).expect("Bytes serialization is infallible.")),
+            Err(err) => future::err(err),
         }
     });
     io.add_method("scheduleTransaction", move |params: Params| {
         trace!("Incoming request: {:?}", params);
-        let (condition, transaction) = match params.parse::<(Condition, Bytes)>() {
-            Ok(res) => res,
+        // Optional third parameter is a hash of a transaction that has to be confirmed first (or null),
+        // optional fourth parameter is the submission priority ("low", "normal" or "high"),
+        // optional fifth parameter is the last block the transaction can be included in (block scheduling only).
+        let (condition, transaction, depends_on, priority, deadline) = match params.clone().parse::<(Condition, Bytes)>() {
+            Ok((condition, transaction)) => (condition, transaction, None, Priority::default(), None),
+            Err(err) => match params.clone().parse::<(Condition, Bytes, Bytes)>() {
+                Ok((condition, transaction, depends_on)) => (condition, transaction, Some(depends_on), Priority::default(), None),
+                Err(_) => match params.clone().parse::<(Condition, Bytes, Option<Bytes>, Priority)>() {
+                    Ok((condition, transaction, depends_on, priority)) => (condition, transaction, depends_on, priority, None),
+                    Err(_) => match params.parse::<(Condition, Bytes, Option<Bytes>, Priority, Option<BlockNumber>)>() {
+                        Ok(res) => res,
+                        Err(_) => return Either::A(future::err(err)),
+                    },
+                },
+            },
+        };
+        let depends_on = match depends_on.map(Bytes::into_vec) {
+            None => None,
+            Some(ref hash) if hash.len() == 32 => Some(H256::from_slice(hash)),
+            Some(_) => return Either::A(future::err(errors::transaction("Invalid dependency hash"))),
+        };
+        let chain = match route_transaction(&chains, &transaction) {
+            Ok(chain) => chain.clone(),
             Err(err) => return Either::A(future::err(err)),
         };
+        if let Err(err) = check_deadline(deadline, &condition, chain.submit_earlier) {
+            return Either::A(future::err(err));
+        }
 
-        let block_verifier = block_verifier.clone();
-        let timestamp_verifier = timestamp_verifier.clone();
-        let block_db = block_db.clone();
-        let timestamp_db = timestamp_db.clone();
         Either::B(pool.spawn_fn(move || {
             debug!("Verifying request: {:?}", condition);
-            let (is_timestamp, num, verifier, db) = match condition {
-                Condition::Number(block_number) => (false, block_number, block_verifier, block_db),
-                Condition::Timestamp(time) => (true, time, timestamp_verifier, timestamp_db),
+            let (num, verifier) = match condition {
+                Condition::Number(block_number) => (block_number, chain.block_verifier.clone()),
+                Condition::Timestamp(time) => (time, chain.timestamp_verifier.clone()),
             };
 
             verifier.verify(num, transaction)
-                .and_then(move |(num, transaction)| {
+                .and_then(move |ScheduledTransaction { tx: transaction, when }| {
+                    // The database is picked by the key, so the transaction always lands at the right trigger.
+                    let db = match when {
+                        ScheduleKey::Block(_) => chain.block_db.clone(),
+                        ScheduleKey::Timestamp(_) => chain.timestamp_db.clone(),
+                    };
+                    let num = when.num();
                     let hash = *transaction.hash();
+                    let transaction = transaction.with_priority(priority);
+                    let transaction = match deadline {
+                        Some(deadline) => transaction.with_deadline(deadline),
+                        None => transaction,
+                    };
+                    let transaction = match depends_on {
+                        Some(depends_on) if depends_on == hash => return Err(errors::transaction("Transaction can't depend on itself.")),
+                        Some(depends_on) => transaction.with_dependency(depends_on),
+                        None => transaction,
+                    };
                     if let Err(e) = db.insert(num, transaction) {
                         if let &database::ErrorKind::SenderExists = e.kind() {
                             warn!("DB sender exists: {}", e);
-                        } else {
-                            warn!("DB write error: {:?}", e);
+                            return Err(errors::internal(e))
                         }
-                        return Err(errors::internal(e))
+                        // Writes always fail closed.
+                        warn!("DB write error: {:?}", e);
+                        return Err(errors::unavailable(e))
                     }
                     info!("[{:?}] Scheduled for {}", hash, num);
                     // TODO [ToDr] After transactions are submitted make sure they are mined, if not - resubmit.
                     Ok(serde_json::to_value(&TransactionId {
-                        is_timestamp,
+                        is_timestamp: when.is_timestamp(),
                         num,
                         hash
                     }.to_bytes()).expect("Bytes serialization is infallible."))
@@ -94,11 +422,100 @@ pub fn start(
         }))
     });
 
-    ServerBuilder::new(io)
-        // don't keep alive, since we're usually doing only one request
-        .keep_alive(false)
-        // enable cors for all domains
-        .cors(None.into())
-        .threads(options.rpc_server_threads)
-        .start_http(&options.rpc_listen_address)
+    io
+}
+
+#[cfg(test)]
+mod tests {
+    use ethcore::transaction::SignedTransaction;
+    use rlp::UntrustedRlp;
+    use tempdir::TempDir;
+    use types::U256;
+    use types::tests::{raw, tx};
+    use verifier::tests::options;
+    use super::*;
+
+    /// Returns a chain with given id accepting legacy transactions wrapped in type `0x7f`.
+    fn chain(dir: &TempDir, chain_id: u64) -> Chain {
+        let blockchain = Arc::new(Blockchain::new("http://127.0.0.1:8545", None).unwrap());
+        blockchain.mock_balance_and_nonce(*tx(0).sender(), U256::from(10).pow(18.into()), 0.into());
+        let mut decoders = Decoders::default();
+        decoders.register(0x7f, Box::new(|raw: &[u8]| {
+            let rlp = UntrustedRlp::new(&raw[1..]).as_val().map_err(errors::rlp)?;
+            SignedTransaction::new(rlp).map_err(errors::transaction)
+        }));
+        Chain {
+            block_db: Arc::new(Database::open(dir.path().join("block"), 1).unwrap()),
+            timestamp_db: Arc::new(Database::open(dir.path().join("timestamp"), 1).unwrap()),
+            blockchain,
+            options: Options { chain_id, ..options() },
+            rejections: None,
+            decoders: Arc::new(decoders),
+            policy: None,
+            replicas: None,
+            dead_letters: None,
+        }
+    }
+
+    #[test]
+    fn should_schedule_typed_transactions() {
+        let (dir1, dir42) = (TempDir::new("chain1").unwrap(), TempDir::new("chain42").unwrap());
+        let (chain1, chain42) = (chain(&dir1, 1), chain(&dir42, 42));
+        let (db1, db42) = (chain1.block_db.clone(), chain42.block_db.clone());
+        let mut typed = vec![0x7f];
+        typed.extend(raw(0));
+        let request = format!(
+            r#"{{"jsonrpc":"2.0","id":1,"method":"scheduleTransaction","params":[{{"block":5}},{}]}}"#,
+            serde_json::to_string(&Bytes::new(typed)).unwrap()
+        );
+
+        // The chain id is read by the registered decoder.
+        let io = handler(vec![chain1, chain42], &options());
+        let response = io.handle_request_sync(&request).unwrap();
+        assert!(response.contains("\"result\""), "Unexpected response: {}", response);
+        assert_eq!(db1.pending(), 1);
+        assert_eq!(db42.pending(), 0);
+    }
+
+    #[test]
+    fn should_route_to_chain_database() {
+        let dir1 = TempDir::new("chain1").unwrap();
+        let dir2 = TempDir::new("chain2").unwrap();
+        let router = Router::new(vec![
+            (1, Database::open(dir1.path(), 1).unwrap()),
+            (42, Database::open(dir2.path(), 1).unwrap()),
+        ]);
+
+        router.route(Some(1)).unwrap().insert(5, tx(0)).unwrap();
+        router.route(Some(42)).unwrap().insert(5, tx(1)).unwrap();
+        assert!(router.route(Some(3)).is_err());
+        assert!(router.route(None).is_err());
+
+        let mut iter = router.route(Some(1)).unwrap().drain(5).unwrap().unwrap();
+        assert_eq!(iter.next(), Some(tx(0)));
+        assert_eq!(iter.next(), None);
+        let mut iter = router.route(Some(42)).unwrap().drain(5).unwrap().unwrap();
+        assert_eq!(iter.next(), Some(tx(1)));
+        assert_eq!(iter.next(), None);
+    }
+
+    #[test]
+    fn should_require_deadline_after_submission_block() {
+        assert!(check_deadline(None, &Condition::Number(10), 0).is_ok());
+        assert!(check_deadline(Some(11), &Condition::Number(10), 0).is_ok());
+        assert!(check_deadline(Some(10), &Condition::Number(10), 0).is_err());
+        // Submitted at block 9.
+        assert!(check_deadline(Some(10), &Condition::Number(10), 1).is_ok());
+        assert_eq!(
+            check_deadline(Some(9), &Condition::Number(10), 1).unwrap_err().data,
+            Some(Value::String("Deadline has to be after the submission block: 9".into()))
+        );
+        assert!(check_deadline(Some(2_000), &Condition::Timestamp(1_000), 0).is_err());
+    }
+
+    #[test]
+    fn should_route_transactions_without_chain_id_to_single_chain() {
+        let router = Router::new(vec![(42, "kovan")]);
+        assert_eq!(router.route(None).unwrap(), &"kovan");
+    }
 }
@@ -1,50 +1,901 @@
 //! Submits transactions to "edge nodes" when a block is mined.
 
-use std::sync::Arc;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::{cmp, fmt, fs, thread};
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc as std_mpsc, Arc};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
 
 use futures::future::{self, Either};
-use futures::sync::mpsc;
-use futures::{Sink as FutureSink, Future, Poll, Stream, Async};
+use futures::stream;
+use futures::sync::{mpsc, oneshot};
+use futures::{task, Sink as FutureSink, Future, Poll, Stream, Async};
+use parking_lot::Mutex;
+use rustc_hex::{FromHex, ToHex};
 use web3::transports;
-use web3::{Error, Web3, Transport};
+use web3::{Web3, Transport};
 
-use database::Database;
-use types::{BlockNumber, Transaction};
+use blockchain::Blockchain;
+use clock::{Clock, SystemClock};
+use database::{self, Database, TransactionsIterator};
+use dead_letters::DeadLetters;
+use metrics::Metrics;
+use tracker::{Status, Tracker};
+use types::{Address, BlockNumber, Bytes, Transaction, H256, U256};
+use verifier;
 use TransportType;
 
+mod error {
+    #![allow(unknown_lints)]
+    #![allow(missing_docs)]
+    error_chain! {
+        errors {
+            Transport(err: String) {
+                description("Transport error.")
+                display("Transport error: {}", err)
+            }
+            NoTransports {
+                description("No transports configured.")
+                display("No transports configured.")
+            }
+            TooManyTransports(count: usize, max: usize) {
+                description("Too many transports configured.")
+                display("Too many transports configured: {} (max: {}).", count, max)
+            }
+            ListenerClosed {
+                description("Block listener closed unexpectedly.")
+                display("Block listener closed unexpectedly.")
+            }
+        }
+    }
+
+    impl From<::web3::Error> for Error {
+        fn from(err: ::web3::Error) -> Self {
+            ErrorKind::Transport(format!("{:?}", err)).into()
+        }
+    }
+}
+
+pub use self::error::{Error, ErrorKind};
+
+/// Notifies the submitter once an endpoint is done with the transaction it's sent with.
+/// Dropped without notification if the transaction is skipped by the endpoint.
+pub type Ack = Option<oneshot::Sender<()>>;
+
+/// Defines how transactions are handed over to the sinks.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SendMode {
+    /// Wait until every sink accepts the transaction.
+    /// The slowest sink is delaying all the others.
+    Wait,
+    /// Never wait for the sinks (fire-and-forget).
+    /// The transaction is dropped for every sink that has it's channel full.
+    TrySend,
+    /// Hand over the transaction to the sinks one by one, in order of their weight.
+    /// The primary (highest weight) sink receives every transaction first,
+    /// the others are only used as redundancy.
+    Priority,
+    /// Wait until the preferred (highest weight) endpoint submits the transaction
+    /// and only then broadcast it to the others (fire-and-forget).
+    /// Gives the preferred endpoint the best chance to see every transaction first.
+    Preferred,
+    /// Consider the transaction sent once given number of sinks accept it,
+    /// hand-over to the others continues in the background.
+    /// Sinks still busy with previous transactions get the transaction once they are done.
+    Quorum(usize),
+}
+
+impl Default for SendMode {
+    fn default() -> Self {
+        SendMode::Wait
+    }
+}
+
+/// Outcome of a transaction failing re-verification just before submission.
+#[derive(Debug, Clone)]
+pub enum ReverifyFailure {
+    /// Drop the transaction (counted as skipped and reported to the tracker as dropped).
+    Drop,
+    /// Only log the failure and submit the transaction anyway.
+    Log,
+    /// Drop the transaction and keep it in given dead-letter store.
+    DeadLetter(Arc<DeadLetters>),
+}
+
+impl Default for ReverifyFailure {
+    fn default() -> Self {
+        ReverifyFailure::Drop
+    }
+}
+
+/// Submitter options.
+#[derive(Debug, Clone)]
+pub struct SubmitterOptions {
+    /// Mode of handing transactions to sinks.
+    pub mode: SendMode,
+    /// Submission metrics.
+    pub metrics: Arc<Metrics>,
+    /// Set when submission is paused.
+    /// Transactions are left in the database until submission is resumed.
+    pub paused: Arc<AtomicBool>,
+    /// Set when the submitters are being stopped (the block listener is expected to close).
+    pub stopping: Arc<AtomicBool>,
+    /// Fail (instead of just logging) when the block listener closes without the submitters being stopped.
+    pub fail_on_listener_close: bool,
+    /// How long to wait for transactions buffered in sinks to be submitted on shutdown.
+    pub shutdown_timeout: Duration,
+    /// Only log transactions that would be submitted, without sending them to the endpoints.
+    pub dry_run: bool,
+    /// Re-verify balance and nonce of every transaction just before submission
+    /// and skip transactions that are going to fail.
+    pub reverify: Option<Arc<Blockchain>>,
+    /// What to do with transactions failing re-verification.
+    pub reverify_failure: ReverifyFailure,
+    /// Maximal number of balance and nonce lookups in flight when re-verifying drained transactions.
+    pub batch_concurrency: usize,
+    /// Give up on a transaction after it could not be handed over to any endpoint that many times
+    /// (scheduled again indefinitely if not set).
+    pub max_submit_attempts: Option<u32>,
+    /// Failed submission attempts of transactions scheduled again (shared by all submitters using these options).
+    pub submit_attempts: Arc<Mutex<HashMap<H256, u32>>>,
+    /// Keeps transactions that failed permanently (given up or past their deadline) for inspection.
+    pub dead_letters: Option<Arc<DeadLetters>>,
+    /// Transactions submitted recently (shared by all submitters using these options).
+    pub recent: Arc<Mutex<RecentlySubmitted>>,
+    /// Tracks submitted transactions until they are confirmed.
+    pub tracker: Option<Arc<Mutex<Tracker>>>,
+    /// Drop transactions waiting in the queue for longer than that (even if their target is not reached yet).
+    pub max_queue_age: Option<Duration>,
+    /// Exclude an endpoint after that many consecutive submission errors.
+    pub breaker_threshold: Option<u32>,
+    /// How long an excluded endpoint is skipped before it's probed again.
+    pub breaker_cooldown: Duration,
+    /// Source of current time for timestamp-based scheduling.
+    pub clock: Arc<Clock>,
+    /// Limits the number of outstanding submissions across all sinks.
+    pub in_flight: Option<Arc<InFlight>>,
+    /// How long to wait before reconnecting a dropped endpoint (doubled after every failed attempt).
+    pub reconnect_delay: Duration,
+    /// Submit using `eth_sendRawTransactionConditional`, letting the endpoint drop transactions
+    /// not included within given number of blocks after the block they are submitted on.
+    pub conditional_blocks: Option<u64>,
+    /// Transforms raw transactions just before they are submitted (submitted as-is if not set).
+    pub transform: Option<Transform>,
+    /// Name of the RPC method submitting raw transactions (for endpoints using a non-standard one).
+    pub send_method: String,
+    /// Maximal number of transports (each of them runs it's own event loop).
+    pub max_transports: usize,
+    /// The timestamp submitter drains all due transactions once per window
+    /// (so they are submitted together, up to `timestamp_window - 1` seconds later than requested).
+    pub timestamp_window: Duration,
+}
+
+impl Default for SubmitterOptions {
+    fn default() -> Self {
+        SubmitterOptions {
+            mode: Default::default(),
+            metrics: Default::default(),
+            paused: Default::default(),
+            stopping: Default::default(),
+            fail_on_listener_close: false,
+            shutdown_timeout: Duration::from_secs(5),
+            dry_run: false,
+            reverify: None,
+            reverify_failure: Default::default(),
+            batch_concurrency: 8,
+            max_submit_attempts: None,
+            submit_attempts: Default::default(),
+            dead_letters: None,
+            recent: Arc::new(Mutex::new(RecentlySubmitted::new(Duration::from_secs(60), 16 * 1024))),
+            tracker: None,
+            max_queue_age: None,
+            breaker_threshold: None,
+            breaker_cooldown: Duration::from_secs(30),
+            clock: Arc::new(SystemClock),
+            in_flight: None,
+            reconnect_delay: Duration::from_secs(1),
+            conditional_blocks: None,
+            transform: None,
+            send_method: SEND_RAW.into(),
+            max_transports: 64,
+            timestamp_window: Duration::from_secs(1),
+        }
+    }
+}
+
+impl SubmitterOptions {
+    /// Pauses the submission.
+    pub fn pause(&self) {
+        info!("Pausing submission.");
+        self.paused.store(true, Ordering::SeqCst);
+    }
+
+    /// Resumes the submission.
+    pub fn resume(&self) {
+        info!("Resuming submission.");
+        self.paused.store(false, Ordering::SeqCst);
+    }
+
+    /// Returns true if submission is paused.
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::SeqCst)
+    }
+
+    /// Marks the submitters as being stopped, so that closing the block listener is not reported as a failure.
+    pub fn stop(&self) {
+        info!("Stopping submission.");
+        self.stopping.store(true, Ordering::SeqCst);
+    }
+
+    /// Returns true if the submitters are being stopped.
+    pub fn is_stopping(&self) -> bool {
+        self.stopping.load(Ordering::SeqCst)
+    }
+}
+
+/// A bounded set of recently submitted transactions.
+/// Prevents broadcasting the same transaction again within a cooldown period.
+#[derive(Debug)]
+pub struct RecentlySubmitted {
+    cooldown: Duration,
+    capacity: usize,
+    /// Transactions together with the instant they expire at.
+    queue: VecDeque<(H256, Instant)>,
+    hashes: HashSet<H256>,
+    journal: Option<Journal>,
+}
+
+/// A file recording submitted (`<hash> <timestamp>`) and forgotten (`<hash> -`) transactions.
+#[derive(Debug)]
+struct Journal {
+    path: PathBuf,
+    file: fs::File,
+    /// Number of entries written since the file was compacted.
+    written: usize,
+    /// Unix timestamp of the `opened` instant.
+    now: u64,
+    opened: Instant,
+}
+
+impl Journal {
+    /// Converts given instant to unix timestamp.
+    fn timestamp(&self, at: Instant) -> u64 {
+        if at >= self.opened {
+            self.now + at.duration_since(self.opened).as_secs()
+        } else {
+            self.now.saturating_sub(self.opened.duration_since(at).as_secs())
+        }
+    }
+
+    /// Appends an entry, the set still works (in memory only) if it can't be written.
+    fn write(&mut self, hash: &H256, timestamp: Option<u64>) {
+        let timestamp = timestamp.map(|t| t.to_string()).unwrap_or_else(|| "-".into());
+        if let Err(err) = writeln!(self.file, "{} {}", hash.to_hex(), timestamp) {
+            warn!("Unable to record submitted transaction at {}: {:?}", self.path.display(), err);
+        }
+        self.written += 1;
+    }
+}
+
+impl RecentlySubmitted {
+    /// Creates a set remembering up to `capacity` transactions for `cooldown` period.
+    pub fn new(cooldown: Duration, capacity: usize) -> Self {
+        RecentlySubmitted {
+            cooldown,
+            capacity,
+            queue: VecDeque::new(),
+            hashes: HashSet::new(),
+            journal: None,
+        }
+    }
+
+    /// Records submitted transactions in a file at given `path`,
+    /// so that they are not submitted again after a restart.
+    ///
+    /// Restores transactions recorded within the cooldown period before `now` (unix timestamp).
+    pub fn with_journal<P: AsRef<Path>>(mut self, path: P, now: u64) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let mut restored = Vec::<(H256, u64)>::new();
+        match fs::File::open(&path) {
+            Ok(file) => for line in io::BufReader::new(file).lines() {
+                let line = line?;
+                let mut parts = line.split(' ');
+                let hash = match parts.next().and_then(|hash| hash.from_hex().ok()) {
+                    Some(ref hash) if hash.len() == 32 => H256::from(&hash[..]),
+                    _ => {
+                        warn!("Ignoring invalid entry of {}: {}", path.display(), line);
+                        continue;
+                    },
+                };
+                restored.retain(|&(h, _)| h != hash);
+                if let Some(timestamp) = parts.next().and_then(|timestamp| timestamp.parse::<u64>().ok()) {
+                    restored.push((hash, timestamp));
+                }
+            },
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => {},
+            Err(err) => return Err(err),
+        }
+
+        let opened = Instant::now();
+        let cooldown = self.cooldown.as_secs();
+        for (hash, timestamp) in restored {
+            let age = now.saturating_sub(timestamp);
+            if age < cooldown && self.queue.len() < self.capacity {
+                self.queue.push_back((hash, opened + Duration::from_secs(cooldown - age)));
+                self.hashes.insert(hash);
+            }
+        }
+        info!("Restored {} recently submitted transactions from {}.", self.queue.len(), path.display());
+
+        let file = fs::OpenOptions::new().create(true).append(true).open(&path)?;
+        self.journal = Some(Journal { path, file, written: 0, now, opened });
+        self.compact()?;
+        Ok(self)
+    }
+
+    /// Marks given transaction as submitted at `now`.
+    /// Returns `false` if it was already submitted within the cooldown period.
+    pub fn insert(&mut self, hash: H256, now: Instant) -> bool {
+        if self.contains(&hash, now) {
+            return false;
+        }
+
+        if self.queue.len() >= self.capacity {
+            if let Some((oldest, _)) = self.queue.pop_front() {
+                self.hashes.remove(&oldest);
+            }
+        }
+        self.queue.push_back((hash, now + self.cooldown));
+        self.hashes.insert(hash);
+        if let Some(ref mut journal) = self.journal {
+            let timestamp = journal.timestamp(now);
+            journal.write(&hash, Some(timestamp));
+        }
+        self.compact_if_needed();
+        true
+    }
+
+    /// Returns true if given transaction was submitted within the cooldown period before `now`.
+    pub fn contains(&mut self, hash: &H256, now: Instant) -> bool {
+        self.expire(now);
+        self.hashes.contains(hash)
+    }
+
+    /// Forgets given transaction, so that it can be submitted again.
+    pub fn remove(&mut self, hash: &H256) {
+        if self.hashes.remove(hash) {
+            self.queue.retain(|&(ref h, _)| h != hash);
+            if let Some(ref mut journal) = self.journal {
+                journal.write(hash, None);
+            }
+            self.compact_if_needed();
+        }
+    }
+
+    /// Rewrites the journal once it has twice as many entries as the set can hold.
+    fn compact_if_needed(&mut self) {
+        let written = self.journal.as_ref().map_or(0, |journal| journal.written);
+        if written > 2 * self.capacity {
+            if let Err(err) = self.compact() {
+                warn!("Unable to compact submitted transactions journal: {:?}", err);
+            }
+        }
+    }
+
+    /// Replaces the journal with a file containing only the transactions currently in the set.
+    /// The file is replaced atomically, so a crash never leaves it corrupted.
+    fn compact(&mut self) -> io::Result<()> {
+        let journal = match self.journal {
+            Some(ref mut journal) => journal,
+            None => return Ok(()),
+        };
+
+        let cooldown = self.cooldown.as_secs();
+        let tmp = journal.path.with_extension("tmp");
+        {
+            let mut file = fs::File::create(&tmp)?;
+            for &(ref hash, expires) in &self.queue {
+                writeln!(file, "{} {}", hash.to_hex(), journal.timestamp(expires).saturating_sub(cooldown))?;
+            }
+            file.sync_all()?;
+        }
+        fs::rename(&tmp, &journal.path)?;
+        journal.file = fs::OpenOptions::new().append(true).open(&journal.path)?;
+        journal.written = 0;
+        Ok(())
+    }
+
+    fn expire(&mut self, now: Instant) {
+        while let Some(&(hash, expires)) = self.queue.front() {
+            if now < expires {
+                break;
+            }
+            self.queue.pop_front();
+            self.hashes.remove(&hash);
+        }
+    }
+}
+
+/// A circuit breaker of a single endpoint.
+///
+/// After `threshold` consecutive failures the endpoint is excluded (open) for `cooldown`.
+/// Afterwards a single transaction is let through (half-open),
+/// the endpoint is included again if it's submitted successfuly or excluded for another `cooldown` otherwise.
+#[derive(Debug)]
+struct CircuitBreaker {
+    threshold: u32,
+    cooldown: Duration,
+    failures: u32,
+    opened_at: Option<Instant>,
+}
+
+impl CircuitBreaker {
+    fn new(threshold: u32, cooldown: Duration) -> Self {
+        CircuitBreaker {
+            threshold,
+            cooldown,
+            failures: 0,
+            opened_at: None,
+        }
+    }
+
+    /// Returns true if a transaction should be submitted to the endpoint at `now`.
+    fn allow(&self, now: Instant) -> bool {
+        match self.opened_at {
+            None => true,
+            Some(opened_at) => now.duration_since(opened_at) >= self.cooldown,
+        }
+    }
+
+    /// Records successful submission.
+    fn success(&mut self) {
+        if self.opened_at.is_some() {
+            info!("Endpoint recovered. Closing circuit breaker.");
+        }
+        self.failures = 0;
+        self.opened_at = None;
+    }
+
+    /// Records failed submission at `now`.
+    fn failure(&mut self, now: Instant) {
+        self.failures = self.failures.saturating_add(1);
+        if self.failures >= self.threshold {
+            if self.opened_at.is_none() {
+                warn!("Endpoint failed {} times in a row. Excluding for {:?}.", self.failures, self.cooldown);
+            }
+            self.opened_at = Some(now);
+        }
+    }
+}
+
+/// Maximal delay between reconnection attempts.
+const MAX_RECONNECT_DELAY_SECS: u64 = 60;
+
+/// A connection to a single endpoint.
+///
+/// When dropped the connection is re-established (on demand) with exponential backoff.
+struct Connection<C> {
+    current: Option<C>,
+    connect: Box<FnMut() -> Result<C, Error> + Send>,
+    initial_delay: Duration,
+    delay: Duration,
+    retry_at: Option<Instant>,
+}
+
+impl<C> Connection<C> {
+    fn new(current: C, connect: Box<FnMut() -> Result<C, Error> + Send>, delay: Duration) -> Self {
+        Connection {
+            current: Some(current),
+            connect,
+            initial_delay: delay,
+            delay,
+            retry_at: None,
+        }
+    }
+
+    /// Returns the current connection.
+    /// Attempts to reconnect if the connection was dropped and the backoff delay elapsed.
+    fn get(&mut self, now: Instant) -> Option<&C> {
+        if self.current.is_none() && self.retry_at.map_or(true, |retry_at| now >= retry_at) {
+            match (self.connect)() {
+                Ok(connection) => {
+                    info!("Reconnected to the endpoint.");
+                    self.current = Some(connection);
+                    self.delay = self.initial_delay;
+                    self.retry_at = None;
+                },
+                Err(err) => {
+                    warn!("Unable to reconnect: {:?}. Retrying in {:?}.", err, self.delay);
+                    self.retry_at = Some(now + self.delay);
+                    self.delay = ::std::cmp::min(self.delay * 2, Duration::from_secs(MAX_RECONNECT_DELAY_SECS));
+                },
+            }
+        }
+        self.current.as_ref()
+    }
+
+    /// Drops the current connection, it's re-established after the backoff delay.
+    fn disconnected(&mut self, now: Instant) {
+        if self.current.take().is_some() {
+            warn!("Endpoint disconnected. Reconnecting in {:?}.", self.delay);
+            self.retry_at = Some(now + self.delay);
+        }
+    }
+}
+
+/// Returns true if the error means that the connection to the endpoint was lost.
+fn is_disconnected(err: &::web3::Error) -> bool {
+    match *err.kind() {
+        ::web3::ErrorKind::Io(_) | ::web3::ErrorKind::Unreachable => true,
+        _ => false,
+    }
+}
+
+/// Name of the standard RPC method submitting raw transactions.
+const SEND_RAW: &str = "eth_sendRawTransaction";
+
+/// Name of the RPC method submitting transactions with preconditions.
+const SEND_CONDITIONAL: &str = "eth_sendRawTransactionConditional";
+
+/// Submits given raw transaction calling `method` with `execute`.
+fn send_raw<F, R>(execute: F, method: &str, payload: &[u8]) -> R where
+    F: FnOnce(&str, Vec<::serde_json::Value>) -> R,
+{
+    execute(method, vec![
+        ::serde_json::to_value(&Bytes::new(payload.to_vec())).expect("Bytes serialization is infallible."),
+    ])
+}
+
+/// Returns parameters of `eth_sendRawTransactionConditional` call
+/// submitting given transaction only up to `max_block`.
+fn conditional_params(payload: &[u8], max_block: BlockNumber) -> Vec<::serde_json::Value> {
+    let mut condition = ::serde_json::Map::new();
+    condition.insert("blockNumberMax".into(), format!("0x{:x}", max_block).into());
+    vec![
+        ::serde_json::to_value(&Bytes::new(payload.to_vec())).expect("Bytes serialization is infallible."),
+        ::serde_json::Value::Object(condition),
+    ]
+}
+
+/// Transforms raw transactions before they are submitted
+/// (e.g. wraps them in an envelope required by a relay).
+#[derive(Clone)]
+pub struct Transform(Arc<Fn(&[u8]) -> Vec<u8> + Send + Sync>);
+
+impl Transform {
+    /// Creates a transform using given function.
+    pub fn new<F: Fn(&[u8]) -> Vec<u8> + Send + Sync + 'static>(f: F) -> Self {
+        Transform(Arc::new(f))
+    }
+}
+
+impl fmt::Debug for Transform {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "Transform")
+    }
+}
+
+/// Returns the payload submitted for given raw transaction.
+fn transformed(rlp: &[u8], transform: Option<&Transform>) -> Vec<u8> {
+    match transform {
+        Some(transform) => (transform.0)(rlp),
+        None => rlp.to_vec(),
+    }
+}
+
+/// Returns true if the error means that the endpoint doesn't support the method.
+fn is_unsupported(err: &::web3::Error) -> bool {
+    match *err.kind() {
+        // Method not found
+        ::web3::ErrorKind::Rpc(ref err) => err.code.code() == -32601,
+        _ => false,
+    }
+}
+
+/// A semaphore limiting the number of concurrent submissions.
+#[derive(Debug)]
+pub struct InFlight {
+    limit: usize,
+    state: Mutex<InFlightState>,
+}
+
+#[derive(Debug, Default)]
+struct InFlightState {
+    outstanding: usize,
+    waiting: Vec<task::Task>,
+}
+
+impl InFlight {
+    /// Creates a semaphore allowing up to `limit` outstanding submissions.
+    pub fn new(limit: usize) -> Self {
+        InFlight {
+            limit,
+            state: Default::default(),
+        }
+    }
+
+    /// Returns a future resolving to a permit once the number of outstanding submissions is below the limit.
+    /// The permit is released when dropped.
+    fn acquire(in_flight: &Arc<InFlight>) -> Acquire {
+        Acquire {
+            in_flight: in_flight.clone(),
+        }
+    }
+
+    fn release(&self) {
+        let mut state = self.state.lock();
+        state.outstanding -= 1;
+        for task in state.waiting.drain(..) {
+            task.notify();
+        }
+    }
+}
+
+/// A future waiting for a submission permit.
+struct Acquire {
+    in_flight: Arc<InFlight>,
+}
+
+impl Future for Acquire {
+    type Item = Permit;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Permit, ()> {
+        let mut state = self.in_flight.state.lock();
+        if state.outstanding < self.in_flight.limit {
+            state.outstanding += 1;
+            return Ok(Async::Ready(Permit { in_flight: self.in_flight.clone() }));
+        }
+
+        state.waiting.push(task::current());
+        Ok(Async::NotReady)
+    }
+}
+
+/// A permit for a single outstanding submission.
+struct Permit {
+    in_flight: Arc<InFlight>,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.in_flight.release();
+    }
+}
+
+/// Summary of a submitter run.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct RunSummary {
+    /// Number of blocks (or timestamps) transactions were drained for.
+    pub processed: u64,
+    /// Number of transactions handed over to the sinks.
+    pub submitted: u64,
+    /// Number of transactions that couldn't be handed over to any of the sinks
+    /// and failed attempts to read transactions from the database.
+    pub errors: u64,
+}
+
 /// Spawns given number of transports and runs a submitter.
 /// Each transport will receive the same set of transactions.
 /// This method listens for incoming block numbers and
 /// submits all transactions scheduled for given block.
 ///
-/// This method blocks until block subscription is over.
+/// This method blocks until block subscription is over and returns the summary of the run.
+/// If the subscription ends without the submitter being stopped (see `SubmitterOptions::stop`),
+/// an error is returned instead when `fail_on_listener_close` is set.
 pub fn run_block<I: Iterator<Item=TransportType>>(
     types: I,
     listener: mpsc::Receiver<BlockNumber>,
     block_db: Arc<Database>,
-    submit_earlier: u64,
-) -> Result<(), Error> {
-    let (sinks, _eloops) = init_transports(types)?;
-    let db = block_db.clone();
-    listener
-        .map(move |block| block + submit_earlier)
-        .filter(move |block| db.has(block))
-        .for_each(move |block| {
-            debug!("Sending transactions for block: {}", block);
-            match block_db.drain(block) {
-                Ok(Some(iterator)) => Either::A(Submitter::new(sinks.clone(), iterator)),
-                Ok(None) => {
-                    warn!("No transactions found in block: {}", block);
-                    Either::B(future::ok(()))
+    options: SubmitterOptions,
+) -> Result<RunSummary, Error> {
+    let transports = init_transports(types, &options)?;
+    let sinks = transports.sinks.clone();
+    let shutdown_timeout = options.shutdown_timeout;
+    let (db, db2) = (block_db.clone(), block_db.clone());
+    let (opts, opts2, opts3) = (options.clone(), options.clone(), options.clone());
+    let summary = Arc::new(Mutex::new(RunSummary::default()));
+    let summary2 = summary.clone();
+    let result = without_gaps(listener)
+        .map(move |block| {
+            prune(&db, &opts);
+            block
+        })
+        .filter(move |block| !opts2.is_paused() && db2.has(block))
+        .for_each(move |block| submit_block(&sinks, &block_db, block, &options, &summary2))
+        .wait();
+
+    transports.shutdown(shutdown_timeout);
+    let summary = summary.lock().clone();
+    result.map_err(|_| unreachable!())?;
+    if opts3.is_stopping() {
+        info!("Block listener closed. Submitter stopped.");
+    } else if opts3.fail_on_listener_close {
+        error!("Block listener closed unexpectedly. Submitter stopped.");
+        return Err(ErrorKind::ListenerClosed.into());
+    } else {
+        warn!("Block listener closed unexpectedly. Submitter stopped.");
+    }
+    Ok(summary)
+}
+
+/// Max number of blocks skipped by the block stream that are filled in
+/// (transactions of older blocks are drained together with the first filled in block).
+const MAX_BLOCK_JUMP: u64 = 256;
+
+/// Fills in blocks skipped by the `blocks` stream (e.g. missed notifications),
+/// so that transactions of every block are drained in order.
+fn without_gaps<S>(blocks: S) -> Box<Stream<Item=BlockNumber, Error=S::Error>> where
+    S: Stream<Item=BlockNumber> + 'static,
+{
+    let mut next = None;
+    Box::new(blocks.map(move |block| {
+        let first = match next {
+            Some(next) if block > next => cmp::max(next, block.saturating_sub(MAX_BLOCK_JUMP)),
+            _ => block,
+        };
+        if first < block {
+            debug!("Blocks {}..{} were skipped. Draining them in order.", first, block);
+        }
+        next = Some(block + 1);
+        stream::iter_ok::<_, S::Error>(first..block + 1)
+    }).flatten())
+}
+
+/// Drains transactions scheduled up to given block and submits them to the `sinks` once,
+/// independently of the block subscription (e.g. for admin triggers).
+///
+/// This method blocks until the transactions are handed over to the sinks and returns the summary.
+pub fn submit_block_now(
+    sinks: &[mpsc::Sender<(Transaction, Ack)>],
+    block_db: &Arc<Database>,
+    block: BlockNumber,
+    options: &SubmitterOptions,
+) -> RunSummary {
+    let summary = Arc::new(Mutex::new(RunSummary::default()));
+    submit_block(sinks, block_db, block, options, &summary).wait()
+        .expect("Unsent transactions are scheduled again; qed");
+    let summary = summary.lock().clone();
+    summary
+}
+
+/// Submits all transactions tracked as in-flight (submitted, but not confirmed) to the `sinks` again
+/// (e.g. after a node outage). Requires confirmation tracking (`options.tracker`).
+///
+/// The transactions are removed from the recently submitted set first, so they are re-broadcast
+/// even within the cooldown, but every transaction is sent once per requeue.
+/// This method blocks until the transactions are handed over to the sinks and returns the summary.
+pub fn requeue_in_flight(
+    sinks: &[mpsc::Sender<(Transaction, Ack)>],
+    options: &SubmitterOptions,
+) -> RunSummary {
+    let in_flight = match options.tracker {
+        Some(ref tracker) => tracker.lock().unconfirmed(),
+        None => {
+            warn!("Confirmation tracking is disabled. Nothing to requeue.");
+            return RunSummary::default();
+        },
+    };
+
+    info!("Requeueing {} in-flight transactions.", in_flight.len());
+    {
+        let mut recent = options.recent.lock();
+        for transaction in &in_flight {
+            recent.remove(transaction.hash());
+        }
+    }
+
+    let summary = Arc::new(Mutex::new(RunSummary { processed: 1, ..Default::default() }));
+    let submitter = Submitter::new(sinks.to_vec(), nonce_ordered(in_flight.into_iter()), options)
+        .with_summary(summary.clone());
+    if let Err(unsent) = submitter.wait() {
+        // Still tracked, so they are requeued by the next call.
+        warn!("Unable to requeue {} transactions.", unsent.len());
+    }
+    let summary = summary.lock().clone();
+    summary
+}
+
+/// Drains transactions scheduled up to given block and submits them to the sinks.
+fn submit_block(
+    sinks: &[mpsc::Sender<(Transaction, Ack)>],
+    block_db: &Arc<Database>,
+    block: BlockNumber,
+    options: &SubmitterOptions,
+    summary: &Arc<Mutex<RunSummary>>,
+) -> Box<Future<Item=(), Error=()>> {
+    debug!("Sending transactions for block: {}", block);
+    match drain(block_db, block, options) {
+        Ok(Some(mut iterator)) => if iterator.is_empty() {
+            summary.lock().processed += 1;
+            warn!("No transactions found in block: {}", block);
+            // Finishes draining (the files are removed).
+            let _ = iterator.next();
+            Box::new(future::ok(()))
+        } else {
+            let max_block = options.conditional_blocks.map(|blocks| block.saturating_add(blocks));
+            let (metrics, tracker, dead_letters) = (options.metrics.clone(), options.tracker.clone(), options.dead_letters.clone());
+            let iterator = iterator.filter(move |transaction| match transaction.deadline() {
+                // The transaction can't be included before the next block.
+                Some(deadline) if deadline <= block => {
+                    warn!("[{:?}] Deadline block {} has passed. Dropping transaction from {:?}.", transaction.hash(), deadline, transaction.sender());
+                    metrics.skipped();
+                    if let Some(ref tracker) = tracker {
+                        tracker.lock().dropped(transaction.clone());
+                    }
+                    if let Some(ref dead_letters) = dead_letters {
+                        dead_letters.insert(transaction.clone(), format!("Deadline block {} has passed.", deadline));
+                    }
+                    false
+                },
+                _ => true,
+            }).map(move |transaction| {
+                // Let the endpoint drop the transaction once the deadline passes (if submitted conditionally).
+                let max_block = match (max_block, transaction.deadline()) {
+                    (Some(max_block), Some(deadline)) => Some(cmp::min(max_block, deadline)),
+                    (max_block, _) => max_block,
+                };
+                match max_block {
+                    Some(max_block) => transaction.with_max_block(max_block),
+                    None => transaction,
                 }
-                Err(err) => {
-                    error!("Unable to read transactions for block {}: {:?}", block, err);
-                    Either::B(future::ok(()))
+            });
+            summary.lock().processed += 1;
+            submit(sinks.to_vec(), reverified(nonce_ordered(iterator), options), block_db, block, options, summary)
+        },
+        Ok(None) => {
+            trace!("Nothing scheduled up to block: {}", block);
+            Box::new(future::ok(()))
+        }
+        Err(err) => {
+            error!("Unable to read transactions for block {}: {:?}", block, err);
+            summary.lock().errors += 1;
+            Box::new(future::ok(()))
+        }
+    }
+}
+
+/// Submission state of a single chain (see `run_chains`).
+pub struct ChainSubmitter {
+    /// Id of the chain (used to key the summaries).
+    pub chain_id: u64,
+    /// Endpoints of the chain.
+    pub transports: Vec<TransportType>,
+    /// New blocks of the chain (e.g. from the `Updater` of the chain's blockchain).
+    pub listener: mpsc::Receiver<BlockNumber>,
+    /// Transactions scheduled for blocks of the chain.
+    pub block_db: Arc<Database>,
+    /// Transactions scheduled for timestamps on the chain (no timestamp submitter is run if not set).
+    pub timestamp_db: Option<Arc<Database>>,
+    /// Submitter options of the chain (shared by it's block and timestamp submitters).
+    pub options: SubmitterOptions,
+}
+
+/// Runs submitters of every chain, each on it's own threads, draining it's own databases
+/// to it's own transports.
+///
+/// This method blocks until block subscriptions of all chains are over and returns the summaries
+/// of block submitters keyed by chain id. Timestamp submitters keep running.
+pub fn run_chains(chains: Vec<ChainSubmitter>) -> HashMap<u64, Result<RunSummary, Error>> {
+    let handles = chains.into_iter().map(|chain| {
+        let ChainSubmitter { chain_id, transports, listener, block_db, timestamp_db, options } = chain;
+        if let Some(timestamp_db) = timestamp_db {
+            let (transports, options) = (transports.clone(), options.clone());
+            thread::spawn(move || {
+                if let Err(err) = run_timestamp(transports.into_iter(), timestamp_db, options) {
+                    error!("Error running timestamp submitter of chain {}: {:?}", chain_id, err);
                 }
-            }
-        })
-        .wait()
-        .map_err(|_| unreachable!())
+            });
+        }
+        let handle = thread::spawn(move || run_block(transports.into_iter(), listener, block_db, options));
+        (chain_id, handle)
+    }).collect::<Vec<_>>();
+
+    handles.into_iter().map(|(chain_id, handle)| {
+        (chain_id, handle.join().expect("Block submitter panicked."))
+    }).collect()
 }
 
 /// Spawns given number of transports and runs a submitter.
@@ -52,150 +903,1945 @@ pub fn run_block<I: Iterator<Item=TransportType>>(
 /// This method periodically submits all transactions
 /// scheduled for current time.
 ///
-/// This method blocks until block subscription is over.
+/// This method blocks until the thread is panicking and returns the summary of the run.
 pub fn run_timestamp<I: Iterator<Item=TransportType>>(
     types: I,
     timestamp_db: Arc<Database>,
-) -> Result<(), Error> {
-    let (sinks, _eloops) = init_transports(types)?;
+    options: SubmitterOptions,
+) -> Result<RunSummary, Error> {
+    let transports = init_transports(types, &options)?;
 
+    let summary = submit_timestamps(&transports.sinks, &timestamp_db, &options, || {
+        if ::std::thread::panicking() {
+            return false;
+        }
+
+        ::std::thread::sleep(::std::time::Duration::from_secs(1));
+        true
+    });
+
+    transports.shutdown(options.shutdown_timeout);
+    Ok(summary)
+}
+
+/// Submits transactions scheduled for current time (according to `options.clock`)
+/// and waits using `sleep` for as long as it returns `true`.
+fn submit_timestamps<F: FnMut() -> bool>(
+    sinks: &[mpsc::Sender<(Transaction, Ack)>],
+    timestamp_db: &Arc<Database>,
+    options: &SubmitterOptions,
+    mut sleep: F,
+) -> RunSummary {
+    let summary = Arc::new(Mutex::new(RunSummary::default()));
+    let window = cmp::max(options.timestamp_window.as_secs(), 1);
+    // The first drain also catches up with all transactions that became due while the submitter was not running.
+    let mut next_drain = 0;
     loop {
-        let time = ::time::now_utc().to_timespec().sec as u64;
-        match timestamp_db.drain(time) {
-            Ok(Some(iterator)) => {
-                debug!("Sending transactions for time: {}", time);
-                Submitter::new(sinks.clone(), iterator).wait()
-                    .expect("Submitter is never returning error; qed");
-            }
-            Err(err) => {
-                error!("Unable to read transactions for timestamp {}: {:?}", time, err);
-            },
-            _ => {}
+        prune(timestamp_db, options);
+        let time = options.clock.now_secs();
+        if time >= next_drain {
+            submit_due(sinks, timestamp_db, time, drain(timestamp_db, time, options), options, &summary);
+            next_drain = time + window;
         }
 
-        if ::std::thread::panicking() {
+        if !sleep() {
             break;
         }
-
-        ::std::thread::sleep(::std::time::Duration::from_secs(1))
     }
 
-    Ok(())
+    let summary = summary.lock().clone();
+    summary
 }
 
-fn init_transports<I: Iterator<Item=TransportType>>(mut types: I) 
-    -> Result<(Vec<mpsc::Sender<Transaction>>, Vec<transports::EventLoopHandle>), Error>
-{
-    let mut sinks = Vec::new();
-    let mut eloops = Vec::new();
-    while let Some(typ) = types.next() {
-        let (sink, eloop) = match typ {
-            TransportType::Ipc(path) => {
-                let (eloop, ipc) = transports::ipc::Ipc::new(&path)?;
-                (Sink::new_sink(&eloop, ipc), eloop)
-            },
-            TransportType::Http(url) => {
-                let (eloop, http) = transports::http::Http::new(&url)?;
-                (Sink::new_sink(&eloop, http), eloop)
-            }
-        };
-        sinks.push(sink);
-        eloops.push(eloop);
+/// Submits transactions drained for given timestamp and waits until they are handed over to the sinks.
+fn submit_due(
+    sinks: &[mpsc::Sender<(Transaction, Ack)>],
+    timestamp_db: &Arc<Database>,
+    time: u64,
+    drained: database::Result<Option<TransactionsIterator>>,
+    options: &SubmitterOptions,
+    summary: &Arc<Mutex<RunSummary>>,
+) {
+    match drained {
+        Ok(Some(iterator)) => {
+            debug!("Sending transactions for time: {}", time);
+            summary.lock().processed += 1;
+            submit(sinks.to_vec(), reverified(nonce_ordered(iterator), options), timestamp_db, time, options, summary).wait()
+                .expect("Unsent transactions are scheduled again; qed");
+        }
+        Err(err) => {
+            error!("Unable to read transactions for timestamp {}: {:?}", time, err);
+            summary.lock().errors += 1;
+        },
+        _ => {}
     }
-
-    Ok((sinks, eloops))
 }
 
-/// A sink for transactions that should be submitted to the network.
-struct Sink<T> {
-    _data: ::std::marker::PhantomData<T>,
+/// Removes transactions waiting in the queue for longer than `max_queue_age` (if configured).
+fn prune(db: &Database, options: &SubmitterOptions) {
+    let max_age = match options.max_queue_age {
+        Some(max_age) => max_age.as_secs(),
+        None => return,
+    };
+
+    let now = options.clock.now_secs();
+    match db.prune(now, max_age) {
+        Ok(0) => {},
+        Ok(removed) => info!("Dropped {} transactions waiting for more than {}s.", removed, max_age),
+        Err(err) => error!("Unable to drop stale transactions: {:?}", err),
+    }
 }
 
-impl<T: Transport + Send + 'static> Sink<T> {
-    pub fn new_sink(eloop: &transports::EventLoopHandle, transport: T) -> mpsc::Sender<Transaction> {
-        let (tx, rx) = mpsc::channel(1024);
-        Self::run(eloop, transport, rx);
-        tx
+/// Drains transactions scheduled up to given number (block or timestamp)
+/// unless the submission is paused.
+fn drain(db: &Database, num: u64, options: &SubmitterOptions) -> database::Result<Option<TransactionsIterator>> {
+    if options.is_paused() {
+        trace!("Submission is paused. Not draining: {}", num);
+        return Ok(None);
     }
 
-    fn run(
-        eloop: &transports::EventLoopHandle,
-        transport: T,
-        receiver: mpsc::Receiver<Transaction>,
-    ) {
-        let web3 = Web3::new(transport);
+    db.drain(num)
+}
 
-        info!("Waiting for transactions to submit...");
-        eloop.remote().spawn(move |_| receiver.for_each(move |transaction| {
-            debug!("[{:?}] Sending transaction from: {:?}", transaction.hash(), transaction.sender());
+/// Submits given transactions to all sinks (recording the outcome in the `summary`).
+/// Transactions that can't be handed over to any of the sinks are scheduled again for given number,
+/// transactions waiting for their dependencies are scheduled again for the next one.
+fn submit<I: Iterator<Item=Transaction> + 'static>(
+    sinks: Vec<mpsc::Sender<(Transaction, Ack)>>,
+    transactions: I,
+    db: &Arc<Database>,
+    num: u64,
+    options: &SubmitterOptions,
+    summary: &Arc<Mutex<RunSummary>>,
+) -> Box<Future<Item=(), Error=()>> {
+    let transactions = released(transactions, db, num, options);
+    let db = db.clone();
+    let submitter = Submitter::new(sinks, transactions, options).with_summary(summary.clone());
+    let (max_attempts, attempts) = (options.max_submit_attempts, options.submit_attempts.clone());
+    let (metrics, tracker, dead_letters) = (options.metrics.clone(), options.tracker.clone(), options.dead_letters.clone());
+    Box::new(submitter.or_else(move |unsent| {
+        warn!("Unable to submit {} transactions. Scheduling again for: {}", unsent.len(), num);
+        for transaction in unsent {
             let hash = *transaction.hash();
-            web3.eth().send_raw_transaction(transaction.rlp().into())
-                .then(move |res| {
-                    match res {
-                        Ok(hash) => debug!("[{:?}] Submitted transaction.", hash),
-                        Err(err) => warn!("[{:?}] Error submitting: {:?}.", hash, err),
+            if let Some(max_attempts) = max_attempts {
+                let mut attempts = attempts.lock();
+                let failed = {
+                    let failed = attempts.entry(hash).or_insert(0);
+                    *failed = failed.saturating_add(1);
+                    *failed
+                };
+                if failed >= max_attempts {
+                    attempts.remove(&hash);
+                    warn!("[{:?}] Giving up after {} attempts. Dropping transaction from {:?}.", hash, failed, transaction.sender());
+                    metrics.skipped();
+                    if let Some(ref tracker) = tracker {
+                        tracker.lock().dropped(transaction.clone());
                     }
-                    Ok(())
-                })
-        }))
-    }
+                    if let Some(ref dead_letters) = dead_letters {
+                        dead_letters.insert(transaction, format!("Unable to submit after {} attempts.", failed));
+                    }
+                    continue;
+                }
+            }
+            if let Err(err) = db.insert(num, transaction) {
+                error!("[{:?}] Unable to schedule again. Transaction is lost: {:?}", hash, err);
+            }
+        }
+        Ok(())
+    }))
 }
 
-type Sending = Future<
-    Item=Vec<mpsc::Sender<Transaction>>,
-    Error=mpsc::SendError<Transaction>,
->;
-/// Submits next transaction from the iterator to all sinks.
-struct Submitter<I> {
-    state: Option<Box<Sending>>,
+/// Orders transactions by priority (keeping the drain order within the same priority)
+/// and transactions of every sender by nonce, so that nodes never receive nonce `N + 1` before `N`.
+/// Transactions of different senders stay interleaved (each sender keeps the positions of it's transactions).
+fn nonce_ordered<I: Iterator<Item=Transaction>>(transactions: I) -> ::std::vec::IntoIter<Transaction> {
+    let mut transactions = transactions.collect::<Vec<_>>();
+    transactions.sort_by(|a, b| b.priority().cmp(&a.priority()));
+    let mut positions = HashMap::<Address, Vec<usize>>::new();
+    for (position, transaction) in transactions.iter().enumerate() {
+        positions.entry(*transaction.sender()).or_insert_with(Vec::new).push(position);
+    }
+
+    for positions in positions.values().filter(|positions| positions.len() > 1) {
+        let mut sorted = positions.iter().map(|&position| transactions[position].clone()).collect::<Vec<_>>();
+        sorted.sort_by_key(|transaction| verifier::decode(transaction).map(|tx| tx.nonce).unwrap_or_else(|_| U256::max_value()));
+        for (&position, transaction) in positions.iter().zip(sorted) {
+            transactions[position] = transaction;
+        }
+    }
+
+    transactions.into_iter()
+}
+
+/// A check run for every transaction just before it's submitted.
+type Check = Box<FnMut(&Transaction) -> Result<(), String>>;
+
+/// Wraps the iterator to re-verify transactions if it's enabled in options.
+///
+/// Transactions not submitted recently are re-verified together up front
+/// (with at most `batch_concurrency` lookups in flight, see `verifier::reverify_batch`).
+fn reverified(iterator: ::std::vec::IntoIter<Transaction>, options: &SubmitterOptions) -> Reverified<::std::vec::IntoIter<Transaction>> {
+    let check = options.reverify.clone().map(|blockchain| {
+        let batch = {
+            let (mut recent, now) = (options.recent.lock(), Instant::now());
+            iterator.as_slice().iter()
+                .filter(|transaction| !recent.contains(transaction.hash(), now))
+                .cloned()
+                .collect::<Vec<_>>()
+        };
+        let hashes = batch.iter().map(|transaction| *transaction.hash()).collect::<Vec<_>>();
+        let results = verifier::reverify_batch(blockchain, batch, options.batch_concurrency).wait()
+            .expect("Failed lookups are returned as results; qed");
+        let mut results = hashes.into_iter()
+            .zip(results)
+            .map(|(hash, result)| (hash, result.map_err(|err| verifier::reason(&err))))
+            .collect::<HashMap<_, _>>();
+        Box::new(move |transaction: &Transaction| {
+            results.remove(transaction.hash()).unwrap_or(Ok(()))
+        }) as Check
+    });
+
+    Reverified {
+        iterator,
+        check,
+        failure: options.reverify_failure.clone(),
+        metrics: options.metrics.clone(),
+        tracker: options.tracker.clone(),
+        recent: options.recent.clone(),
+    }
+}
+
+/// Skips transactions that don't pass the check anymore.
+/// Transactions submitted recently are not checked (they are skipped by the submitter anyway).
+struct Reverified<I> {
     iterator: I,
+    check: Option<Check>,
+    failure: ReverifyFailure,
+    metrics: Arc<Metrics>,
+    tracker: Option<Arc<Mutex<Tracker>>>,
+    recent: Arc<Mutex<RecentlySubmitted>>,
+}
+
+impl<I: Iterator<Item=Transaction>> Iterator for Reverified<I> {
+    type Item = Transaction;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let transaction = self.iterator.next()?;
+            let check = match self.check {
+                Some(ref mut check) => check,
+                None => return Some(transaction),
+            };
+            if self.recent.lock().contains(transaction.hash(), Instant::now()) {
+                return Some(transaction);
+            }
+
+            let reason = match check(&transaction) {
+                Ok(()) => return Some(transaction),
+                Err(reason) => reason,
+            };
+            if let ReverifyFailure::Log = self.failure {
+                warn!("[{:?}] Re-verification failed, submitting anyway: {}", transaction.hash(), reason);
+                return Some(transaction);
+            }
+
+            warn!("[{:?}] Skipping transaction from {:?}: {}", transaction.hash(), transaction.sender(), reason);
+            self.metrics.skipped();
+            if let Some(ref tracker) = self.tracker {
+                tracker.lock().dropped(transaction.clone());
+            }
+            if let ReverifyFailure::DeadLetter(ref dead_letters) = self.failure {
+                dead_letters.insert(transaction, format!("Re-verification failed: {}", reason));
+            }
+        }
+    }
+}
+
+/// Wraps the iterator to hold transactions until their dependencies are confirmed.
+/// Held transactions are scheduled again for `num + 1`.
+fn released<I: Iterator<Item=Transaction>>(iterator: I, db: &Arc<Database>, num: u64, options: &SubmitterOptions) -> Released<I> {
+    Released {
+        iterator,
+        db: db.clone(),
+        num,
+        metrics: options.metrics.clone(),
+        tracker: options.tracker.clone(),
+    }
+}
+
+/// Holds transactions which dependencies are not confirmed yet
+/// and drops transactions which dependencies were dropped.
+struct Released<I> {
+    iterator: I,
+    db: Arc<Database>,
+    num: u64,
+    metrics: Arc<Metrics>,
+    tracker: Option<Arc<Mutex<Tracker>>>,
+}
+
+impl<I> Released<I> {
+    /// Returns true if transaction with given hash is still scheduled
+    /// (assumes it is if the database can't be read).
+    fn scheduled(&self, hash: &H256) -> bool {
+        match self.db.find(|transaction| transaction.hash() == hash) {
+            Ok(found) => found.is_some(),
+            Err(err) => {
+                warn!("[{:?}] Unable to look up scheduled transaction: {:?}", hash, err);
+                true
+            },
+        }
+    }
+}
+
+impl<I: Iterator<Item=Transaction>> Iterator for Released<I> {
+    type Item = Transaction;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let transaction = self.iterator.next()?;
+            let depends_on = match transaction.depends_on() {
+                Some(depends_on) => *depends_on,
+                None => return Some(transaction),
+            };
+            let tracker = match self.tracker {
+                Some(ref tracker) => tracker,
+                None => {
+                    warn!("[{:?}] Confirmations are not tracked. Ignoring dependency on {:?}.", transaction.hash(), depends_on);
+                    return Some(transaction);
+                },
+            };
+
+            let status = tracker.lock().status(&depends_on);
+            match status {
+                Some(Status::Confirmed(_)) => {
+                    debug!("[{:?}] Dependency {:?} is confirmed. Releasing.", transaction.hash(), depends_on);
+                    return Some(transaction);
+                },
+                Some(Status::Dropped) => {
+                    warn!("[{:?}] Dependency {:?} was dropped. Dropping transaction from {:?}.", transaction.hash(), depends_on, transaction.sender());
+                    self.metrics.skipped();
+                    tracker.lock().dropped(transaction);
+                },
+                // Never submitted (and not scheduled anymore) or not tracked anymore.
+                None if !self.scheduled(&depends_on) => {
+                    debug!("[{:?}] Dependency {:?} is not tracked nor scheduled. Releasing.", transaction.hash(), depends_on);
+                    return Some(transaction);
+                },
+                _ => {
+                    let (hash, next) = (*transaction.hash(), self.num + 1);
+                    trace!("[{:?}] Waiting for confirmation of {:?}. Scheduling again for: {}", hash, depends_on, next);
+                    if let Err(err) = self.db.insert(next, transaction) {
+                        error!("[{:?}] Unable to schedule again. Transaction is lost: {:?}", hash, err);
+                    }
+                },
+            }
+        }
+    }
+}
+
+/// Sinks of all initialized transports.
+struct Transports {
+    sinks: Vec<mpsc::Sender<(Transaction, Ack)>>,
+    finished: Vec<std_mpsc::Receiver<()>>,
+    eloops: Vec<transports::EventLoopHandle>,
+}
+
+impl Transports {
+    /// Closes all sinks and waits (up to `timeout`) until transactions buffered in them are submitted.
+    /// Returns `false` if some of the sinks didn't finish in time.
+    fn shutdown(self, timeout: Duration) -> bool {
+        let Transports { sinks, finished, eloops } = self;
+        // Closing the sinks lets the receiving loops finish after processing buffered transactions.
+        drop(sinks);
+
+        let deadline = Instant::now() + timeout;
+        let mut all_finished = true;
+        for (endpoint, finished) in finished.into_iter().enumerate() {
+            let now = Instant::now();
+            let left = if deadline > now { deadline - now } else { Duration::from_secs(0) };
+            if finished.recv_timeout(left).is_err() {
+                warn!("Endpoint {} didn't submit all buffered transactions before shutdown.", endpoint);
+                all_finished = false;
+            }
+        }
+
+        // Event loops are dropped only after the sinks are finished.
+        drop(eloops);
+        all_finished
+    }
+}
+
+/// Orders transports by weight (highest first), preserving the original order of equal ones.
+fn by_weight<I: Iterator<Item=TransportType>>(types: I) -> Vec<TransportType> {
+    let mut types = types.collect::<Vec<_>>();
+    types.sort_by(|a, b| b.weight().cmp(&a.weight()));
+    types
+}
+
+/// Initializes sinks for all transports that could be constructed (ordered by weight).
+/// Returns an error only if none of the transports could be initialized.
+fn init_transports<I: Iterator<Item=TransportType>>(types: I, options: &SubmitterOptions) -> Result<Transports, Error> {
+    let mut transports = Transports {
+        sinks: Vec::new(),
+        finished: Vec::new(),
+        eloops: Vec::new(),
+    };
+    let types = by_weight(types);
+    if types.len() > options.max_transports {
+        return Err(ErrorKind::TooManyTransports(types.len(), options.max_transports).into());
+    }
+
+    let mut last_error = None;
+    for typ in types {
+        match init_transport(typ.clone(), options) {
+            Ok((sink, finished, eloop)) => {
+                transports.sinks.push(sink);
+                transports.finished.push(finished);
+                transports.eloops.push(eloop);
+            },
+            Err(err) => {
+                warn!("Unable to initialize transport {:?}: {:?}", typ, err);
+                last_error = Some(err);
+            },
+        }
+    }
+
+    if transports.sinks.is_empty() {
+        return Err(last_error.unwrap_or_else(|| ErrorKind::NoTransports.into()));
+    }
+
+    Ok(transports)
+}
+
+fn init_transport(typ: TransportType, options: &SubmitterOptions)
+    -> Result<(mpsc::Sender<(Transaction, Ack)>, std_mpsc::Receiver<()>, transports::EventLoopHandle), Error>
+{
+    Ok(match typ {
+        TransportType::Ipc(path) => {
+            let (eloop, ipc) = transports::ipc::Ipc::new(&path)?;
+            let (sink, finished) = Sink::new_sink(&eloop, ipc, Box::new(move || -> Result<_, Error> {
+                let (eloop, ipc) = transports::ipc::Ipc::new(&path)?;
+                Ok((Web3::new(ipc), Some(eloop)))
+            }), options);
+            (sink, finished, eloop)
+        },
+        TransportType::Http(url) => {
+            let (eloop, http) = transports::http::Http::new(&url)?;
+            let (sink, finished) = Sink::new_sink(&eloop, http, Box::new(move || -> Result<_, Error> {
+                let (eloop, http) = transports::http::Http::new(&url)?;
+                Ok((Web3::new(http), Some(eloop)))
+            }), options);
+            (sink, finished, eloop)
+        },
+        TransportType::Weighted(transport, _) => return init_transport(*transport, options),
+    })
+}
+
+/// A sink for transactions that should be submitted to the network.
+struct Sink<T> {
+    _data: ::std::marker::PhantomData<T>,
+}
+
+/// Web3 instance of a (re)connected endpoint together with it's event loop
+/// (`None` if the event loop is owned by the `Transports`).
+type Connected<T> = (Web3<T>, Option<transports::EventLoopHandle>);
+
+impl<T: Transport + Send + 'static> Sink<T> where
+    T::Out: 'static,
+{
+    /// Creates new sink submitting transactions using given transport.
+    /// The transport is rebuilt using `reconnect` if the connection is lost.
+    /// Returns the sender for transactions and a receiver notified when the sink is finished.
+    pub fn new_sink(
+        eloop: &transports::EventLoopHandle,
+        transport: T,
+        reconnect: Box<FnMut() -> Result<Connected<T>, Error> + Send>,
+        options: &SubmitterOptions,
+    ) -> (mpsc::Sender<(Transaction, Ack)>, std_mpsc::Receiver<()>) {
+        let (tx, rx) = mpsc::channel(1024);
+        let finished = Self::run(eloop, transport, reconnect, rx, options);
+        (tx, finished)
+    }
+
+    fn run(
+        eloop: &transports::EventLoopHandle,
+        transport: T,
+        reconnect: Box<FnMut() -> Result<Connected<T>, Error> + Send>,
+        receiver: mpsc::Receiver<(Transaction, Ack)>,
+        options: &SubmitterOptions,
+    ) -> std_mpsc::Receiver<()> {
+        let connection = Arc::new(Mutex::new(Connection::new((Web3::new(transport), None), reconnect, options.reconnect_delay)));
+        let metrics = options.metrics.clone();
+        let dry_run = options.dry_run;
+        let breaker = options.breaker_threshold.map(|threshold| {
+            Arc::new(Mutex::new(CircuitBreaker::new(threshold, options.breaker_cooldown)))
+        });
+        let in_flight = options.in_flight.clone();
+        // Cleared once the endpoint turns out not to support conditional submission.
+        let conditional = options.conditional_blocks.map(|_| Arc::new(AtomicBool::new(true)));
+        let transform = options.transform.clone();
+        let send_method = options.send_method.clone();
+
+        spawn_sink(eloop, receiver, move |(transaction, ack)| {
+            if dry_run {
+                info!("[{:?}] Would send transaction from: {:?}", transaction.hash(), transaction.sender());
+                metrics.submitted();
+                return Box::new(future::ok(()));
+            }
+
+            if let Some(ref breaker) = breaker {
+                if !breaker.lock().allow(Instant::now()) {
+                    debug!("[{:?}] Endpoint is excluded. Skipping.", transaction.hash());
+                    return Box::new(future::ok(()));
+                }
+            }
+
+            let transport = match connection.lock().get(Instant::now()) {
+                Some(&(ref web3, _)) => web3.transport().clone(),
+                None => {
+                    warn!("[{:?}] Endpoint is disconnected. Skipping.", transaction.hash());
+                    metrics.error();
+                    return Box::new(future::ok(()));
+                },
+            };
+
+            debug!("[{:?}] Sending {:?} transaction from: {:?}", transaction.hash(), transaction.kind(), transaction.sender());
+            let payload = transformed(transaction.rlp(), transform.as_ref());
+            let hash = *transaction.hash();
+            let metrics = metrics.clone();
+            let breaker = breaker.clone();
+            let connection = connection.clone();
+            let conditional = conditional.clone();
+            let send_method = send_method.clone();
+            let permit = match in_flight {
+                Some(ref in_flight) => Either::A(InFlight::acquire(in_flight).map(Some)),
+                None => Either::B(future::ok(None)),
+            };
+            Box::new(permit.and_then(move |permit| {
+                let submission: Box<Future<Item=(), Error=::web3::Error>> = match (conditional, transaction.max_block()) {
+                    (Some(ref supported), Some(max_block)) if supported.load(Ordering::SeqCst) => {
+                        let supported = supported.clone();
+                        let (rlp, fallback) = (payload.clone(), transport.clone());
+                        Box::new(transport.execute(SEND_CONDITIONAL, conditional_params(&payload, max_block)).map(|_| ()).or_else(move |err| {
+                            if !is_unsupported(&err) {
+                                return Either::A(future::err(err));
+                            }
+                            warn!("Endpoint doesn't support {}. Falling back to plain submission.", SEND_CONDITIONAL);
+                            supported.store(false, Ordering::SeqCst);
+                            Either::B(send_raw(|method, params| fallback.execute(method, params), &send_method, &rlp).map(|_| ()))
+                        }))
+                    },
+                    _ => Box::new(send_raw(|method, params| transport.execute(method, params), &send_method, &payload).map(|_| ())),
+                };
+                submission.then(move |res| -> Result<(), ()> {
+                    // Release the permit as soon as the submission is finished.
+                    drop(permit);
+                    if let Some(ack) = ack {
+                        let _ = ack.send(());
+                    }
+                    match res {
+                        Ok(()) => {
+                            debug!("[{:?}] Submitted transaction.", hash);
+                            metrics.submitted();
+                            if let Some(ref breaker) = breaker {
+                                breaker.lock().success();
+                            }
+                        },
+                        Err(err) => {
+                            warn!("[{:?}] Error submitting: {:?}.", hash, err);
+                            metrics.error();
+                            if is_disconnected(&err) {
+                                connection.lock().disconnected(Instant::now());
+                            }
+                            if let Some(ref breaker) = breaker {
+                                breaker.lock().failure(Instant::now());
+                            }
+                        },
+                    }
+                    Ok(())
+                })
+            }))
+        })
+    }
+}
+
+/// Spawns a loop on given event loop, submitting every transaction coming from the `receiver`.
+/// Returned receiver is notified when the `receiver` is closed and all transactions are processed.
+fn spawn_sink<F>(
+    eloop: &transports::EventLoopHandle,
+    receiver: mpsc::Receiver<(Transaction, Ack)>,
+    submit: F,
+) -> std_mpsc::Receiver<()> where
+    F: FnMut((Transaction, Ack)) -> Box<Future<Item=(), Error=()>> + Send + 'static,
+{
+    let (finished_tx, finished) = std_mpsc::channel();
+
+    info!("Waiting for transactions to submit...");
+    eloop.remote().spawn(move |_| receiver.for_each(submit).then(move |_| -> Result<(), ()> {
+        let _ = finished_tx.send(());
+        Ok(())
+    }));
+
+    finished
+}
+
+/// Hands over the transaction to given `(endpoint, sink)` pairs that are not busy.
+/// Returns false if all sinks are disconnected.
+fn try_send<'a, I: Iterator<Item=(usize, &'a mut mpsc::Sender<(Transaction, Ack)>)>>(
+    metrics: &Metrics,
+    sinks: I,
+    transaction: &Transaction,
+) -> bool {
+    let mut connected = false;
+    for (endpoint, sink) in sinks {
+        match sink.try_send((transaction.clone(), None)) {
+            Ok(()) => connected = true,
+            Err(ref err) if err.is_full() => {
+                debug!("[{:?}] Endpoint {} is busy. Dropping.", transaction.hash(), endpoint);
+                metrics.dropped(endpoint);
+                connected = true;
+            },
+            Err(_) => {
+                warn!("[{:?}] Endpoint {} is disconnected.", transaction.hash(), endpoint);
+            },
+        }
+    }
+    connected
+}
+
+type Sending = Future<
+    Item=Vec<Option<mpsc::Sender<(Transaction, Ack)>>>,
+    Error=(),
+>;
+
+/// Hand-over of a transaction to a single sink.
+/// Resolves to the sink (`None` if it's disconnected).
+type Handover = Future<
+    Item=Option<mpsc::Sender<(Transaction, Ack)>>,
+    Error=(),
+>;
+
+/// Hands over given transaction to the sink.
+/// Resolves to the sink once it accepts the transaction (or to `None` if it's disconnected).
+fn hand_over(sink: mpsc::Sender<(Transaction, Ack)>, transaction: Transaction) -> Box<Handover> {
+    let hash = *transaction.hash();
+    Box::new(sink.send((transaction, None)).then(move |res| -> Result<_, ()> {
+        if let Err(ref err) = res {
+            warn!("[{:?}] Endpoint is disconnected: {:?}", hash, err);
+        }
+        Ok(res.ok())
+    }))
+}
+
+enum State {
+    /// All sinks are ready to accept next transaction.
+    Ready(Vec<mpsc::Sender<(Transaction, Ack)>>),
+    /// Waiting for all sinks to accept current transaction.
+    Sending(Box<Sending>, Transaction),
+    /// Waiting for a quorum of sinks to accept current transaction (collecting sinks that are done).
+    Quorum {
+        transaction: Transaction,
+        accepted: usize,
+        needed: usize,
+        idle: Vec<mpsc::Sender<(Transaction, Ack)>>,
+    },
+    /// All transactions reached a quorum, waiting for hand-overs continuing in the background.
+    Finishing,
+}
+
+/// A future sending given set of transactions to all sinks.
+/// Resolves when all transactions are handed over to the sinks.
+///
+/// Fails if a transaction can't be handed over to any of the sinks
+/// (returning all transactions that were not sent).
+pub struct Submitter<I> {
+    state: Option<State>,
+    iterator: I,
+    mode: SendMode,
+    metrics: Arc<Metrics>,
+    recent: Arc<Mutex<RecentlySubmitted>>,
+    tracker: Option<Arc<Mutex<Tracker>>>,
+    summary: Option<Arc<Mutex<RunSummary>>>,
+    attempts: Arc<Mutex<HashMap<H256, u32>>>,
+    /// Hand-overs continuing after the transaction reached a quorum (together with the hash of the transaction
+    /// and transactions queued for the sink in the meantime).
+    background: Vec<(H256, Box<Handover>, VecDeque<Transaction>)>,
 }
 
 impl<I: Iterator<Item=Transaction>> Submitter<I> {
-    pub fn new(
-        sinks: Vec<mpsc::Sender<Transaction>>,
-        mut iterator: I,
+    /// Creates a submitter sending every transaction to each of the `sinks`.
+    pub fn new<T: IntoIterator<Item=Transaction, IntoIter=I>>(
+        sinks: Vec<mpsc::Sender<(Transaction, Ack)>>,
+        transactions: T,
+        options: &SubmitterOptions,
     ) -> Self {
-        if let Some(next) = iterator.next() {
-            debug!("[{:?}] Sending to {} endpoints.", next.hash(), sinks.len());
-            Submitter {
-                state: Some(Box::new(
-                    future::join_all(sinks.into_iter().map(move |sink| sink.send(next.clone())))
-                )),
-                iterator,
-            }
-        } else {
-            Submitter {
-                state: None,
-                iterator,
+        Submitter {
+            state: Some(State::Ready(sinks)),
+            iterator: transactions.into_iter(),
+            mode: options.mode,
+            metrics: options.metrics.clone(),
+            recent: options.recent.clone(),
+            tracker: options.tracker.clone(),
+            summary: None,
+            attempts: options.submit_attempts.clone(),
+            background: Vec::new(),
+        }
+    }
+
+    /// Records submitted and unsent transactions in given summary.
+    pub fn with_summary(mut self, summary: Arc<Mutex<RunSummary>>) -> Self {
+        self.summary = Some(summary);
+        self
+    }
+
+    /// Hands over the transaction to sinks that are not busy.
+    /// Returns false if all sinks are disconnected.
+    fn try_send(&self, sinks: &mut [mpsc::Sender<(Transaction, Ack)>], transaction: &Transaction) -> bool {
+        try_send(&self.metrics, sinks.iter_mut().enumerate(), transaction)
+    }
+
+    /// Marks given transaction as handed over to the sinks.
+    fn sent(&self, transaction: Transaction) {
+        if let Some(ref summary) = self.summary {
+            summary.lock().submitted += 1;
+        }
+        self.attempts.lock().remove(transaction.hash());
+        if let Some(ref tracker) = self.tracker {
+            tracker.lock().track(transaction);
+        }
+    }
+
+    /// Polls hand-overs continuing in the background, moving sinks that are done to `idle`
+    /// (sinks with queued transactions are handed over the next one instead).
+    /// Returns the number of sinks that accepted the transaction of given hash.
+    fn poll_background(&mut self, hash: Option<&H256>, idle: &mut Vec<mpsc::Sender<(Transaction, Ack)>>) -> usize {
+        let mut accepted = 0;
+        let mut pending = Vec::with_capacity(self.background.len());
+        for (mut transaction, mut handover, mut queued) in self.background.drain(..) {
+            loop {
+                match handover.poll() {
+                    Ok(Async::NotReady) => {
+                        pending.push((transaction, handover, queued));
+                        break;
+                    },
+                    Ok(Async::Ready(Some(sink))) => {
+                        if hash == Some(&transaction) {
+                            accepted += 1;
+                        }
+                        match queued.pop_front() {
+                            Some(next) => {
+                                transaction = *next.hash();
+                                handover = hand_over(sink, next);
+                            },
+                            None => {
+                                idle.push(sink);
+                                break;
+                            },
+                        }
+                    },
+                    // Disconnected sinks are not used anymore.
+                    Ok(Async::Ready(None)) | Err(()) => {
+                        if !queued.is_empty() {
+                            warn!("Dropping {} transactions queued for a disconnected endpoint.", queued.len());
+                        }
+                        break;
+                    },
+                }
             }
         }
+        self.background = pending;
+        accepted
+    }
+
+    /// Returns given transaction together with all remaining ones.
+    fn unsent(&mut self, transaction: Transaction) -> Vec<Transaction> {
+        warn!("[{:?}] All endpoints are disconnected.", transaction.hash());
+        self.recent.lock().remove(transaction.hash());
+        let unsent = Some(transaction).into_iter().chain(self.iterator.by_ref()).collect::<Vec<_>>();
+        if let Some(ref summary) = self.summary {
+            summary.lock().errors += unsent.len() as u64;
+        }
+        unsent
     }
 }
 
 impl<I: Iterator<Item=Transaction>> Future for Submitter<I> {
     type Item = ();
-    type Error = ();
+    type Error = Vec<Transaction>;
 
-    fn poll(&mut self) -> Poll<(), ()> {
+    fn poll(&mut self) -> Poll<(), Vec<Transaction>> {
         loop {
-            let next = match self.state {
+            let sinks = match self.state.take() {
                 None => return Ok(Async::Ready(())),
-                Some(ref mut sending) => {
-                    let sinks = try_ready!(sending.poll().map_err(|err| {
-                        warn!("Send error: {:?}", err);
-                    }));
-
-                    self.iterator.next().map(move |next| {
-                        debug!("[{:?}] Sending to {} endpoints.", next.hash(), sinks.len());
-                        Box::new(
-                            future::join_all(sinks.into_iter().map(move |sink| sink.send(next.clone())))
-                        ) as Box<Sending>
-                    })
+                Some(State::Ready(sinks)) => sinks,
+                Some(State::Sending(mut sending, transaction)) => match sending.poll() {
+                    Ok(Async::Ready(sinks)) => {
+                        let sinks = sinks.into_iter().filter_map(|sink| sink).collect::<Vec<_>>();
+                        if sinks.is_empty() {
+                            return Err(self.unsent(transaction));
+                        }
+                        self.sent(transaction);
+                        sinks
+                    },
+                    Ok(Async::NotReady) => {
+                        self.state = Some(State::Sending(sending, transaction));
+                        return Ok(Async::NotReady);
+                    },
+                    Err(()) => unreachable!("Sending to a single sink never fails; qed"),
+                },
+                Some(State::Quorum { transaction, accepted, needed, mut idle }) => {
+                    let accepted = accepted + self.poll_background(Some(transaction.hash()), &mut idle);
+                    let waiting = self.background.iter().any(|&(ref hash, _, _)| hash == transaction.hash());
+                    if accepted < needed && waiting {
+                        self.state = Some(State::Quorum { transaction, accepted, needed, idle });
+                        return Ok(Async::NotReady);
+                    }
+                    if accepted == 0 {
+                        return Err(self.unsent(transaction));
+                    }
+                    self.sent(transaction);
+                    idle
+                },
+                Some(State::Finishing) => {
+                    self.poll_background(None, &mut Vec::new());
+                    if self.background.is_empty() {
+                        return Ok(Async::Ready(()));
+                    }
+                    self.state = Some(State::Finishing);
+                    return Ok(Async::NotReady);
+                },
+            };
+            // Pick up sinks that are done with previous transactions.
+            let mut sinks = sinks;
+            if !self.background.is_empty() {
+                self.poll_background(None, &mut sinks);
+                if sinks.is_empty() {
+                    self.state = Some(State::Ready(sinks));
+                    return Ok(Async::NotReady);
                 }
+            }
+
+            let next = match self.iterator.next() {
+                Some(next) => next,
+                None if self.background.is_empty() => return Ok(Async::Ready(())),
+                None => {
+                    self.state = Some(State::Finishing);
+                    continue;
+                },
+            };
+
+            if !self.recent.lock().insert(*next.hash(), Instant::now()) {
+                debug!("[{:?}] Submitted recently. Skipping.", next.hash());
+                self.state = Some(State::Ready(sinks));
+                continue;
+            }
+
+            debug!("[{:?}] Sending to {} endpoints.", next.hash(), sinks.len());
+            let mode = self.mode;
+            self.state = Some(match mode {
+                SendMode::Wait => {
+                    let hash = *next.hash();
+                    let sending = future::join_all(sinks.into_iter().map(|sink| sink.send((next.clone(), None)).then(move |res| -> Result<_, ()> {
+                        if let Err(ref err) = res {
+                            warn!("[{:?}] Endpoint is disconnected: {:?}", hash, err);
+                        }
+                        Ok(res.ok())
+                    })));
+                    State::Sending(Box::new(sending), next)
+                },
+                SendMode::Priority => {
+                    let (hash, transaction) = (*next.hash(), next.clone());
+                    let sending = stream::iter_ok::<_, ()>(sinks).fold(Vec::new(), move |mut sent, sink| {
+                        sink.send((transaction.clone(), None)).then(move |res| -> Result<_, ()> {
+                            if let Err(ref err) = res {
+                                warn!("[{:?}] Endpoint is disconnected: {:?}", hash, err);
+                            }
+                            sent.push(res.ok());
+                            Ok(sent)
+                        })
+                    });
+                    State::Sending(Box::new(sending), next)
+                },
+                SendMode::Preferred if !sinks.is_empty() => {
+                    let (hash, metrics, transaction) = (*next.hash(), self.metrics.clone(), next.clone());
+                    let mut others = sinks;
+                    let preferred = others.remove(0);
+                    let (ack, acked) = oneshot::channel();
+                    let sending = preferred.send((next.clone(), Some(ack))).then(move |res| match res {
+                        // Resolves once the endpoint is done with the transaction (or dropped it).
+                        Ok(preferred) => Either::A(acked.then(move |_| -> Result<_, ()> { Ok(Some(preferred)) })),
+                        Err(err) => {
+                            warn!("[{:?}] Preferred endpoint is disconnected: {:?}", hash, err);
+                            Either::B(future::ok(None))
+                        },
+                    }).map(move |preferred| {
+                        // Broadcast to the others without waiting.
+                        let connected = try_send(&metrics, others.iter_mut().enumerate().map(|(idx, sink)| (idx + 1, sink)), &transaction);
+                        let mut sent = vec![preferred];
+                        if connected {
+                            sent.extend(others.into_iter().map(Some));
+                        }
+                        sent
+                    });
+                    State::Sending(Box::new(sending), next)
+                },
+                SendMode::Preferred => return Err(self.unsent(next)),
+                SendMode::Quorum(_) if sinks.is_empty() => return Err(self.unsent(next)),
+                SendMode::Quorum(quorum) => {
+                    let hash = *next.hash();
+                    let needed = cmp::min(cmp::max(quorum, 1), sinks.len());
+                    // Sinks still busy with previous transactions get this one once they are done.
+                    for &mut (_, _, ref mut queued) in &mut self.background {
+                        debug!("[{:?}] Endpoint is busy, queueing.", hash);
+                        queued.push_back(next.clone());
+                    }
+                    for sink in sinks {
+                        self.background.push((hash, hand_over(sink, next.clone()), VecDeque::new()));
+                    }
+                    State::Quorum { transaction: next, accepted: 0, needed, idle: Vec::new() }
+                },
+                SendMode::TrySend => {
+                    let mut sinks = sinks;
+                    if !self.try_send(&mut sinks, &next) {
+                        return Err(self.unsent(next));
+                    }
+                    self.sent(next);
+                    State::Ready(sinks)
+                },
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use futures::executor::{self, Notify};
+    use futures::{Future, Stream};
+    use futures::sync::mpsc;
+    use parking_lot::Mutex;
+    use tempdir::TempDir;
+    use types::U256;
+    use types::tests::tx;
+    use super::*;
+
+    /// Ignores notifications of futures polled manually.
+    struct Noop;
+    impl Notify for Noop {
+        fn notify(&self, _id: usize) {}
+    }
+
+    /// Collects transactions handed over to a sink (once all senders are dropped).
+    fn received(rx: mpsc::Receiver<(Transaction, Ack)>) -> Vec<Transaction> {
+        rx.map(|(transaction, _)| transaction).collect().wait().unwrap()
+    }
+
+    #[test]
+    fn should_drop_transactions_for_full_sinks() {
+        let (mut full, full_rx) = mpsc::channel(0);
+        let (other, other_rx) = mpsc::channel(16);
+        // Fill up the channel.
+        full.try_send((tx(1), None)).unwrap();
+        let options = SubmitterOptions {
+            mode: SendMode::TrySend,
+            ..Default::default()
+        };
+
+        Submitter::new(vec![full, other], vec![tx(0)].into_iter(), &options).wait().unwrap();
+
+        assert_eq!(options.metrics.dropped_per_endpoint(), vec![1]);
+        assert_eq!(received(full_rx), vec![tx(1)]);
+        assert_eq!(received(other_rx), vec![tx(0)]);
+    }
+
+    #[test]
+    fn should_send_each_transaction_to_each_sink() {
+        let (first, first_rx) = mpsc::channel(16);
+        let (second, second_rx) = mpsc::channel(16);
+
+        Submitter::new(vec![first, second], vec![tx(0), tx(1), tx(2)], &Default::default()).wait().unwrap();
+
+        assert_eq!(received(first_rx), vec![tx(0), tx(1), tx(2)]);
+        assert_eq!(received(second_rx), vec![tx(0), tx(1), tx(2)]);
+    }
+
+    #[test]
+    fn should_send_to_primary_endpoint_first() {
+        let backup = TransportType::Http("http://127.0.0.1:8546".into()).with_weight(1);
+        let primary = TransportType::Ipc("/tmp/txsched.ipc".into()).with_weight(10);
+        let other = TransportType::Http("http://127.0.0.1:8547".into());
+        let weights = by_weight(vec![other, backup, primary].into_iter()).iter().map(TransportType::weight).collect::<Vec<_>>();
+        assert_eq!(weights, vec![10, 1, 0]);
+
+        let (mut primary, primary_rx) = mpsc::channel(0);
+        let (backup, backup_rx) = mpsc::channel(16);
+        // The primary endpoint is busy.
+        primary.try_send((tx(1), None)).unwrap();
+        let options = SubmitterOptions {
+            mode: SendMode::Priority,
+            ..Default::default()
+        };
+        let noop = Arc::new(Noop);
+        let mut submitter = executor::spawn(Submitter::new(vec![primary, backup], vec![tx(0)], &options));
+        let mut backup_rx = executor::spawn(backup_rx.map(|(transaction, _)| transaction));
+
+        // Backup doesn't receive the transaction until the primary accepts it.
+        assert_eq!(submitter.poll_future_notify(&noop, 0), Ok(Async::NotReady));
+        assert_eq!(backup_rx.poll_stream_notify(&noop, 0), Ok(Async::NotReady));
+
+        let mut primary_rx = primary_rx.map(|(transaction, _)| transaction).wait();
+        assert_eq!(primary_rx.next(), Some(Ok(tx(1))));
+        assert_eq!(submitter.poll_future_notify(&noop, 0), Ok(Async::Ready(())));
+        assert_eq!(primary_rx.next(), Some(Ok(tx(0))));
+        assert_eq!(backup_rx.poll_stream_notify(&noop, 0), Ok(Async::Ready(Some(tx(0)))));
+    }
+
+    #[test]
+    fn should_broadcast_after_preferred_endpoint_submits() {
+        let (preferred, preferred_rx) = mpsc::channel(16);
+        let (other, other_rx) = mpsc::channel(16);
+        let options = SubmitterOptions {
+            mode: SendMode::Preferred,
+            ..Default::default()
+        };
+        let noop = Arc::new(Noop);
+        let mut submitter = executor::spawn(Submitter::new(vec![preferred, other], vec![tx(0)], &options));
+        let mut other_rx = executor::spawn(other_rx.map(|(transaction, _)| transaction));
+
+        // The preferred endpoint receives the transaction, but didn't submit it yet.
+        assert_eq!(submitter.poll_future_notify(&noop, 0), Ok(Async::NotReady));
+        let (submitted, ack) = preferred_rx.wait().next().unwrap().unwrap();
+        assert_eq!(submitted, tx(0));
+        assert_eq!(submitter.poll_future_notify(&noop, 0), Ok(Async::NotReady));
+        assert_eq!(other_rx.poll_stream_notify(&noop, 0), Ok(Async::NotReady));
+
+        // Others are dispatched only after the preferred endpoint is done.
+        ack.expect("Preferred endpoint is notified.").send(()).unwrap();
+        assert_eq!(submitter.poll_future_notify(&noop, 0), Ok(Async::Ready(())));
+        assert_eq!(other_rx.poll_stream_notify(&noop, 0), Ok(Async::Ready(Some(tx(0)))));
+    }
+
+    #[test]
+    fn should_progress_once_quorum_of_sinks_accepts() {
+        let (mut stuck, stuck_rx) = mpsc::channel(0);
+        let (first, first_rx) = mpsc::channel(16);
+        let (second, second_rx) = mpsc::channel(16);
+        // The endpoint is stuck.
+        stuck.try_send((tx(2), None)).unwrap();
+        let options = SubmitterOptions {
+            mode: SendMode::Quorum(2),
+            ..Default::default()
+        };
+        let summary = Arc::new(Mutex::new(RunSummary::default()));
+        let noop = Arc::new(Noop);
+        let submitter = Submitter::new(vec![stuck, first, second], vec![tx(0), tx(1)], &options).with_summary(summary.clone());
+        let mut submitter = executor::spawn(submitter);
+        let (mut first_rx, mut second_rx) = (
+            executor::spawn(first_rx.map(|(transaction, _)| transaction)),
+            executor::spawn(second_rx.map(|(transaction, _)| transaction)),
+        );
+
+        // Both transactions reach the quorum while the stuck endpoint didn't accept the first one yet.
+        assert_eq!(submitter.poll_future_notify(&noop, 0), Ok(Async::NotReady));
+        assert_eq!(summary.lock().submitted, 2);
+        for rx in &mut [&mut first_rx, &mut second_rx] {
+            assert_eq!(rx.poll_stream_notify(&noop, 0), Ok(Async::Ready(Some(tx(0)))));
+            assert_eq!(rx.poll_stream_notify(&noop, 0), Ok(Async::Ready(Some(tx(1)))));
+        }
+
+        // The hand-over to the stuck endpoint finishes in the background.
+        let mut stuck_rx = stuck_rx.map(|(transaction, _)| transaction).wait();
+        assert_eq!(stuck_rx.next(), Some(Ok(tx(2))));
+        assert_eq!(submitter.poll_future_notify(&noop, 0), Ok(Async::NotReady));
+        assert_eq!(stuck_rx.next(), Some(Ok(tx(0))));
+        // Followed by the transaction it missed while being busy.
+        assert_eq!(submitter.poll_future_notify(&noop, 0), Ok(Async::Ready(())));
+        assert_eq!(stuck_rx.next(), Some(Ok(tx(1))));
+        assert_eq!(summary.lock().submitted, 2);
+    }
+
+    #[test]
+    fn should_refuse_too_many_transports() {
+        let options = SubmitterOptions {
+            max_transports: 2,
+            ..Default::default()
+        };
+        let types = (0..3).map(|port| TransportType::Http(format!("http://127.0.0.1:{}", 8545 + port)));
+
+        match init_transports(types, &options) {
+            Err(err) => match *err.kind() {
+                ErrorKind::TooManyTransports(3, 2) => {},
+                _ => panic!("Unexpected error: {:?}", err),
+            },
+            Ok(_) => panic!("Expected an error."),
+        }
+    }
+
+    #[test]
+    fn should_schedule_unsent_transactions_again() {
+        let dir = TempDir::new("submitter").unwrap();
+        let db = Arc::new(Database::open(dir.path(), 1).unwrap());
+        db.insert(5, tx(0)).unwrap();
+        db.insert(5, tx(1)).unwrap();
+        db.insert(5, tx(2)).unwrap();
+        let options = SubmitterOptions::default();
+        let (sink, rx) = mpsc::channel(16);
+        let mut rx = Some(rx);
+
+        // The only endpoint disconnects after the first transaction.
+        let transactions = drain(&db, 5, &options).unwrap().unwrap().inspect(move |transaction| {
+            if transaction == &tx(1) {
+                drop(rx.take());
+            }
+        });
+        let summary = Arc::new(Mutex::new(RunSummary::default()));
+        submit(vec![sink], transactions, &db, 5, &options, &summary).wait().unwrap();
+
+        assert_eq!(db.drain(5).unwrap().unwrap().collect::<Vec<_>>(), vec![tx(1), tx(2)]);
+        assert_eq!(*summary.lock(), RunSummary { processed: 0, submitted: 1, errors: 2 });
+    }
+
+    #[test]
+    fn should_move_transactions_exhausting_attempts_to_dead_letters() {
+        let dir = TempDir::new("submitter").unwrap();
+        let db = Arc::new(Database::open(dir.path(), 1).unwrap());
+        let dead_letters = Arc::new(DeadLetters::new(16));
+        let options = SubmitterOptions {
+            max_submit_attempts: Some(2),
+            dead_letters: Some(dead_letters.clone()),
+            ..Default::default()
+        };
+        db.insert(5, tx(0)).unwrap();
+        let summary = Arc::new(Mutex::new(RunSummary::default()));
+        // All endpoints are disconnected.
+        let submit_disconnected = || {
+            let (sink, _) = mpsc::channel(16);
+            let transactions = drain(&db, 5, &options).unwrap().unwrap();
+            submit(vec![sink], transactions, &db, 5, &options, &summary).wait().unwrap();
+        };
+
+        // Scheduled again after the first attempt.
+        submit_disconnected();
+        assert!(dead_letters.is_empty());
+        assert_eq!(options.submit_attempts.lock().get(tx(0).hash()), Some(&1));
+
+        submit_disconnected();
+        assert_eq!(dead_letters.len(), 1);
+        let letter = dead_letters.get(tx(0).hash()).unwrap();
+        assert_eq!(letter.transaction, tx(0));
+        assert_eq!(letter.reason, "Unable to submit after 2 attempts.");
+        assert!(options.submit_attempts.lock().is_empty());
+    }
+
+    #[test]
+    fn should_summarize_the_run() {
+        let dir = TempDir::new("submitter").unwrap();
+        let db = Arc::new(Database::open(dir.path(), 1).unwrap());
+        db.insert(5, tx(0)).unwrap();
+        db.insert(7, tx(1)).unwrap();
+        db.insert(7, tx(2)).unwrap();
+        let (mut blocks, listener) = mpsc::channel(16);
+        for block in 4..9 {
+            blocks.try_send(block).unwrap();
+        }
+        drop(blocks);
+        let options = SubmitterOptions {
+            dry_run: true,
+            ..Default::default()
+        };
+
+        let transports = vec![TransportType::Http("http://127.0.0.1:1".into())];
+        let summary = run_block(transports.into_iter(), listener, db, options).unwrap();
+        assert_eq!(summary, RunSummary { processed: 2, submitted: 3, errors: 0 });
+    }
+
+    #[test]
+    fn should_fail_when_listener_closes_unexpectedly() {
+        let dir = TempDir::new("submitter").unwrap();
+        let db = Arc::new(Database::open(dir.path(), 1).unwrap());
+        let run = |options: SubmitterOptions| {
+            let (blocks, listener) = mpsc::channel(16);
+            // The producer dies.
+            drop(blocks);
+            let transports = vec![TransportType::Http("http://127.0.0.1:1".into())];
+            run_block(transports.into_iter(), listener, db.clone(), options)
+        };
+        let options = SubmitterOptions {
+            dry_run: true,
+            fail_on_listener_close: true,
+            ..Default::default()
+        };
+
+        let err = run(options.clone()).unwrap_err();
+        match *err.kind() {
+            ErrorKind::ListenerClosed => {},
+            ref kind => panic!("Unexpected error: {:?}", kind),
+        }
+        assert_eq!(run(SubmitterOptions { fail_on_listener_close: false, ..options.clone() }).unwrap(), RunSummary::default());
+        // Closing the listener is expected once stopped.
+        options.stop();
+        assert_eq!(run(options).unwrap(), RunSummary::default());
+    }
+
+    #[test]
+    fn should_run_submitter_per_chain() {
+        let (dir1, dir42) = (TempDir::new("chain1").unwrap(), TempDir::new("chain42").unwrap());
+        let db1 = Arc::new(Database::open(dir1.path(), 1).unwrap());
+        let db42 = Arc::new(Database::open(dir42.path(), 1).unwrap());
+        db1.insert(5, tx(0)).unwrap();
+        db42.insert(7, tx(1)).unwrap();
+        db42.insert(7, tx(2)).unwrap();
+        let chain = |chain_id, blocks: Vec<BlockNumber>, endpoints: u16, block_db| {
+            let (mut blockchain, listener) = mpsc::channel(16);
+            for block in blocks {
+                blockchain.try_send(block).unwrap();
+            }
+            let options = SubmitterOptions {
+                dry_run: true,
+                ..Default::default()
             };
+            let transports = (0..endpoints).map(|port| TransportType::Http(format!("http://127.0.0.1:{}", 1 + port))).collect();
+            let metrics = options.metrics.clone();
+            (ChainSubmitter { chain_id, transports, listener, block_db, timestamp_db: None, options }, metrics)
+        };
+        let (chain1, metrics1) = chain(1, vec![5], 2, db1);
+        let (chain42, metrics42) = chain(42, vec![5, 6, 7], 3, db42);
+
+        let summaries = run_chains(vec![chain1, chain42]);
+
+        assert_eq!(summaries.len(), 2);
+        assert_eq!(*summaries[&1].as_ref().unwrap(), RunSummary { processed: 1, submitted: 1, errors: 0 });
+        assert_eq!(*summaries[&42].as_ref().unwrap(), RunSummary { processed: 1, submitted: 2, errors: 0 });
+        // Every transaction is handed over to each endpoint of it's chain only.
+        assert_eq!(metrics1.total_submitted(), 2);
+        assert_eq!(metrics42.total_submitted(), 6);
+    }
+
+    #[test]
+    fn should_fill_in_skipped_blocks() {
+        let blocks = stream::iter_ok::<_, ()>(vec![10, 14, 15, 13, 1_000]);
+
+        let mut expected = vec![10, 11, 12, 13, 14, 15, 13];
+        expected.extend(1_000 - MAX_BLOCK_JUMP..1_001);
+        assert_eq!(without_gaps(blocks).collect().wait().unwrap(), expected);
+    }
+
+    #[test]
+    fn should_send_higher_priority_transactions_first() {
+        use types::Priority;
+
+        let dir = TempDir::new("submitter").unwrap();
+        let db = Arc::new(Database::open(dir.path(), 1).unwrap());
+        let (low, normal, high) = (tx(0).with_priority(Priority::Low), tx(1), tx(2).with_priority(Priority::High));
+        db.insert(5, low.clone()).unwrap();
+        db.insert(5, normal.clone()).unwrap();
+        db.insert(5, high.clone()).unwrap();
+        let (sink, rx) = mpsc::channel(16);
+
+        submit_block_now(&[sink], &db, 5, &Default::default());
+
+        assert_eq!(received(rx), vec![high, normal, low]);
+    }
+
+    #[test]
+    fn should_drop_transactions_past_deadline() {
+        let dir = TempDir::new("submitter").unwrap();
+        let db = Arc::new(Database::open(dir.path(), 1).unwrap());
+        let tracker = Arc::new(Mutex::new(Tracker::new(1)));
+        let options = SubmitterOptions {
+            tracker: Some(tracker.clone()),
+            ..Default::default()
+        };
+        // Not drained for block 5 (e.g. the submitter was paused).
+        db.insert(5, tx(0).with_deadline(6)).unwrap();
+        db.insert(5, tx(1).with_deadline(7)).unwrap();
+        let (sink, rx) = mpsc::channel(16);
+
+        let summary = submit_block_now(&[sink], &db, 6, &options);
+
+        assert_eq!(summary.submitted, 1);
+        assert_eq!(received(rx), vec![tx(1).with_deadline(7)]);
+        assert_eq!(tracker.lock().status(tx(0).hash()), Some(::tracker::Status::Dropped));
+        assert_eq!(options.metrics.total_skipped(), 1);
+    }
+
+    #[test]
+    fn should_drain_each_skipped_block() {
+        use types::tests::signed;
+
+        let dir = TempDir::new("submitter").unwrap();
+        let db = Arc::new(Database::open(dir.path(), 1).unwrap());
+        for block in 11..15 {
+            db.insert(block, signed(Address::from(block), 0)).unwrap();
+        }
+        let (mut blocks, listener) = mpsc::channel(16);
+        blocks.try_send(10).unwrap();
+        blocks.try_send(14).unwrap();
+        drop(blocks);
+        let options = SubmitterOptions {
+            dry_run: true,
+            ..Default::default()
+        };
+
+        let transports = vec![TransportType::Http("http://127.0.0.1:1".into())];
+        let summary = run_block(transports.into_iter(), listener, db.clone(), options).unwrap();
+        // Every block is drained separately.
+        assert_eq!(summary, RunSummary { processed: 4, submitted: 4, errors: 0 });
+        assert_eq!(db.pending(), 0);
+    }
+
+    #[test]
+    fn should_track_sent_transactions() {
+        let (sink, _rx) = mpsc::channel(16);
+        let tracker = Arc::new(Mutex::new(Tracker::new(1)));
+        let options = SubmitterOptions {
+            tracker: Some(tracker.clone()),
+            ..Default::default()
+        };
+
+        Submitter::new(vec![sink], vec![tx(0), tx(1)], &options).wait().unwrap();
+
+        assert_eq!(tracker.lock().status(tx(0).hash()), Some(::tracker::Status::Pending));
+        assert_eq!(tracker.lock().status(tx(1).hash()), Some(::tracker::Status::Pending));
+    }
+
+    #[test]
+    fn should_requeue_in_flight_transactions_once() {
+        let (sink, rx) = mpsc::channel(16);
+        let tracker = Arc::new(Mutex::new(Tracker::new(1)));
+        let options = SubmitterOptions {
+            tracker: Some(tracker.clone()),
+            ..Default::default()
+        };
+        Submitter::new(vec![sink.clone()], vec![tx(0), tx(1), tx(2)], &options).wait().unwrap();
+        // Confirmed transactions are not requeued.
+        tracker.lock().update(tx(2).hash(), Some(1), 5);
+
+        let summary = requeue_in_flight(&[sink.clone()], &options);
+        // Submitting the same transactions again within the cooldown is still skipped.
+        Submitter::new(vec![sink], vec![tx(0), tx(1)], &options).wait().unwrap();
+
+        assert_eq!(summary.submitted, 2);
+        let mut requeued = received(rx).split_off(3);
+        requeued.sort_by_key(|transaction| *transaction.hash());
+        let mut expected = vec![tx(0), tx(1)];
+        expected.sort_by_key(|transaction| *transaction.hash());
+        assert_eq!(requeued, expected);
+        assert_eq!(tracker.lock().in_flight(), 2);
+    }
+
+    #[test]
+    fn should_not_drain_when_paused() {
+        let dir = TempDir::new("submitter").unwrap();
+        let db = Database::open(dir.path(), 1).unwrap();
+        db.insert(5, tx(0)).unwrap();
+        let options = SubmitterOptions::default();
+
+        options.pause();
+        assert!(drain(&db, 5, &options).unwrap().is_none());
+        assert_eq!(db.has(&5), true);
+
+        options.resume();
+        let mut iter = drain(&db, 5, &options).unwrap().unwrap();
+        assert_eq!(iter.next(), Some(tx(0)));
+        assert_eq!(iter.next(), None);
+        assert_eq!(db.has(&5), false);
+    }
+
+    #[test]
+    fn should_skip_transports_that_cannot_be_initialized() {
+        let options = Default::default();
+        let valid = TransportType::Http("http://127.0.0.1:8545".into());
+        let invalid = TransportType::Ipc("/non-existent/txsched.ipc".into());
+
+        let transports = init_transports(vec![valid, invalid.clone()].into_iter(), &options).unwrap();
+        assert_eq!(transports.sinks.len(), 1);
 
-            self.state = next;
+        assert!(init_transports(vec![invalid].into_iter(), &options).is_err());
+    }
+
+    #[test]
+    fn should_fail_without_transports() {
+        let dir = TempDir::new("submitter").unwrap();
+        let db = Arc::new(Database::open(dir.path(), 1).unwrap());
+        let (_tx, listener) = mpsc::channel(1);
+
+        let err = run_block(Vec::new().into_iter(), listener, db.clone(), Default::default()).unwrap_err();
+        assert_eq!(err.to_string(), "No transports configured.");
+        let err = run_timestamp(Vec::new().into_iter(), db, Default::default()).unwrap_err();
+        match *err.kind() {
+            ErrorKind::NoTransports => {},
+            ref kind => panic!("Unexpected error: {:?}", kind),
         }
     }
+
+    #[test]
+    fn should_submit_buffered_transactions_on_shutdown() {
+        let (eloop, _http) = transports::http::Http::new("http://127.0.0.1:8545").unwrap();
+        let submitted = Arc::new(Mutex::new(Vec::new()));
+        let (mut sink, receiver) = mpsc::channel(16);
+        let finished = {
+            let submitted = submitted.clone();
+            spawn_sink(&eloop, receiver, move |(transaction, _)| {
+                // Simulate a slow endpoint, so that transactions stay buffered.
+                thread::sleep(Duration::from_millis(50));
+                submitted.lock().push(transaction);
+                Box::new(future::ok(()))
+            })
+        };
+        sink.try_send((tx(0), None)).unwrap();
+        sink.try_send((tx(1), None)).unwrap();
+        sink.try_send((tx(2), None)).unwrap();
+
+        let transports = Transports {
+            sinks: vec![sink],
+            finished: vec![finished],
+            eloops: vec![eloop],
+        };
+
+        assert_eq!(transports.shutdown(Duration::from_secs(5)), true);
+        assert_eq!(*submitted.lock(), vec![tx(0), tx(1), tx(2)]);
+    }
+
+    #[test]
+    fn should_not_send_transactions_in_dry_run() {
+        let dir = TempDir::new("submitter").unwrap();
+        let db = Database::open(dir.path(), 1).unwrap();
+        db.insert(5, tx(0)).unwrap();
+        db.insert(5, tx(1)).unwrap();
+        let options = SubmitterOptions {
+            dry_run: true,
+            ..Default::default()
+        };
+        // There is no node listening, so any request would end up with an error.
+        let transports = init_transports(vec![TransportType::Http("http://127.0.0.1:1".into())].into_iter(), &options).unwrap();
+
+        let iterator = drain(&db, 5, &options).unwrap().unwrap();
+        Submitter::new(transports.sinks.clone(), iterator, &options).wait().unwrap();
+        assert!(transports.shutdown(Duration::from_secs(5)));
+
+        assert_eq!(db.has(&5), false);
+        assert_eq!(options.metrics.total_submitted(), 2);
+        assert_eq!(options.metrics.total_errors(), 0);
+    }
+
+    #[test]
+    fn should_skip_transactions_failing_reverification() {
+        let metrics = Arc::new(Metrics::default());
+        // Sender of the first transaction has sent another transaction in the meantime.
+        let stale = *tx(0).sender();
+        let check = Box::new(move |transaction: &Transaction| {
+            let nonce = if *transaction.sender() == stale { 1u64 } else { 0 };
+            let tx = verifier::decode(transaction).unwrap();
+            verifier::verify_state(&tx, U256::max_value(), nonce.into(), &Default::default(), None).map_err(|err| verifier::reason(&err))
+        }) as Check;
+        let iterator = Reverified {
+            iterator: vec![tx(0), tx(1)].into_iter(),
+            check: Some(check),
+            failure: ReverifyFailure::Drop,
+            metrics: metrics.clone(),
+            tracker: None,
+            recent: Arc::new(Mutex::new(RecentlySubmitted::new(Duration::from_secs(60), 16))),
+        };
+
+        assert_eq!(iterator.collect::<Vec<_>>(), vec![tx(1)]);
+        assert_eq!(metrics.total_skipped(), 1);
+    }
+
+    #[test]
+    fn should_move_transactions_failing_reverification_to_dead_letters() {
+        let dead_letters = Arc::new(DeadLetters::default());
+        let failing = *tx(0).hash();
+        let check = Box::new(move |transaction: &Transaction| {
+            if *transaction.hash() == failing { Err("Insufficient balance".to_owned()) } else { Ok(()) }
+        }) as Check;
+        let reverified = |check, failure| Reverified {
+            iterator: vec![tx(0), tx(1)].into_iter(),
+            check: Some(check),
+            failure,
+            metrics: Default::default(),
+            tracker: None,
+            recent: Arc::new(Mutex::new(RecentlySubmitted::new(Duration::from_secs(60), 16))),
+        };
+
+        let submitted = reverified(check, ReverifyFailure::DeadLetter(dead_letters.clone())).collect::<Vec<_>>();
+
+        assert_eq!(submitted, vec![tx(1)]);
+        assert_eq!(dead_letters.len(), 1);
+        let letter = dead_letters.get(&failing).unwrap();
+        assert_eq!(letter.transaction, tx(0));
+        assert_eq!(letter.reason, "Re-verification failed: Insufficient balance");
+
+        // Logged only
+        let check = Box::new(|_: &Transaction| -> Result<(), String> { Err("Insufficient balance".to_owned()) }) as Check;
+        assert_eq!(reverified(check, ReverifyFailure::Log).collect::<Vec<_>>(), vec![tx(0), tx(1)]);
+    }
+
+    #[test]
+    fn should_reverify_transactions_in_bounded_batch() {
+        use serde_json::Value;
+        use blockchain::tests::MockNode;
+
+        // Every sender has sent one transaction in the meantime.
+        let node = MockNode::start(|method, _| {
+            thread::sleep(Duration::from_millis(20));
+            match method {
+                "eth_getBalance" => Some(Value::String("0xde0b6b3a7640000".into())),
+                "eth_getTransactionCount" => Some(Value::String("0x1".into())),
+                _ => None,
+            }
+        });
+        let options = SubmitterOptions {
+            reverify: Some(Arc::new(Blockchain::new(node.url(), None).unwrap())),
+            batch_concurrency: 2,
+            ..Default::default()
+        };
+
+        let submitted = reverified(vec![tx(0), tx(1), tx(2), tx(3)].into_iter(), &options).collect::<Vec<_>>();
+
+        assert_eq!(submitted, vec![tx(1), tx(2), tx(3)]);
+        assert_eq!(options.metrics.total_skipped(), 1);
+        assert_eq!(node.calls("eth_getBalance"), 4);
+        assert!(node.max_in_flight("eth_getBalance") <= 2);
+    }
+
+    #[test]
+    fn should_release_dependent_transaction_after_confirmation() {
+        let dir = TempDir::new("submitter").unwrap();
+        let db = Arc::new(Database::open(dir.path(), 1).unwrap());
+        let tracker = Arc::new(Mutex::new(Tracker::new(1)));
+        let options = SubmitterOptions {
+            tracker: Some(tracker.clone()),
+            ..Default::default()
+        };
+        let dependent = tx(1).with_dependency(*tx(0).hash());
+        tracker.lock().track(tx(0));
+
+        // Held (and scheduled for the next block) until the dependency is confirmed.
+        assert_eq!(released(vec![dependent.clone()].into_iter(), &db, 5, &options).count(), 0);
+        tracker.lock().update(tx(0).hash(), Some(5), 5);
+        let held = drain(&db, 6, &options).unwrap().unwrap();
+        assert_eq!(released(held, &db, 6, &options).count(), 0);
+
+        // Released once it's confirmed.
+        tracker.lock().update(tx(0).hash(), Some(5), 6);
+        let held = drain(&db, 7, &options).unwrap().unwrap();
+        assert_eq!(released(held, &db, 7, &options).collect::<Vec<_>>(), vec![dependent]);
+        assert!(!db.has(&8));
+    }
+
+    #[test]
+    fn should_release_transaction_with_unknown_dependency() {
+        let dir = TempDir::new("submitter").unwrap();
+        let db = Arc::new(Database::open(dir.path(), 2).unwrap());
+        let tracker = Arc::new(Mutex::new(Tracker::new(1)));
+        let options = SubmitterOptions {
+            tracker: Some(tracker.clone()),
+            ..Default::default()
+        };
+        let dependent = tx(1).with_dependency(*tx(0).hash());
+
+        // Held while the dependency is still scheduled.
+        db.insert(9, tx(0)).unwrap();
+        assert_eq!(released(vec![dependent.clone()].into_iter(), &db, 5, &options).count(), 0);
+        assert!(db.has(&6));
+
+        // Released once the dependency is neither scheduled nor tracked.
+        db.remove(&9, tx(0).hash()).unwrap();
+        let held = drain(&db, 6, &options).unwrap().unwrap();
+        assert_eq!(released(held, &db, 6, &options).collect::<Vec<_>>(), vec![dependent]);
+        assert!(!db.has(&7));
+    }
+
+    #[test]
+    fn should_drop_transaction_with_dropped_dependency() {
+        let dir = TempDir::new("submitter").unwrap();
+        let db = Arc::new(Database::open(dir.path(), 1).unwrap());
+        let tracker = Arc::new(Mutex::new(Tracker::new(1)));
+        let options = SubmitterOptions {
+            tracker: Some(tracker.clone()),
+            ..Default::default()
+        };
+        let dependent = tx(1).with_dependency(*tx(0).hash());
+        tracker.lock().dropped(tx(0));
+
+        assert_eq!(released(vec![dependent].into_iter(), &db, 5, &options).count(), 0);
+        assert!(!db.has(&6));
+        assert_eq!(options.metrics.total_skipped(), 1);
+        // Transactions depending on the dropped one are dropped as well.
+        assert_eq!(tracker.lock().status(tx(1).hash()), Some(Status::Dropped));
+    }
+
+    #[test]
+    fn should_submit_block_on_demand() {
+        let dir = TempDir::new("submitter").unwrap();
+        let db = Arc::new(Database::open(dir.path(), 1).unwrap());
+        db.insert(5, tx(0)).unwrap();
+        db.insert(5, tx(1)).unwrap();
+        db.insert(7, tx(2)).unwrap();
+        let (first, first_rx) = mpsc::channel(16);
+        let (second, second_rx) = mpsc::channel(16);
+
+        let summary = submit_block_now(&[first, second], &db, 5, &Default::default());
+
+        assert_eq!(summary.processed, 1);
+        assert_eq!(summary.submitted, 2);
+        assert_eq!(received(first_rx), vec![tx(0), tx(1)]);
+        assert_eq!(received(second_rx), vec![tx(0), tx(1)]);
+        assert!(!db.has(&5));
+        assert_eq!(db.pending(), 1);
+    }
+
+    #[test]
+    fn should_submit_transaction_due_by_both_triggers_once() {
+        let dir = TempDir::new("submitter").unwrap();
+        let block_db = Arc::new(Database::open(dir.path(), 1).unwrap());
+        let timestamp_db = Arc::new(Database::open(dir.path().join("time"), 1).unwrap());
+        block_db.insert(5, tx(0)).unwrap();
+        timestamp_db.insert(1_000, tx(0)).unwrap();
+        // Block and timestamp submitters share recently submitted transactions.
+        let block_options = SubmitterOptions::default();
+        let timestamp_options = block_options.clone();
+        let summary = Arc::new(Mutex::new(RunSummary::default()));
+        let (sink, rx) = mpsc::channel(16);
+
+        let transactions = drain(&block_db, 5, &block_options).unwrap().unwrap();
+        submit(vec![sink.clone()], transactions, &block_db, 5, &block_options, &summary).wait().unwrap();
+        submit_due(&[sink], &timestamp_db, 1_000, drain(&timestamp_db, 1_000, &timestamp_options), &timestamp_options, &summary);
+
+        assert_eq!(received(rx), vec![tx(0)]);
+        assert_eq!(summary.lock().submitted, 1);
+    }
+
+    #[test]
+    fn should_not_resubmit_within_cooldown() {
+        let (sink, rx) = mpsc::channel(16);
+        let options = SubmitterOptions::default();
+
+        Submitter::new(vec![sink.clone()], vec![tx(0)], &options).wait().unwrap();
+        Submitter::new(vec![sink], vec![tx(0), tx(1)], &options).wait().unwrap();
+
+        assert_eq!(received(rx), vec![tx(0), tx(1)]);
+    }
+
+    #[test]
+    fn should_expire_recently_submitted() {
+        let now = Instant::now();
+        let mut recent = RecentlySubmitted::new(Duration::from_secs(10), 2);
+        let (a, b, c) = (*tx(0).hash(), *tx(1).hash(), *tx(2).hash());
+
+        assert!(recent.insert(a, now));
+        assert!(!recent.insert(a, now + Duration::from_secs(9)));
+        assert!(recent.insert(a, now + Duration::from_secs(10)));
+
+        // Oldest entries are evicted when over capacity.
+        let now = now + Duration::from_secs(10);
+        assert!(recent.insert(b, now));
+        assert!(recent.insert(c, now));
+        assert!(recent.insert(a, now));
+        assert_eq!(recent.queue.len(), 2);
+    }
+
+    #[test]
+    fn should_not_resubmit_transactions_submitted_before_restart() {
+        let dir = TempDir::new("submitter").unwrap();
+        let journal_dir = TempDir::new("journal").unwrap();
+        let journal = journal_dir.path().join("submitted");
+        let db = Arc::new(Database::open(dir.path(), 1).unwrap());
+        let recent = || RecentlySubmitted::new(Duration::from_secs(60), 16);
+        {
+            let mut recent = recent().with_journal(&journal, 1_000).unwrap();
+            assert!(recent.insert(*tx(0).hash(), Instant::now()));
+            assert!(recent.insert(*tx(2).hash(), Instant::now()));
+            recent.remove(tx(2).hash());
+        }
+
+        // Restart: the in-flight record is lost, but the transaction is still in the database.
+        db.insert(5, tx(0)).unwrap();
+        db.insert(5, tx(1)).unwrap();
+        db.insert(5, tx(2)).unwrap();
+        let options = SubmitterOptions {
+            recent: Arc::new(Mutex::new(recent().with_journal(&journal, 1_030).unwrap())),
+            ..Default::default()
+        };
+        let (sink, rx) = mpsc::channel(16);
+        let summary = submit_block_now(&[sink], &db, 5, &options);
+
+        assert_eq!(received(rx), vec![tx(1), tx(2)]);
+        assert_eq!(summary.submitted, 2);
+
+        // Entries older than the cooldown are not restored.
+        let mut expired = recent().with_journal(&journal, 1_060).unwrap();
+        assert!(!expired.contains(tx(0).hash(), Instant::now()));
+        assert!(expired.contains(tx(1).hash(), Instant::now()));
+    }
+
+    #[test]
+    fn should_submit_transactions_when_clock_reaches_timestamp() {
+        use clock::tests::MockClock;
+
+        let dir = TempDir::new("submitter").unwrap();
+        let db = Arc::new(Database::open(dir.path(), 1).unwrap());
+        db.insert(990, tx(0)).unwrap();
+        db.insert(1_002, tx(1)).unwrap();
+        db.insert(1_005, tx(2)).unwrap();
+        let clock = Arc::new(MockClock::new(1_000));
+        let options = SubmitterOptions {
+            clock: clock.clone(),
+            ..Default::default()
+        };
+        let (sink, rx) = mpsc::channel(16);
+
+        let mut pending = Vec::new();
+        let summary = submit_timestamps(&[sink], &db, &options, || {
+            pending.push((clock.now_secs(), db.pending()));
+            clock.advance(1);
+            clock.now_secs() <= 1_005
+        });
+
+        assert_eq!(pending, vec![(1_000, 2), (1_001, 2), (1_002, 1), (1_003, 1), (1_004, 1), (1_005, 0)]);
+        assert_eq!(received(rx), vec![tx(0), tx(1), tx(2)]);
+        assert_eq!(summary, RunSummary { processed: 3, submitted: 3, errors: 0 });
+    }
+
+    #[test]
+    fn should_drain_transactions_due_within_window_together() {
+        use clock::tests::MockClock;
+
+        let dir = TempDir::new("submitter").unwrap();
+        let db = Arc::new(Database::open(dir.path(), 1).unwrap());
+        db.insert(1_001, tx(0)).unwrap();
+        db.insert(1_002, tx(1)).unwrap();
+        db.insert(1_003, tx(2)).unwrap();
+        let clock = Arc::new(MockClock::new(1_000));
+        let options = SubmitterOptions {
+            clock: clock.clone(),
+            timestamp_window: Duration::from_secs(2),
+            ..Default::default()
+        };
+        let (sink, rx) = mpsc::channel(16);
+
+        let mut pending = Vec::new();
+        let summary = submit_timestamps(&[sink], &db, &options, || {
+            pending.push((clock.now_secs(), db.pending()));
+            clock.advance(1);
+            clock.now_secs() <= 1_004
+        });
+
+        // Nothing is drained before it's due.
+        assert_eq!(pending, vec![(1_000, 3), (1_001, 3), (1_002, 1), (1_003, 1), (1_004, 0)]);
+        assert_eq!(received(rx), vec![tx(0), tx(1), tx(2)]);
+        assert_eq!(summary, RunSummary { processed: 2, submitted: 3, errors: 0 });
+    }
+
+    #[test]
+    fn should_catch_up_with_past_due_timestamps_on_startup() {
+        use clock::tests::MockClock;
+
+        let dir = TempDir::new("submitter").unwrap();
+        {
+            let db = Database::open(dir.path(), 4).unwrap();
+            db.insert(1_000, tx(0)).unwrap();
+            db.insert(1_010, tx(1)).unwrap();
+            db.insert(1_020, tx(2)).unwrap();
+            db.insert(2_000, tx(3)).unwrap();
+        }
+
+        // Restarted after downtime.
+        let db = Arc::new(Database::open(dir.path(), 4).unwrap());
+        let options = SubmitterOptions {
+            clock: Arc::new(MockClock::new(1_500)),
+            ..Default::default()
+        };
+        let (sink, rx) = mpsc::channel(16);
+
+        let summary = submit_timestamps(&[sink], &db, &options, || false);
+
+        assert_eq!(received(rx), vec![tx(0), tx(1), tx(2)]);
+        assert_eq!(db.pending(), 1);
+        assert_eq!(summary, RunSummary { processed: 1, submitted: 3, errors: 0 });
+    }
+
+    #[test]
+    fn should_prune_stale_transactions() {
+        use clock::tests::MockClock;
+
+        let clock = Arc::new(MockClock::new(1_000));
+        let dir = TempDir::new("submitter").unwrap();
+        let db = Database::open(dir.path(), 1).unwrap().with_clock(clock.clone());
+        let options = SubmitterOptions {
+            clock: clock.clone(),
+            max_queue_age: Some(Duration::from_secs(60)),
+            ..Default::default()
+        };
+        db.insert(5, tx(0)).unwrap();
+        clock.advance(30);
+        db.insert(5, tx(1)).unwrap();
+        db.insert(7, tx(2)).unwrap();
+
+        clock.advance(60);
+        prune(&db, &options);
+        assert_eq!(db.pending(), 2);
+
+        clock.advance(1);
+        prune(&db, &options);
+        assert_eq!(db.pending(), 0);
+        assert_eq!(db.has(&7), false);
+        // Senders should be allowed again.
+        db.insert(6, tx(0)).unwrap();
+    }
+
+    #[test]
+    fn should_send_transactions_of_each_sender_in_nonce_order() {
+        use types::tests::signed;
+
+        let (a, b) = (Address::from(1), Address::from(2));
+        let (sink, rx) = mpsc::channel(16);
+        let transactions = vec![signed(a, 1), signed(b, 6), signed(b, 5), signed(a, 0)];
+
+        Submitter::new(vec![sink], nonce_ordered(transactions.into_iter()), &Default::default()).wait().unwrap();
+
+        assert_eq!(received(rx), vec![signed(a, 0), signed(b, 5), signed(b, 6), signed(a, 1)]);
+    }
+
+    #[test]
+    fn should_limit_outstanding_submissions() {
+        use std::sync::atomic::AtomicUsize;
+
+        let in_flight = Arc::new(InFlight::new(2));
+        let outstanding = Arc::new(AtomicUsize::new(0));
+        let max_outstanding = Arc::new(Mutex::new(0));
+
+        let submissions = (0..6).map(|_| {
+            let (in_flight, outstanding, max_outstanding) = (in_flight.clone(), outstanding.clone(), max_outstanding.clone());
+            thread::spawn(move || {
+                let _permit = InFlight::acquire(&in_flight).wait().unwrap();
+                let now = outstanding.fetch_add(1, Ordering::SeqCst) + 1;
+                {
+                    let mut max = max_outstanding.lock();
+                    *max = ::std::cmp::max(*max, now);
+                }
+                // Slow transport.
+                thread::sleep(Duration::from_millis(20));
+                outstanding.fetch_sub(1, Ordering::SeqCst);
+            })
+        }).collect::<Vec<_>>();
+        for submission in submissions {
+            submission.join().unwrap();
+        }
+
+        assert!(*max_outstanding.lock() <= 2);
+        assert_eq!(in_flight.state.lock().outstanding, 0);
+    }
+
+    #[test]
+    fn should_reconnect_dropped_endpoint_with_backoff() {
+        use std::sync::atomic::AtomicUsize;
+
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let connect = {
+            let attempts = attempts.clone();
+            Box::new(move || -> Result<usize, Error> {
+                // The endpoint is back on the third attempt.
+                let attempt = attempts.fetch_add(1, Ordering::SeqCst) + 1;
+                if attempt < 3 {
+                    Err(ErrorKind::Transport("Connection refused".into()).into())
+                } else {
+                    Ok(attempt)
+                }
+            })
+        };
+        let now = Instant::now();
+        let mut connection = Connection::new(0, connect, Duration::from_secs(1));
+        assert_eq!(connection.get(now), Some(&0));
+
+        connection.disconnected(now);
+        assert_eq!(connection.get(now), None);
+        assert_eq!(attempts.load(Ordering::SeqCst), 0);
+
+        let now = now + Duration::from_secs(1);
+        assert_eq!(connection.get(now), None);
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+
+        // The delay is doubled after a failed attempt.
+        assert_eq!(connection.get(now + Duration::from_secs(1)), None);
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+        let now = now + Duration::from_secs(2);
+        assert_eq!(connection.get(now), None);
+        assert_eq!(attempts.load(Ordering::SeqCst), 2);
+
+        let now = now + Duration::from_secs(4);
+        assert_eq!(connection.get(now), Some(&3));
+        assert_eq!(connection.delay, Duration::from_secs(1));
+    }
+
+    #[test]
+    fn should_submit_with_block_precondition() {
+        use types::tests::raw;
+
+        let params = conditional_params(tx(0).rlp(), 0x1f);
+        assert_eq!(params.len(), 2);
+        assert_eq!(params[0], ::serde_json::to_value(&Bytes::new(raw(0))).unwrap());
+        assert_eq!(params[1]["blockNumberMax"], "0x1f");
+    }
+
+    #[test]
+    fn should_submit_using_configured_method() {
+        use types::tests::raw;
+
+        let options = SubmitterOptions::default();
+        assert_eq!(options.send_method, "eth_sendRawTransaction");
+
+        // Records the requests instead of sending them.
+        let mut requests = Vec::new();
+        send_raw(|method, params| requests.push((method.to_owned(), params)), &options.send_method, tx(0).rlp());
+        send_raw(|method, params| requests.push((method.to_owned(), params)), "relay_submitRawTransaction", tx(1).rlp());
+
+        assert_eq!(requests[0], ("eth_sendRawTransaction".to_owned(), vec![::serde_json::to_value(&Bytes::new(raw(0))).unwrap()]));
+        assert_eq!(requests[1], ("relay_submitRawTransaction".to_owned(), vec![::serde_json::to_value(&Bytes::new(raw(1))).unwrap()]));
+    }
+
+    #[test]
+    fn should_submit_transformed_payload() {
+        use types::tests::raw;
+
+        let transform = Transform::new(|rlp: &[u8]| {
+            let mut payload = vec![0xff];
+            payload.extend_from_slice(rlp);
+            payload
+        });
+        let payload = transformed(tx(0).rlp(), Some(&transform));
+        let mut expected = vec![0xff];
+        expected.extend_from_slice(&raw(0));
+
+        assert_eq!(payload, expected);
+        assert_eq!(conditional_params(&payload, 0x1f)[0], ::serde_json::to_value(&Bytes::new(expected)).unwrap());
+        assert_eq!(transformed(tx(0).rlp(), None), raw(0));
+    }
+
+    #[test]
+    fn should_exclude_failing_endpoint_until_cooldown() {
+        let now = Instant::now();
+        let mut breaker = CircuitBreaker::new(3, Duration::from_secs(30));
+
+        breaker.failure(now);
+        breaker.failure(now);
+        assert!(breaker.allow(now));
+        breaker.failure(now);
+        assert!(!breaker.allow(now));
+        assert!(!breaker.allow(now + Duration::from_secs(29)));
+
+        // Half-open probe fails.
+        let now = now + Duration::from_secs(30);
+        assert!(breaker.allow(now));
+        breaker.failure(now);
+        assert!(!breaker.allow(now + Duration::from_secs(1)));
+
+        // Half-open probe succeeds.
+        let now = now + Duration::from_secs(30);
+        assert!(breaker.allow(now));
+        breaker.success();
+        breaker.failure(now);
+        assert!(breaker.allow(now));
+    }
 }
@@ -0,0 +1,394 @@
+//! Confirmation tracking of submitted transactions
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use futures::Future;
+use parking_lot::Mutex;
+
+use blockchain::Blockchain;
+use clock::{Clock, SystemClock};
+use types::{BlockNumber, Transaction, H256};
+
+/// Confirmation status of a submitted transaction.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Status {
+    /// Submitted, but not included in any block yet.
+    Pending,
+    /// Included in given block, but not confirmed by enough blocks yet.
+    Included(BlockNumber),
+    /// Included in given block and confirmed by enough blocks.
+    Confirmed(BlockNumber),
+    /// Dropped by the scheduler without being submitted.
+    Dropped,
+}
+
+/// How long a pending transaction is given to be mined before it's considered dropped.
+/// Both thresholds have to pass, so on slow chains the transaction is given more time.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct DropGrace {
+    /// Number of blocks mined since the transaction was seen pending.
+    pub blocks: u64,
+    /// Number of seconds since the transaction was submitted (or seen pending again after a reorg).
+    pub seconds: u64,
+}
+
+#[derive(Debug)]
+struct Tracked {
+    transaction: Transaction,
+    status: Status,
+    /// Block the transaction was first seen pending at (if it was polled already).
+    pending_block: Option<BlockNumber>,
+    /// Unix timestamp the transaction was submitted at (or seen pending again after a reorg).
+    pending_time: u64,
+}
+
+/// Tracks submitted transactions until they are confirmed.
+#[derive(Debug)]
+pub struct Tracker {
+    depth: u64,
+    max_tracked: Option<usize>,
+    drop_grace: DropGrace,
+    clock: Arc<Clock>,
+    transactions: HashMap<H256, Tracked>,
+    /// Number of transactions confirmed so far (including the ones not tracked anymore).
+    confirmed: usize,
+}
+
+impl Tracker {
+    /// Creates a tracker considering transactions confirmed
+    /// after `depth` blocks are mined on top of the inclusion block.
+    pub fn new(depth: u64) -> Self {
+        Tracker {
+            depth,
+            max_tracked: None,
+            drop_grace: DropGrace::default(),
+            clock: Arc::new(SystemClock),
+            transactions: HashMap::new(),
+            confirmed: 0,
+        }
+    }
+
+    /// Considers pending transactions dropped only after given grace period.
+    pub fn with_drop_grace(mut self, drop_grace: DropGrace) -> Self {
+        self.drop_grace = drop_grace;
+        self
+    }
+
+    /// Uses given source of current time.
+    pub fn with_clock(mut self, clock: Arc<Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Tracks at most `limit` transactions.
+    /// Once the limit is reached confirmed and dropped transactions are forgotten
+    /// and new transactions are not tracked if it's still reached.
+    pub fn with_max_tracked(mut self, limit: usize) -> Self {
+        self.max_tracked = Some(limit);
+        self
+    }
+
+    /// Starts tracking given (just submitted) transaction.
+    /// Returns `false` if the transaction is not tracked because the tracker is full.
+    pub fn track(&mut self, transaction: Transaction) -> bool {
+        self.insert(transaction, Status::Pending)
+    }
+
+    /// Records that given transaction was dropped without being submitted
+    /// (transactions depending on it are dropped as well).
+    pub fn dropped(&mut self, transaction: Transaction) -> bool {
+        let hash = *transaction.hash();
+        if let Some(tracked) = self.transactions.get_mut(&hash) {
+            tracked.status = Status::Dropped;
+            return true;
+        }
+        self.insert(transaction, Status::Dropped)
+    }
+
+    fn insert(&mut self, transaction: Transaction, status: Status) -> bool {
+        let hash = *transaction.hash();
+        let limit = self.max_tracked.unwrap_or(usize::max_value());
+        if self.transactions.len() >= limit && !self.transactions.contains_key(&hash) {
+            self.evict_finished();
+        }
+        if self.transactions.len() >= limit && !self.transactions.contains_key(&hash) {
+            warn!("[{:?}] Already tracking {} transactions. Not tracking.", hash, self.transactions.len());
+            return false;
+        }
+
+        trace!("[{:?}] Tracking.", hash);
+        let now = self.clock.now_secs();
+        self.transactions.entry(hash).or_insert(Tracked {
+            transaction,
+            status,
+            pending_block: None,
+            pending_time: now,
+        });
+        true
+    }
+
+    /// Stops tracking confirmed and dropped transactions.
+    fn evict_finished(&mut self) {
+        let before = self.transactions.len();
+        self.transactions.retain(|_, tracked| match tracked.status {
+            Status::Confirmed(_) | Status::Dropped => false,
+            _ => true,
+        });
+        debug!("Forgot {} confirmed or dropped transactions.", before - self.transactions.len());
+    }
+
+    /// Returns status of given transaction or `None` if it's not tracked.
+    pub fn status(&self, hash: &H256) -> Option<Status> {
+        self.transactions.get(hash).map(|tracked| tracked.status)
+    }
+
+    /// Returns true if given transaction is still pending after the drop grace period
+    /// (both `blocks` since it was first seen pending and `seconds` since it was submitted have passed).
+    pub fn considered_dropped(&self, hash: &H256, latest: BlockNumber) -> bool {
+        let tracked = match self.transactions.get(hash) {
+            Some(tracked) if tracked.status == Status::Pending => tracked,
+            _ => return false,
+        };
+
+        match tracked.pending_block {
+            Some(block) => {
+                latest >= block + self.drop_grace.blocks
+                    && self.clock.now_secs() >= tracked.pending_time + self.drop_grace.seconds
+            },
+            None => false,
+        }
+    }
+
+    /// Returns all submitted transactions that are not confirmed yet.
+    pub fn unconfirmed(&self) -> Vec<Transaction> {
+        self.transactions.values()
+            .filter(|tracked| match tracked.status {
+                Status::Confirmed(_) | Status::Dropped => false,
+                _ => true,
+            })
+            .map(|tracked| tracked.transaction.clone())
+            .collect()
+    }
+
+    /// Returns number of submitted transactions that are not confirmed yet.
+    pub fn in_flight(&self) -> usize {
+        self.transactions.values()
+            .filter(|tracked| match tracked.status {
+                Status::Confirmed(_) | Status::Dropped => false,
+                _ => true,
+            })
+            .count()
+    }
+
+    /// Returns number of transactions confirmed so far.
+    pub fn confirmed(&self) -> usize {
+        self.confirmed
+    }
+
+    /// Updates the status of given transaction.
+    ///
+    /// `included` is the block the transaction receipt points to (if any)
+    /// and `latest` is the latest block of the chain.
+    /// Returns the new status or `None` if the transaction is not tracked.
+    pub fn update(&mut self, hash: &H256, included: Option<BlockNumber>, latest: BlockNumber) -> Option<Status> {
+        let depth = self.depth;
+        let now = self.clock.now_secs();
+        let confirmed = &mut self.confirmed;
+        let tracked = self.transactions.get_mut(hash)?;
+        match tracked.status {
+            Status::Confirmed(_) | Status::Dropped => return Some(tracked.status),
+            _ => {},
+        }
+
+        tracked.status = match (tracked.status, included) {
+            (Status::Included(block), None) => {
+                warn!("[{:?}] Receipt from block {} is gone (reorg?).", hash, block);
+                tracked.pending_block = None;
+                tracked.pending_time = now;
+                Status::Pending
+            },
+            (_, None) => Status::Pending,
+            (_, Some(block)) if latest >= block + depth => {
+                debug!("[{:?}] Confirmed in block {}.", hash, block);
+                *confirmed += 1;
+                Status::Confirmed(block)
+            },
+            (_, Some(block)) => Status::Included(block),
+        };
+        if tracked.status == Status::Pending && tracked.pending_block.is_none() {
+            tracked.pending_block = Some(latest);
+        }
+        Some(tracked.status)
+    }
+}
+
+/// Fetches receipts of all unconfirmed transactions and updates their status.
+pub fn poll(tracker: &Mutex<Tracker>, blockchain: &Blockchain) {
+    let latest = blockchain.latest_block();
+    let unconfirmed = tracker.lock().unconfirmed();
+    trace!("Fetching receipts of {} transactions.", unconfirmed.len());
+    let mut dropped = 0;
+    for transaction in unconfirmed {
+        let hash = *transaction.hash();
+        match blockchain.transaction_block(hash).wait() {
+            Ok(included) => {
+                let mut tracker = tracker.lock();
+                tracker.update(&hash, included, latest);
+                if tracker.considered_dropped(&hash, latest) {
+                    dropped += 1;
+                }
+            },
+            Err(err) => {
+                warn!("[{:?}] Unable to fetch receipt: {:?}", hash, err);
+            },
+        }
+    }
+    if dropped > 0 {
+        warn!("{} transactions were not mined within the drop grace period.", dropped);
+    }
+}
+
+/// Polls receipts of tracked transactions every `interval`.
+///
+/// This method blocks indefinitely.
+pub fn run(tracker: &Mutex<Tracker>, blockchain: &Blockchain, interval: Duration) {
+    every(interval, thread::sleep, || {
+        poll(tracker, blockchain);
+        true
+    })
+}
+
+/// Runs `f` every `interval` (waiting with given `sleep` function) for as long as it returns `true`.
+fn every<S, F>(interval: Duration, mut sleep: S, mut f: F) where
+    S: FnMut(Duration),
+    F: FnMut() -> bool,
+{
+    while f() {
+        sleep(interval);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use types::tests::tx;
+    use super::*;
+
+    #[test]
+    fn should_poll_at_configured_interval() {
+        use std::cell::Cell;
+
+        let now = Cell::new(Duration::from_secs(0));
+        let mut polls = Vec::new();
+        every(
+            Duration::from_secs(12),
+            |duration| now.set(now.get() + duration),
+            || {
+                polls.push(now.get().as_secs());
+                polls.len() < 4
+            },
+        );
+
+        assert_eq!(polls, vec![0, 12, 24, 36]);
+    }
+
+    #[test]
+    fn should_confirm_after_depth() {
+        let mut tracker = Tracker::new(3);
+        let hash = *tx(0).hash();
+        tracker.track(tx(0));
+        assert_eq!(tracker.status(&hash), Some(Status::Pending));
+
+        assert_eq!(tracker.update(&hash, None, 10), Some(Status::Pending));
+        assert_eq!(tracker.update(&hash, Some(11), 11), Some(Status::Included(11)));
+        assert_eq!(tracker.update(&hash, Some(11), 13), Some(Status::Included(11)));
+        assert_eq!(tracker.update(&hash, Some(11), 14), Some(Status::Confirmed(11)));
+        assert_eq!(tracker.unconfirmed(), vec![]);
+        assert_eq!(tracker.update(tx(1).hash(), Some(11), 14), None);
+    }
+
+    #[test]
+    fn should_stop_tracking_over_limit() {
+        use types::Address;
+        use types::tests::signed;
+
+        let tx = |id: u64| signed(Address::from(id + 1), 0);
+        let mut tracker = Tracker::new(3).with_max_tracked(10);
+        let tracked = (0..20).filter(|id| tracker.track(tx(*id))).count();
+
+        assert_eq!(tracked, 10);
+        assert_eq!(tracker.in_flight(), 10);
+        assert_eq!(tracker.status(tx(9).hash()), Some(Status::Pending));
+        assert_eq!(tracker.status(tx(10).hash()), None);
+        // Already tracked transactions are still accepted.
+        assert!(tracker.track(tx(0)));
+    }
+
+    #[test]
+    fn should_forget_finished_transactions_over_limit() {
+        use types::Address;
+        use types::tests::signed;
+
+        let tx = |id: u64| signed(Address::from(id + 1), 0);
+        let mut tracker = Tracker::new(3).with_max_tracked(3);
+        assert!(tracker.track(tx(0)));
+        assert!(tracker.track(tx(1)));
+        assert!(tracker.dropped(tx(2)));
+        assert_eq!(tracker.update(tx(0).hash(), Some(10), 13), Some(Status::Confirmed(10)));
+
+        // Confirmed and dropped transactions make room for new ones.
+        assert!(tracker.track(tx(3)));
+        assert!(tracker.track(tx(4)));
+        assert_eq!(tracker.status(tx(0).hash()), None);
+        assert_eq!(tracker.status(tx(2).hash()), None);
+        assert_eq!(tracker.in_flight(), 3);
+        assert_eq!(tracker.confirmed(), 1);
+        // Still full of pending transactions.
+        assert!(!tracker.track(tx(5)));
+    }
+
+    #[test]
+    fn should_consider_transaction_dropped_after_both_thresholds() {
+        use clock::tests::MockClock;
+
+        let clock = Arc::new(MockClock::new(1_000));
+        let mut tracker = Tracker::new(3)
+            .with_drop_grace(DropGrace { blocks: 5, seconds: 60 })
+            .with_clock(clock.clone());
+        let hash = *tx(0).hash();
+        tracker.track(tx(0));
+        assert!(!tracker.considered_dropped(&hash, 100));
+
+        assert_eq!(tracker.update(&hash, None, 10), Some(Status::Pending));
+        // Enough blocks, but not enough time.
+        assert_eq!(tracker.update(&hash, None, 20), Some(Status::Pending));
+        assert!(!tracker.considered_dropped(&hash, 20));
+        // Enough time, but not enough blocks.
+        clock.advance(60);
+        assert!(!tracker.considered_dropped(&hash, 14));
+        assert!(tracker.considered_dropped(&hash, 15));
+
+        // Included transactions are never dropped.
+        assert_eq!(tracker.update(&hash, Some(21), 21), Some(Status::Included(21)));
+        assert!(!tracker.considered_dropped(&hash, 100));
+    }
+
+    #[test]
+    fn should_not_confirm_transaction_removed_by_reorg() {
+        let mut tracker = Tracker::new(3);
+        let hash = *tx(0).hash();
+        tracker.track(tx(0));
+
+        assert_eq!(tracker.update(&hash, Some(11), 12), Some(Status::Included(11)));
+        // Block 11 is reorged out.
+        assert_eq!(tracker.update(&hash, None, 12), Some(Status::Pending));
+        assert_eq!(tracker.update(&hash, None, 14), Some(Status::Pending));
+        assert_eq!(tracker.unconfirmed(), vec![tx(0)]);
+
+        // Included again in another block.
+        assert_eq!(tracker.update(&hash, Some(13), 15), Some(Status::Included(13)));
+        assert_eq!(tracker.update(&hash, Some(13), 16), Some(Status::Confirmed(13)));
+    }
+}
@@ -1,6 +1,5 @@
 use byteorder::{LittleEndian, ByteOrder};
 use ethcore::transaction::SignedTransaction;
-use rlp;
 
 mod bytes;
 
@@ -8,6 +7,32 @@ pub use self::bytes::Bytes;
 
 pub type BlockNumber = u64;
 
+/// Returns the block on which a transaction scheduled for `target` block should be submitted.
+/// Transactions are stored and drained using that number.
+pub fn effective_submit_block(target: BlockNumber, submit_earlier: u64, per_tx_lead: u64) -> BlockNumber {
+    target.saturating_sub(submit_earlier).saturating_sub(per_tx_lead)
+}
+
+/// A block number a transaction is scheduled for, known to be above the latest block.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TargetBlock(BlockNumber);
+
+impl TargetBlock {
+    /// Returns `None` unless `block` is strictly above the `latest` block.
+    pub fn new(block: BlockNumber, latest: BlockNumber) -> Option<Self> {
+        if block > latest {
+            Some(TargetBlock(block))
+        } else {
+            None
+        }
+    }
+
+    /// Returns the block number.
+    pub fn number(&self) -> BlockNumber {
+        self.0
+    }
+}
+
 pub type Address = ::ethcore_bigint::hash::H160;
 pub type U256 = ::ethcore_bigint::prelude::U256;
 pub type H256 = ::ethcore_bigint::hash::H256;
@@ -21,27 +46,131 @@ pub enum Condition {
 	Timestamp(u64),
 }
 
+/// Transaction envelope type (EIP-2718).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TransactionKind {
+    /// Legacy (untyped) transaction, a plain RLP list.
+    Legacy,
+    /// Type 1 transaction (EIP-2930).
+    AccessList,
+    /// Type 2 transaction (EIP-1559).
+    DynamicFee,
+    /// Any other typed transaction.
+    Other(u8),
+}
+
+impl TransactionKind {
+    /// Classifies raw transaction by it's leading byte.
+    /// Returns `None` if the bytes are not a valid transaction envelope.
+    pub fn classify(raw: &[u8]) -> Option<Self> {
+        match raw.first() {
+            None => None,
+            Some(&byte) if byte >= 0xc0 => Some(TransactionKind::Legacy),
+            Some(&0x01) => Some(TransactionKind::AccessList),
+            Some(&0x02) => Some(TransactionKind::DynamicFee),
+            Some(&byte) if byte < 0x80 => Some(TransactionKind::Other(byte)),
+            Some(_) => None,
+        }
+    }
+}
+
+/// Submission priority of a scheduled transaction (scheduler metadata, not part of the signed transaction).
+///
+/// Higher priority transactions are sent first within a drain
+/// and are preferred when the drain is limited.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize)]
+pub enum Priority {
+    #[serde(rename="low")]
+    Low = 0,
+    #[serde(rename="normal")]
+    Normal = 1,
+    #[serde(rename="high")]
+    High = 2,
+}
+
+impl Default for Priority {
+    fn default() -> Self {
+        Priority::Normal
+    }
+}
+
+impl Priority {
+    /// Decodes the priority from it's byte representation.
+    pub fn from_u8(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(Priority::Low),
+            1 => Some(Priority::Normal),
+            2 => Some(Priority::High),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub struct Transaction {
     sender: Address,
     hash: H256,
     rlp: Vec<u8>,
-}
-
-impl From<SignedTransaction> for Transaction {
-    fn from(transaction: SignedTransaction) -> Self {
-        let rlp = rlp::encode(&transaction).to_vec();
-        Transaction {
-            sender: transaction.sender(),
-            hash: transaction.hash(),
-            rlp,
-        }
-    }
+    max_block: Option<BlockNumber>,
+    depends_on: Option<H256>,
+    priority: Priority,
+    deadline: Option<BlockNumber>,
 }
 
 impl Transaction {
     pub fn new(sender: Address, hash: H256, rlp: Vec<u8>) -> Self {
-        Transaction { sender, hash, rlp }
+        Transaction { sender, hash, rlp, max_block: None, depends_on: None, priority: Priority::default(), deadline: None }
+    }
+
+    /// Sets the last block the transaction is allowed to be included in.
+    /// The transaction is dropped if it's not submitted before that block is mined.
+    pub fn with_deadline(mut self, deadline: BlockNumber) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    /// Returns the deadline block of the transaction (if set).
+    pub fn deadline(&self) -> Option<BlockNumber> {
+        self.deadline
+    }
+
+    /// Sets the submission priority of the transaction.
+    pub fn with_priority(mut self, priority: Priority) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    /// Returns the submission priority of the transaction.
+    pub fn priority(&self) -> Priority {
+        self.priority
+    }
+
+    /// Holds the transaction until given transaction is confirmed (requires confirmation tracking).
+    pub fn with_dependency(mut self, hash: H256) -> Self {
+        self.depends_on = Some(hash);
+        self
+    }
+
+    /// Returns the hash of the transaction that has to be confirmed first (if any).
+    pub fn depends_on(&self) -> Option<&H256> {
+        self.depends_on.as_ref()
+    }
+
+    /// Sets the last block the transaction can be included in (used as a submission precondition, not persisted).
+    pub fn with_max_block(mut self, max_block: BlockNumber) -> Self {
+        self.max_block = Some(max_block);
+        self
+    }
+
+    /// Returns the last block the transaction can be included in (if set).
+    pub fn max_block(&self) -> Option<BlockNumber> {
+        self.max_block
+    }
+
+    /// Creates a transaction from verified transaction and it's original raw bytes.
+    /// The raw bytes are submitted as-is (never re-encoded).
+    pub fn from_verified(transaction: &SignedTransaction, raw: Vec<u8>) -> Self {
+        Transaction::new(transaction.sender(), transaction.hash(), raw)
     }
 
     pub fn sender(&self) -> &Address {
@@ -55,6 +184,45 @@ impl Transaction {
     pub fn rlp(&self) -> &[u8] {
         &self.rlp
     }
+
+    pub fn kind(&self) -> TransactionKind {
+        TransactionKind::classify(&self.rlp).expect("Only valid transactions are constructed; qed")
+    }
+}
+
+/// Trigger a transaction is scheduled for, together with the number it's stored with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScheduleKey {
+    /// Submitted when given block is mined.
+    Block(BlockNumber),
+    /// Submitted at given unix timestamp.
+    Timestamp(u64),
+}
+
+impl ScheduleKey {
+    /// Returns the block number or timestamp the transaction is stored with.
+    pub fn num(&self) -> u64 {
+        match *self {
+            ScheduleKey::Block(num) | ScheduleKey::Timestamp(num) => num,
+        }
+    }
+
+    /// Returns true if the transaction is scheduled for a timestamp.
+    pub fn is_timestamp(&self) -> bool {
+        match *self {
+            ScheduleKey::Block(_) => false,
+            ScheduleKey::Timestamp(_) => true,
+        }
+    }
+}
+
+/// A verified transaction carrying it's scheduling context.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScheduledTransaction {
+    /// The transaction.
+    pub tx: Transaction,
+    /// When the transaction should be submitted.
+    pub when: ScheduleKey,
 }
 
 pub struct TransactionId {
@@ -91,3 +259,77 @@ impl TransactionId {
         bytes.into()
     }
 }
+
+#[cfg(test)]
+pub mod tests {
+    use ethcore::transaction::{Action, SignedTransaction, Transaction as UnsignedTransaction};
+    use rlp::{self, UntrustedRlp};
+    use rustc_hex::FromHex;
+    use super::{effective_submit_block, Address, TargetBlock, Transaction, TransactionKind};
+
+    /// Returns RLP of one of the predefined, signed test transactions.
+    pub fn raw(id: u64) -> Vec<u8> {
+        let tx = match id {
+            0 => "f864808504a817c800825208943535353535353535353535353535353535353535808025a0044852b2a670ade5407e78fb2863c51de9fcb96542a07186fe3aeda6bb8a116da0044852b2a670ade5407e78fb2863c51de9fcb96542a07186fe3aeda6bb8a116d",
+            1 => "f864018504a817c80182a410943535353535353535353535353535353535353535018025a0489efdaa54c0f20c7adf612882df0950f5a951637e0307cdcb4c672f298b8bcaa0489efdaa54c0f20c7adf612882df0950f5a951637e0307cdcb4c672f298b8bc6",
+            2 => "f864028504a817c80282f618943535353535353535353535353535353535353535088025a02d7c5bef027816a800da1736444fb58a807ef4c9603b7848673f7e3a68eb14a5a02d7c5bef027816a800da1736444fb58a807ef4c9603b7848673f7e3a68eb14a5",
+            3 => "f865038504a817c803830148209435353535353535353535353535353535353535351b8025a02a80e1ef1d7842f27f2e6be0972bb708b9a135c38860dbe73c27c3486c34f4e0a02a80e1ef1d7842f27f2e6be0972bb708b9a135c38860dbe73c27c3486c34f4de",
+            _ => panic!("Unknown id."),
+        };
+        FromHex::from_hex(tx).unwrap()
+    }
+
+    /// Returns one of the predefined, signed test transactions.
+    pub fn tx(id: u64) -> Transaction {
+        let transaction = raw(id);
+        let rlp = UntrustedRlp::new(&transaction).as_val().unwrap();
+        Transaction::from_verified(&SignedTransaction::new(rlp).unwrap(), transaction)
+    }
+
+    /// Returns a transaction of given sender and nonce (with fake signature).
+    pub fn signed(sender: Address, nonce: u64) -> Transaction {
+        let transaction = UnsignedTransaction {
+            nonce: nonce.into(),
+            gas_price: 1_000_000_000.into(),
+            gas: 21_000.into(),
+            action: Action::Call(sender),
+            value: 1.into(),
+            data: vec![],
+        }.fake_sign(sender);
+        Transaction::from_verified(&transaction, rlp::encode(&*transaction).into_vec())
+    }
+
+    #[test]
+    fn should_classify_transactions() {
+        assert_eq!(TransactionKind::classify(&raw(0)), Some(TransactionKind::Legacy));
+        assert_eq!(tx(0).kind(), TransactionKind::Legacy);
+        assert_eq!(tx(0).rlp(), &*raw(0));
+
+        assert_eq!(TransactionKind::classify(&[0x01, 0xc0]), Some(TransactionKind::AccessList));
+        assert_eq!(TransactionKind::classify(&[0x02, 0xc0]), Some(TransactionKind::DynamicFee));
+        assert_eq!(TransactionKind::classify(&[0x04, 0xc0]), Some(TransactionKind::Other(4)));
+        assert_eq!(TransactionKind::classify(&[0x80]), None);
+        assert_eq!(TransactionKind::classify(&[]), None);
+    }
+
+    #[test]
+    fn should_only_construct_target_blocks_above_latest() {
+        assert_eq!(TargetBlock::new(101, 100).map(|target| target.number()), Some(101));
+        assert_eq!(TargetBlock::new(100, 100), None);
+        assert_eq!(TargetBlock::new(0, 100), None);
+        assert_eq!(TargetBlock::new(u64::max_value(), u64::max_value()), None);
+    }
+
+    #[test]
+    fn should_compute_effective_submit_block() {
+        assert_eq!(effective_submit_block(100, 0, 0), 100);
+        assert_eq!(effective_submit_block(100, 1, 0), 99);
+        assert_eq!(effective_submit_block(100, 0, 3), 97);
+        assert_eq!(effective_submit_block(100, 1, 2), 97);
+        // Saturates instead of underflowing
+        assert_eq!(effective_submit_block(1, 0, 5), 0);
+        assert_eq!(effective_submit_block(1, 5, 0), 0);
+        assert_eq!(effective_submit_block(1, 5, 5), 0);
+        assert_eq!(effective_submit_block(u64::max_value(), u64::max_value(), u64::max_value()), 0);
+    }
+}
@@ -1,15 +1,132 @@
+use std::collections::HashMap;
+use std::{cmp, fmt};
 use std::sync::Arc;
+use std::time::Instant;
 
-use ethcore::transaction::{Action, SignedTransaction};
-use futures::{future, Future};
-use jsonrpc_core::Error;
+use ethcore::transaction::{Action, SignedTransaction, UnverifiedTransaction};
+use futures::{future, stream, Future, Stream};
+use futures::sync::mpsc;
+use jsonrpc_core::{Error, Value};
+use parking_lot::Mutex;
 use rlp::UntrustedRlp;
 
 use blockchain::Blockchain;
-use database::Database;
+use database::{self, Database};
 use errors;
-use options::Options;
-use types::{Bytes, Transaction};
+use options::{BalanceMargin, GasSchedule, Options};
+use types::{self, Address, Bytes, ScheduleKey, ScheduledTransaction, TargetBlock, Transaction, TransactionKind, H256, U256};
+
+/// A record describing rejected transaction.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rejection {
+    /// Sender of the transaction (if it could be recovered).
+    pub sender: Option<Address>,
+    /// Reason of the rejection.
+    pub reason: String,
+    /// Requested block number or timestamp.
+    pub num: u64,
+}
+
+/// Transaction representation used by the verification (common for all transaction types).
+pub type DecodedTransaction = SignedTransaction;
+
+/// A decoder of raw transactions of a single type.
+pub type Decoder = Box<Fn(&[u8]) -> Result<DecodedTransaction, Error> + Send + Sync>;
+
+/// Decoders of raw transactions keyed by the transaction type byte (EIP-2718).
+/// Legacy transactions are always supported, typed ones are rejected unless a decoder is registered.
+#[derive(Default)]
+pub struct Decoders {
+    typed: HashMap<u8, Decoder>,
+}
+
+impl fmt::Debug for Decoders {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("Decoders")
+            .field("typed", &self.typed.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+impl Decoders {
+    /// Registers a decoder for transactions of given type.
+    pub fn register(&mut self, typ: u8, decoder: Decoder) {
+        self.typed.insert(typ, decoder);
+    }
+
+    /// Decodes and recovers the sender of given raw transaction.
+    pub fn decode(&self, transaction: &[u8]) -> Result<DecodedTransaction, Error> {
+        match TransactionKind::classify(transaction) {
+            Some(TransactionKind::Legacy) | None => {
+                let rlp = UntrustedRlp::new(transaction).as_val().map_err(errors::rlp)?;
+                recover(rlp)
+            },
+            Some(kind) => match self.typed.get(&transaction[0]) {
+                Some(decoder) => decoder(transaction),
+                None => {
+                    debug!("Rejecting. Unsupported transaction type: {:?}", kind);
+                    Err(errors::transaction("Typed transactions are not supported."))
+                },
+            },
+        }
+    }
+}
+
+/// Custom acceptance policy applied at the end of verification.
+/// Transactions are rejected with the returned reason.
+#[derive(Clone)]
+pub struct Policy(Arc<Fn(&Transaction) -> Result<(), String> + Send + Sync>);
+
+impl Policy {
+    /// Creates a policy using given function.
+    pub fn new<F: Fn(&Transaction) -> Result<(), String> + Send + Sync + 'static>(f: F) -> Self {
+        Policy(Arc::new(f))
+    }
+}
+
+impl fmt::Debug for Policy {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "Policy")
+    }
+}
+
+/// A token bucket limiting the rate of incoming requests.
+///
+/// Holds up to `rate` tokens (allowing bursts of up to one second worth of requests)
+/// and is refilled with `rate` tokens per second.
+#[derive(Debug)]
+pub struct TokenBucket {
+    rate: u32,
+    state: Mutex<(f64, Instant)>,
+}
+
+impl TokenBucket {
+    /// Creates a full bucket allowing `rate` requests per second.
+    pub fn new(rate: u32) -> Self {
+        TokenBucket {
+            rate,
+            state: Mutex::new((rate as f64, Instant::now())),
+        }
+    }
+
+    /// Takes a token at `now`. Returns false if there are no tokens left.
+    fn take(&self, now: Instant) -> bool {
+        let mut state = self.state.lock();
+        let (ref mut tokens, ref mut refilled_at) = *state;
+        if now > *refilled_at {
+            let elapsed = now.duration_since(*refilled_at);
+            let elapsed = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1_000_000_000.0;
+            *tokens = (*tokens + elapsed * self.rate as f64).min(self.rate as f64);
+            *refilled_at = now;
+        }
+
+        if *tokens < 1.0 {
+            return false;
+        }
+        *tokens -= 1.0;
+        true
+    }
+}
 
 #[derive(Debug)]
 enum VerifierMode {
@@ -32,6 +149,10 @@ pub struct Verifier {
     database: Arc<Database>,
     options: Options,
     mode: VerifierMode,
+    rejections: Option<mpsc::Sender<Rejection>>,
+    decoders: Arc<Decoders>,
+    rate_limit: Option<Arc<TokenBucket>>,
+    policy: Option<Policy>,
 }
 
 impl Verifier {
@@ -41,7 +162,7 @@ impl Verifier {
         database: Arc<Database>,
         options: Options,
     ) -> Self {
-        Verifier { blockchain, database, options, mode: VerifierMode::Block, }
+        Verifier { blockchain, database, options, mode: VerifierMode::Block, rejections: None, decoders: Default::default(), rate_limit: None, policy: None, }
     }
 
     /// Create new verifier for timestamp-based scheduling.
@@ -50,23 +171,102 @@ impl Verifier {
         database: Arc<Database>,
         options: Options,
     ) -> Self {
-        Verifier { blockchain, database, options, mode: VerifierMode::Timestamp, }
+        Verifier { blockchain, database, options, mode: VerifierMode::Timestamp, rejections: None, decoders: Default::default(), rate_limit: None, policy: None, }
+    }
+
+    /// Report every rejected transaction to given sink.
+    pub fn with_rejections(mut self, rejections: mpsc::Sender<Rejection>) -> Self {
+        self.rejections = Some(rejections);
+        self
+    }
+
+    /// Limit the rate of incoming requests (the bucket can be shared by multiple verifiers).
+    pub fn with_rate_limit(mut self, rate_limit: Arc<TokenBucket>) -> Self {
+        self.rate_limit = Some(rate_limit);
+        self
+    }
+
+    /// Decode transactions using given decoders.
+    pub fn with_decoders(mut self, decoders: Arc<Decoders>) -> Self {
+        self.decoders = decoders;
+        self
+    }
+
+    /// Apply given custom policy to transactions passing all other checks.
+    pub fn with_policy(mut self, policy: Policy) -> Self {
+        self.policy = Some(policy);
+        self
+    }
+
+    /// Returns the options this verifier enforces (chain id, gas price and schedule limits, enabled checks).
+    pub fn config(&self) -> &Options {
+        &self.options
     }
 
     /// Verify and parse given number (block or timestamp) and RLP.
+    /// Returns the transaction together with the key it should be stored with.
     pub fn verify(&self, num: u64, transaction: Bytes)
-        -> Box<Future<Item=(u64, Transaction), Error=Error> + Send>
+        -> Box<Future<Item=ScheduledTransaction, Error=Error> + Send>
     {
-        let result = match self.mode {
-            VerifierMode::Block => self.verify_block_number(num),
-            VerifierMode::Timestamp => self.verify_timestamp(num),
+        if let Some(ref rate_limit) = self.rate_limit {
+            if !rate_limit.take(Instant::now()) {
+                debug!("Rejecting request. Rate limit exceeded.");
+                return Box::new(future::err(errors::retry_later("Too many requests.", 1)));
+            }
+        }
+
+        let mut rejections = match self.rejections {
+            Some(ref rejections) => rejections.clone(),
+            None => return self.verify_internal(num, transaction, &mut None),
         };
-        if let Err(err) = result {
+
+        let raw = transaction.clone();
+        let max_size = self.options.max_transaction_size;
+        let mut sender = None;
+        let result = self.verify_internal(num, transaction, &mut sender);
+        Box::new(result.map_err(move |err| {
+            let rejection = Rejection {
+                // Recover the sender only if the transaction was rejected before it was decoded
+                // (and don't bother decoding oversized payloads).
+                sender: sender.or_else(|| if raw.0.len() > max_size { None } else { recover_sender(&raw) }),
+                reason: reason(&err),
+                num,
+            };
+            if let Err(e) = rejections.try_send(rejection) {
+                warn!("Unable to report rejection: {:?}", e);
+            }
+            err
+        }))
+    }
+
+    /// Verifies only the encoding of given raw transaction (RLP, signature, chain id, gas and gas price),
+    /// without checking the schedule or the state of the sender.
+    pub fn verify_encoding(&self, transaction: Bytes) -> Result<Transaction, Error> {
+        let raw = transaction.into_vec();
+        let tx = verify_transaction(&raw, &self.decoders, &self.options)?;
+        Ok(Transaction::from_verified(&tx, raw))
+    }
+
+    /// Verifies the transaction, setting `recovered` to it's sender as soon as it's decoded.
+    fn verify_internal(&self, num: u64, transaction: Bytes, recovered: &mut Option<Address>)
+        -> Box<Future<Item=ScheduledTransaction, Error=Error> + Send>
+    {
+        if let Err(err) = self.verify_capacity() {
             return Box::new(future::err(err));
         }
 
+        let result = match self.mode {
+            VerifierMode::Block => self.verify_block_number(num).map(|target| target.number()),
+            VerifierMode::Timestamp => self.verify_timestamp(num).map(|_| num),
+        };
+        let num = match result {
+            Ok(num) => num,
+            Err(err) => return Box::new(future::err(err)),
+        };
+
         // Verify some basics about the transaction.
-        let tx = match verify_transaction(transaction, &self.options) {
+        let raw = transaction.into_vec();
+        let tx = match verify_transaction(&raw, &self.decoders, &self.options) {
             Ok(tx) => tx,
             Err(err) => {
                 debug!("Rejecting request: {:?}", err);
@@ -75,18 +275,46 @@ impl Verifier {
         };
 
         let (hash, sender) = (tx.hash(), tx.sender());
+        *recovered = Some(sender);
+        // Check if it's a cancellation of already scheduled transaction
+        let replaced = match find_replaced(&self.database, &tx, self.options.database_fail_open) {
+            Ok(replaced) => replaced,
+            Err(err) => return Box::new(future::err(err)),
+        };
         // Verify transaction sender
-        if !self.database.sender_allowed(&sender) {
+        if replaced.is_none() && !self.database.sender_allowed(&sender) {
             debug!("[{:?}] Rejecting. Sender already has too many transactions: {}", hash, sender);
             return Box::new(future::err(errors::transaction("Sender already has too many transactions.")));
         }
 
+        let key = self.storage_key(num);
+        let when: fn(u64) -> ScheduleKey = match self.mode {
+            VerifierMode::Block => ScheduleKey::Block,
+            VerifierMode::Timestamp => ScheduleKey::Timestamp,
+        };
+
         // Validate balance and nonce
         let blockchain = self.blockchain.clone();
-        let strict_nonce = self.options.strict_nonce;
-        Box::new(self.blockchain.is_certified(sender)
-            .map_err(errors::transaction)
-            .and_then(move |is_certified| {
+        let database = self.database.clone();
+        let nonce_window = self.options.nonce_window();
+        let margin = self.options.balance_margin.clone();
+        let policy = self.policy.clone();
+        let max_gas_price = match worst_case_gas_price(&raw, &tx) {
+            Ok(max_gas_price) => max_gas_price,
+            Err(err) => return Box::new(future::err(err)),
+        };
+        let fees = match dynamic_fees(&raw) {
+            Ok(fees) => fees,
+            Err(err) => return Box::new(future::err(err)),
+        };
+        let certified = self.blockchain.is_certified(sender).map_err(errors::transaction);
+        Box::new(self.verify_base_fee(num, tx.gas_price, hash)
+            .join4(
+                self.verify_gas_price_floor(sender, tx.gas_price, hash).join(self.verify_tip(tx.gas_price, fees, hash)),
+                self.verify_sender_code(sender, hash),
+                certified,
+            )
+            .and_then(move |(_, _, _, is_certified)| {
                 if !is_certified {
                     debug!("[{:?}] Rejecting. Sender not certified: {:?}", hash, sender);
                     return future::Either::A(future::err(errors::transaction(
@@ -97,101 +325,1594 @@ impl Verifier {
                 future::Either::B(blockchain.balance_and_nonce(sender)
                     .map_err(errors::transaction)
                     .and_then(move |(balance, nonce)| {
-                        let required = tx.value.saturating_add(tx.gas.saturating_mul(tx.gas_price));
-                        if  balance < required {
-                            debug!("[{:?}] Rejecting. Insufficient balance: {:?} < {:?}", hash, balance, required);
-                            return Err(errors::transaction(
-                                format!("Insufficient balance (required: {}, got: {})", required, balance)
-                            ));
-                        }
-
-                        if strict_nonce && tx.nonce != nonce {
-                            debug!("[{:?}] Rejecting. Invalid nonce: {:?} != {:?}", hash, tx.nonce, nonce);
-                            return Err(errors::transaction(
-                                format!("Invalid nonce (required: {}, got: {})", nonce, tx.nonce)
-                            ));
-                        } else if !strict_nonce && tx.nonce < nonce {
-                            debug!("[{:?}] Rejecting. Invalid nonce: {:?} < {:?}", hash, tx.nonce, nonce);
-                            return Err(errors::transaction(
-                                format!("Invalid nonce (required at least: {}, got: {})", nonce, tx.nonce)
-                            ));
+                        verify_state_at(&tx, max_gas_price, balance, nonce, &margin, nonce_window)?;
+                        let transaction = Transaction::from_verified(&tx, raw);
+                        if let Some(policy) = policy {
+                            (policy.0)(&transaction).map_err(|reason| {
+                                debug!("[{:?}] Rejecting. Custom policy: {}", hash, reason);
+                                errors::transaction(reason)
+                            })?;
                         }
+                        let key = match replaced {
+                            Some((key, replaced)) => {
+                                if database.remove(&key, &replaced).map_err(errors::unavailable)?.is_none() {
+                                    return Err(errors::transaction("Cancelled transaction is not scheduled anymore."));
+                                }
+                                info!("[{:?}] Cancelled by: {:?}", replaced, hash);
+                                key
+                            },
+                            None => key,
+                        };
 
-                        Ok((num, tx.into()))
+                        Ok(ScheduledTransaction { tx: transaction, when: when(key) })
                     }))
             })
         )
     }
 
-    fn verify_block_number(&self, block_number: u64) -> Result<(), Error> {
-        let latest_block = self.blockchain.latest_block();
-        if block_number > latest_block + self.options.max_schedule_block {
-            debug!("Rejecting request. Block is too high: {} > {}", block_number, latest_block + self.options.max_schedule_block);
-            return Err(errors::block(format!(
-                "Block number is too high: {} > {}",
-                block_number,
-                latest_block + self.options.max_schedule_block,
-            )));
+    /// Moves already scheduled transaction of given `sender` and `nonce` to a new block number or timestamp.
+    /// The new number has to be within the range allowed for scheduling.
+    /// Returns the number and hash the transaction is stored with now.
+    pub fn reschedule(&self, sender: &Address, nonce: U256, num: u64) -> Result<(u64, H256), Error> {
+        match self.mode {
+            VerifierMode::Block => { self.verify_block_number(num)?; },
+            VerifierMode::Timestamp => self.verify_timestamp(num)?,
+        }
+
+        let scheduled = self.database.find(|scheduled| {
+            scheduled.sender() == sender && decode(scheduled).map(|scheduled| scheduled.nonce == nonce).unwrap_or(false)
+        }).map_err(errors::internal)?;
+        let (old_key, scheduled) = match scheduled {
+            Some(scheduled) => scheduled,
+            None => return Err(errors::transaction("Transaction not found.")),
+        };
+
+        let key = self.storage_key(num);
+        let hash = *scheduled.hash();
+        if self.database.remove(&old_key, &hash).map_err(errors::internal)?.is_none() {
+            return Err(errors::transaction("Transaction is not scheduled anymore."));
+        }
+        self.database.insert(key, scheduled).map_err(errors::internal)?;
+        info!("[{:?}] Rescheduled from {} to {}", hash, old_key, key);
+
+        Ok((key, hash))
+    }
+
+    /// Returns the number transaction scheduled for given block number or timestamp is stored with.
+    /// Block-scheduled transactions are stored under the block they should be submitted on.
+    fn storage_key(&self, num: u64) -> u64 {
+        match self.mode {
+            VerifierMode::Block => types::effective_submit_block(num, self.options.submit_earlier, 0),
+            VerifierMode::Timestamp => num,
+        }
+    }
+
+    /// Best-effort check if the gas price is going to cover the base fee at target block.
+    /// The base fee is projected using maximal increase allowed by EIP-1559.
+    fn verify_base_fee(&self, block_number: u64, gas_price: U256, hash: H256)
+        -> Box<Future<Item=(), Error=Error> + Send>
+    {
+        if !self.options.reject_underpriced && !self.options.warn_underpriced {
+            return Box::new(future::ok(()));
         }
 
-        Ok(())
+        let blocks = match self.mode {
+            VerifierMode::Block => block_number.saturating_sub(self.blockchain.latest_block()),
+            VerifierMode::Timestamp => return Box::new(future::ok(())),
+        };
+
+        let reject = self.options.reject_underpriced;
+        Box::new(self.blockchain.base_fee().then(move |base_fee| {
+            let base_fee = match base_fee {
+                Ok(Some(base_fee)) => base_fee,
+                Ok(None) => return Ok(()),
+                Err(err) => {
+                    warn!("[{:?}] Unable to fetch base fee: {:?}", hash, err);
+                    return Ok(());
+                },
+            };
+
+            if covers_base_fee(gas_price, base_fee, blocks) {
+                return Ok(());
+            }
+
+            if reject {
+                debug!("[{:?}] Rejecting. Gas price may not cover base fee in {} blocks: {:?}", hash, blocks, gas_price);
+                Err(errors::transaction("Gas price may not cover the base fee at target block."))
+            } else {
+                warn!("[{:?}] Gas price may not cover base fee in {} blocks: {:?}", hash, blocks, gas_price);
+                Ok(())
+            }
+        }))
+    }
+
+    /// Checks the gas price against a percentile of recent gas prices (if enabled).
+    fn verify_gas_price_floor(&self, sender: Address, gas_price: U256, hash: H256)
+        -> Box<Future<Item=(), Error=Error> + Send>
+    {
+        let n = match self.options.min_gas_price_percentile {
+            Some(_) if self.options.gas_price_exempt.contains(&sender) => return Box::new(future::ok(())),
+            Some(n) => n,
+            None => return Box::new(future::ok(())),
+        };
+
+        let min_gas_price = U256::from(self.options.min_gas_price);
+        Box::new(self.blockchain.gas_price_percentile(n).then(move |floor| {
+            let floor = match floor {
+                Ok(Some(floor)) => floor,
+                Ok(None) => min_gas_price,
+                Err(err) => {
+                    warn!("[{:?}] Unable to fetch gas prices: {:?}", hash, err);
+                    min_gas_price
+                },
+            };
+
+            if gas_price < floor {
+                debug!("[{:?}] Rejecting. Gas price below {}th percentile: {:?} < {:?}", hash, n, gas_price, floor);
+                return Err(errors::transaction(format!("Gas price is too low. Required: {} wei", floor)));
+            }
+            Ok(())
+        }))
+    }
+
+    /// Checks the gas price against the base fee of the latest block increased by `min_tip` (if enabled).
+    fn verify_tip(&self, gas_price: U256, fees: Option<(U256, U256)>, hash: H256)
+        -> Box<Future<Item=(), Error=Error> + Send>
+    {
+        let min_tip = match self.options.min_tip {
+            Some(min_tip) => U256::from(min_tip),
+            None => return Box::new(future::ok(())),
+        };
+
+        Box::new(self.blockchain.base_fee().then(move |base_fee| match base_fee {
+            Ok(Some(base_fee)) => check_tip(gas_price, fees, base_fee, min_tip, hash),
+            Ok(None) => Ok(()),
+            Err(err) => {
+                warn!("[{:?}] Unable to fetch base fee: {:?}", hash, err);
+                Ok(())
+            },
+        }))
+    }
+
+    /// Rejects senders with code deployed (if enabled).
+    fn verify_sender_code(&self, sender: Address, hash: H256)
+        -> Box<Future<Item=(), Error=Error> + Send>
+    {
+        if !self.options.reject_contract_senders {
+            return Box::new(future::ok(()));
+        }
+
+        Box::new(self.blockchain.is_contract(sender).map_err(errors::transaction).and_then(move |is_contract| {
+            if is_contract {
+                debug!("[{:?}] Rejecting. Sender is a contract: {:?}", hash, sender);
+                return Err(errors::transaction("Sender is a contract."));
+            }
+            Ok(())
+        }))
+    }
+
+    /// Asks the client to retry later if the queue is over the soft limit.
+    fn verify_capacity(&self) -> Result<(), Error> {
+        match self.options.soft_queue_limit {
+            Some(limit) if self.database.pending() >= limit => {
+                debug!("Rejecting request. Queue is over the soft limit: {} >= {}", self.database.pending(), limit);
+                Err(errors::retry_later("Too many scheduled transactions.", self.options.retry_after))
+            },
+            _ => Ok(()),
+        }
+    }
+
+    fn verify_block_number(&self, block_number: u64) -> Result<TargetBlock, Error> {
+        check_block_number(block_number, self.blockchain.latest_block(), &self.options)
     }
 
     fn verify_timestamp(&self, time: u64) -> Result<(), Error> {
-        let current_time_seconds = ::time::now_utc().to_timespec().sec as u64;
-        if time > current_time_seconds + self.options.max_schedule_seconds {
-            debug!("Rejecting request. Timestamp is too high: {} > {}", time, current_time_seconds + self.options.max_schedule_seconds);
-            return Err(errors::timestamp(format!(
-                "Timestamp is too high: {} > {}",
-                time,
-                current_time_seconds + self.options.max_schedule_seconds,
+        check_timestamp(time, ::time::now_utc().to_timespec().sec as u64, &self.options)
+    }
+}
+
+/// Checks if given unix timestamp is within the range allowed for scheduling.
+///
+/// The timestamp has to be at least `min_lead_seconds` ahead of `now`.
+fn check_timestamp(time: u64, now: u64, options: &Options) -> Result<(), Error> {
+    if time > now + options.max_schedule_seconds {
+        debug!("Rejecting request. Timestamp is too high: {} > {}", time, now + options.max_schedule_seconds);
+        return Err(errors::timestamp(format!(
+            "Timestamp is too high: {} > {}",
+            time,
+            now + options.max_schedule_seconds,
+        )));
+    }
+
+    if time < now + options.min_lead_seconds {
+        debug!("Rejecting request. Timestamp is too soon: {} < {} + {}", time, now, options.min_lead_seconds);
+        return Err(errors::timestamp(format!(
+            "Timestamp is too soon: {} (required at least {} seconds ahead of {})",
+            time,
+            options.min_lead_seconds,
+            now,
+        )));
+    }
+
+    Ok(())
+}
+
+/// Checks if given block number is within the range allowed for scheduling.
+///
+/// The latest block and up to `min_block_slack` blocks behind it are still accepted
+/// to tolerate small skew between the client and the node. They are scheduled for the next block.
+fn check_block_number(block_number: u64, latest_block: u64, options: &Options) -> Result<TargetBlock, Error> {
+    let block_number = if block_number <= latest_block && block_number.saturating_add(options.min_block_slack) >= latest_block {
+        debug!("Block {} is within slack of the latest block {}. Scheduling for the next one.", block_number, latest_block);
+        latest_block + 1
+    } else {
+        block_number
+    };
+    let target = match TargetBlock::new(block_number, latest_block) {
+        Some(target) => target,
+        None => {
+            debug!("Rejecting request. Block is too low: {} <= {} (slack: {})", block_number, latest_block, options.min_block_slack);
+            return Err(errors::block(format!(
+                "Block number is too low: {} (latest: {})",
+                block_number,
+                latest_block,
             )));
+        },
+    };
+
+    if block_number < latest_block + options.min_lead_blocks {
+        debug!("Rejecting request. Block is too soon: {} < {}", block_number, latest_block + options.min_lead_blocks);
+        return Err(errors::transaction("Target block too soon."));
+    }
+
+    if block_number > latest_block + options.max_schedule_block {
+        debug!("Rejecting request. Block is too high: {} > {}", block_number, latest_block + options.max_schedule_block);
+        return Err(errors::block(format!(
+            "Block number is too high: {} > {}",
+            block_number,
+            latest_block + options.max_schedule_block,
+        )));
+    }
+
+    Ok(target)
+}
+
+/// Checks if current `balance` and `nonce` of the sender allow the transaction to be included.
+/// The balance has to cover the transaction cost together with given `margin`.
+pub fn verify_state(
+    tx: &UnverifiedTransaction,
+    balance: U256,
+    nonce: U256,
+    margin: &BalanceMargin,
+    nonce_window: Option<u64>,
+) -> Result<(), Error> {
+    verify_state_at(tx, tx.gas_price, balance, nonce, margin, nonce_window)
+}
+
+/// Same as `verify_state`, but computes the transaction cost using given `gas_price`
+/// (see `worst_case_gas_price`).
+pub fn verify_state_at(
+    tx: &UnverifiedTransaction,
+    gas_price: U256,
+    balance: U256,
+    nonce: U256,
+    margin: &BalanceMargin,
+    nonce_window: Option<u64>,
+) -> Result<(), Error> {
+    let hash = tx.hash();
+    // Stored transactions are re-verified without `verify_transaction`, so the overflow has to be checked here too.
+    let overflow = || {
+        debug!("[{:?}] Rejecting. Cost overflows: {:?} + {:?} * {:?}", hash, tx.value, tx.gas, gas_price);
+        errors::transaction("Transaction cost overflows.")
+    };
+    let required = margin.required(transaction_cost(tx.value, tx.gas, gas_price).ok_or_else(&overflow)?);
+    // Distinct error for senders that are not funded at all.
+    if balance.is_zero() && !required.is_zero() {
+        debug!("[{:?}] Rejecting. Sender has no balance.", hash);
+        return Err(errors::transaction("Sender has no balance."));
+    }
+    let gas_cost = transaction_cost(0.into(), tx.gas, gas_price).ok_or_else(&overflow)?;
+    if balance < gas_cost {
+        debug!("[{:?}] Rejecting. Gas cost exceeds balance: {:?} < {:?}", hash, balance, gas_cost);
+        return Err(errors::transaction(
+            format!("Gas cost exceeds balance (required: {}, got: {})", gas_cost, balance)
+        ));
+    }
+    if  balance < required {
+        debug!("[{:?}] Rejecting. Insufficient balance: {:?} < {:?}", hash, balance, required);
+        return Err(errors::transaction(
+            format!("Insufficient balance (required: {}, got: {})", required, balance)
+        ));
+    }
+
+    match validate_nonce(nonce, tx.nonce, nonce_window) {
+        Ok(()) => Ok(()),
+        Err(err) => {
+            debug!("[{:?}] Rejecting. Invalid nonce: {:?} (on-chain: {:?})", hash, tx.nonce, nonce);
+            Err(errors::transaction(match (err, nonce_window) {
+                (NonceError::TooLow { min }, _) => format!("Nonce already used on-chain (required at least: {}, got: {})", min, tx.nonce),
+                (NonceError::TooHigh { .. }, Some(0)) => format!("Nonce is too high (required: {}, got: {})", nonce, tx.nonce),
+                (NonceError::TooHigh { max }, _) => format!("Nonce is too far ahead (max: {}, got: {})", max, tx.nonce),
+            }))
+        },
+    }
+}
+
+/// Reason of the nonce being rejected.
+#[derive(Debug, Clone, PartialEq)]
+pub enum NonceError {
+    /// Nonce is below the on-chain nonce (already used).
+    TooLow {
+        /// Minimal acceptable nonce.
+        min: U256,
+    },
+    /// Nonce is more than the window above the on-chain nonce.
+    TooHigh {
+        /// Maximal acceptable nonce.
+        max: U256,
+    },
+}
+
+/// Checks if the nonce of a transaction is between the `on_chain` nonce
+/// and `on_chain + window` (inclusive, unbounded if there is no window).
+pub fn validate_nonce(on_chain: U256, tx_nonce: U256, window: Option<u64>) -> Result<(), NonceError> {
+    if tx_nonce < on_chain {
+        return Err(NonceError::TooLow { min: on_chain });
+    }
+
+    match window {
+        Some(window) if tx_nonce > on_chain.saturating_add(window.into()) => {
+            Err(NonceError::TooHigh { max: on_chain.saturating_add(window.into()) })
+        },
+        _ => Ok(()),
+    }
+}
+
+/// Checks that transactions of given `sender` (the already scheduled ones plus the new one)
+/// form a contiguous sequence of nonces starting at the `on_chain` nonce.
+/// Transactions of other senders are ignored.
+pub fn validate_sequence(sender: &Address, on_chain: U256, txs: &[Transaction]) -> Result<(), Error> {
+    let mut nonces = txs.iter()
+        .filter(|tx| tx.sender() == sender)
+        .map(|tx| decode(tx).map(|tx| tx.nonce))
+        .collect::<Result<Vec<_>, _>>()?;
+    nonces.sort();
+
+    let mut expected = on_chain;
+    for nonce in nonces {
+        if nonce != expected {
+            debug!("[{:?}] Rejecting. Nonce sequence broken: {:?} != {:?}", sender, nonce, expected);
+            return Err(errors::transaction(format!("Nonce sequence is not contiguous (expected: {}, got: {})", expected, nonce)));
         }
+        expected = expected.saturating_add(1.into());
+    }
+
+    Ok(())
+}
 
-        Ok(())
+/// Returns true if given transaction is a zero-value transfer to self.
+fn is_cancellation(tx: &SignedTransaction) -> bool {
+    match tx.action {
+        Action::Call(to) => tx.value.is_zero() && to == tx.sender(),
+        Action::Create => false,
+    }
+}
+
+/// Finds scheduled transaction (number and hash) that is cancelled by given one.
+///
+/// A scheduled transaction can be cancelled by a zero-value transfer to self
+/// with the same nonce and at least 10% higher gas price.
+/// The cancellation replaces the original transaction at it's original schedule.
+fn find_replaced(database: &Database, tx: &SignedTransaction, fail_open: bool) -> Result<Option<(u64, H256)>, Error> {
+    if !is_cancellation(tx) {
+        return Ok(None);
     }
+
+    let sender = tx.sender();
+    let scheduled = database_read(database.find(|scheduled| {
+        scheduled.sender() == &sender && decode(scheduled).map(|scheduled| scheduled.nonce == tx.nonce).unwrap_or(false)
+    }), fail_open)?;
+    let (num, scheduled) = match scheduled {
+        Some(scheduled) => scheduled,
+        None => return Ok(None),
+    };
+
+    let original_gas_price = decode(&scheduled)?.gas_price;
+    let required = original_gas_price.saturating_add(original_gas_price / U256::from(10));
+    if tx.gas_price < required {
+        debug!("[{:?}] Rejecting. Cancellation gas price too low: {:?} < {:?}", tx.hash(), tx.gas_price, required);
+        return Err(errors::transaction(format!("Cancellation gas price is too low. Required: {} wei", required)));
+    }
+
+    Ok(Some((num, *scheduled.hash())))
+}
+
+/// Applies the failure policy to a database read made during verification.
+/// Failed reads are treated as empty if `fail_open` is set, otherwise the request is rejected.
+fn database_read<T: Default>(result: database::Result<T>, fail_open: bool) -> Result<T, Error> {
+    match result {
+        Ok(result) => Ok(result),
+        Err(err) if fail_open => {
+            warn!("Database read failed, assuming nothing is scheduled: {:?}", err);
+            Ok(T::default())
+        },
+        Err(err) => {
+            error!("Database read failed, rejecting request: {:?}", err);
+            Err(errors::unavailable(err))
+        },
+    }
+}
+
+/// Re-verifies balance and nonce of already scheduled transaction against current state of the sender.
+/// The transaction is accepted if the state can't be fetched.
+pub fn reverify(blockchain: &Blockchain, transaction: &Transaction) -> Box<Future<Item=(), Error=Error> + Send> {
+    let tx = match decode(transaction) {
+        Ok(tx) => tx,
+        Err(err) => return Box::new(future::err(err)),
+    };
+
+    let hash = *transaction.hash();
+    Box::new(blockchain.balance_and_nonce(*transaction.sender()).then(move |res| match res {
+        Ok((balance, nonce)) => verify_state(&tx, balance, nonce, &BalanceMargin::default(), None),
+        Err(err) => {
+            warn!("[{:?}] Unable to fetch balance and nonce, not re-verifying: {:?}", hash, err);
+            Ok(())
+        },
+    }))
+}
+
+/// Re-verifies balance and nonce of given transactions (see `reverify`).
+/// At most `concurrency` lookups are in flight at once, results are returned in the order of the transactions.
+pub fn reverify_batch(blockchain: Arc<Blockchain>, transactions: Vec<Transaction>, concurrency: usize)
+    -> Box<Future<Item=Vec<Result<(), Error>>, Error=()> + Send>
+{
+    bounded(transactions, concurrency, move |transaction| reverify(&blockchain, &transaction))
+}
+
+/// Runs `lookup` for every item, keeping at most `concurrency` of them in flight.
+/// Results are returned in the order of the items.
+fn bounded<T, F, R>(items: Vec<T>, concurrency: usize, mut lookup: F)
+    -> Box<Future<Item=Vec<Result<R::Item, R::Error>>, Error=()> + Send> where
+    T: Send + 'static,
+    F: FnMut(T) -> R + Send + 'static,
+    R: Future + Send + 'static,
+    R::Item: Send + 'static,
+    R::Error: Send + 'static,
+{
+    Box::new(stream::iter_ok::<_, ()>(items)
+        .map(move |item| lookup(item).then(|res| Ok::<_, ()>(res)))
+        .buffered(cmp::max(concurrency, 1))
+        .collect())
+}
+
+/// Decodes already scheduled transaction.
+pub fn decode(transaction: &Transaction) -> Result<UnverifiedTransaction, Error> {
+    UntrustedRlp::new(transaction.rlp()).as_val().map_err(errors::rlp)
 }
 
-fn verify_transaction(transaction: Bytes, options: &Options) -> Result<SignedTransaction, Error> {
-    let rlp = UntrustedRlp::new(&transaction.into_vec()).as_val().map_err(errors::rlp)?;
-    let tx = SignedTransaction::new(rlp).map_err(errors::transaction)?;
+/// Returns the reason of given rejection.
+pub fn reason(err: &Error) -> String {
+    match err.data {
+        Some(Value::String(ref reason)) => reason.clone(),
+        _ => err.message.clone(),
+    }
+}
+
+/// Returns the chain id given transaction is signed for
+/// or `None` if the transaction is not replay-protected.
+pub fn chain_id(transaction: &Bytes) -> Result<Option<u64>, Error> {
+    let tx: UnverifiedTransaction = UntrustedRlp::new(&transaction.0).as_val().map_err(errors::rlp)?;
+    Ok(tx.chain_id())
+}
+
+/// Attempts to recover the sender of given raw transaction.
+fn recover_sender(transaction: &Bytes) -> Option<Address> {
+    let tx: UnverifiedTransaction = UntrustedRlp::new(&transaction.0).as_val().ok()?;
+    recover(tx).ok().map(|tx| tx.sender())
+}
+
+/// Recovers the sender of given transaction (runs ecrecover, so it's expensive).
+/// The sender is cached in the returned transaction.
+fn recover(transaction: UnverifiedTransaction) -> Result<SignedTransaction, Error> {
+    SignedTransaction::new(transaction).map_err(errors::transaction)
+}
+
+fn verify_transaction(transaction: &[u8], decoders: &Decoders, options: &Options) -> Result<SignedTransaction, Error> {
+    // Reject oversized payloads before decoding anything.
+    if transaction.len() > options.max_transaction_size {
+        debug!("Rejecting. Raw transaction too large: {} > {}", transaction.len(), options.max_transaction_size);
+        return Err(errors::transaction("Raw transaction too large."));
+    }
+    // Reject malformed recovery id before attempting to recover the sender.
+    if TransactionKind::classify(transaction) == Some(TransactionKind::Legacy) {
+        let unverified: UnverifiedTransaction = UntrustedRlp::new(transaction).as_val().map_err(errors::rlp)?;
+        check_recovery_id(unverified.original_v(), options.chain_id)?;
+    }
+    // Validate fees of type-2 transactions (nodes reject a tip above the max fee)
+    if let Some((max_priority_fee, max_fee)) = dynamic_fees(transaction)? {
+        if max_priority_fee > max_fee {
+            debug!("Rejecting. Priority fee exceeds max fee: {:?} > {:?}", max_priority_fee, max_fee);
+            return Err(errors::transaction("Priority fee exceeds max fee."));
+        }
+    }
+    let tx = decoders.decode(transaction)?;
     tx.verify_basic(true, Some(options.chain_id), false).map_err(errors::transaction)?;
     // Validate basic gas
-    let minimal_gas = minimal_gas(&tx);
+    let minimal_gas = minimal_gas(&tx, &options.gas_schedule);
     if tx.gas < minimal_gas.into() {
         debug!("[{:?}] Rejecting. Gas too low: {:?} < {}", tx.hash(), tx.gas, minimal_gas);
         return Err(errors::transaction(format!("Gas is too low. Required: {}", minimal_gas)));
     }
 
+    // Validate gas required by the target contract (if configured)
+    if let Action::Call(to) = tx.action {
+        if let Some(&min_gas) = options.min_gas_per_target.get(&to) {
+            if tx.gas < min_gas.into() {
+                debug!("[{:?}] Rejecting. Gas too low for {:?}: {:?} < {}", tx.hash(), to, tx.gas, min_gas);
+                return Err(errors::transaction(format!("Gas is too low for the target. Required: {}", min_gas)));
+            }
+        }
+    }
+
     // Validate maximal gas
     if tx.gas > options.max_gas.into() {
         debug!("[{:?}] Rejecting. Gas too high: {:?} > {}", tx.hash(), tx.gas, options.max_gas);
         return Err(errors::transaction(format!("Gas is too high. Maximal: {}", options.max_gas)));
     }
 
-    // Validate gas price
-    if tx.gas_price < options.min_gas_price.into() {
+    verify_recipient(&tx, options)?;
+
+    // Validate gas price (percentile floor is validated against blockchain state)
+    if options.gas_price_exempt.contains(&tx.sender()) {
+        if tx.gas_price.is_zero() {
+            debug!("[{:?}] Rejecting. Zero gas price of exempt sender: {:?}", tx.hash(), tx.sender());
+            return Err(errors::transaction("Gas price must be non-zero."));
+        }
+    } else if options.min_gas_price_percentile.is_none() && tx.gas_price < options.min_gas_price.into() {
         debug!("[{:?}] Rejecting. Gas price too low: {:?} < {}", tx.hash(), tx.gas_price, options.min_gas_price);
         return Err(errors::transaction(format!("Gas price is too low. Required: {} wei", options.min_gas_price)));
     }
 
+    // Validate total cost (at the highest price the transaction may pay)
+    let max_gas_price = worst_case_gas_price(transaction, &tx)?;
+    if transaction_cost(tx.value, tx.gas, max_gas_price).is_none() {
+        debug!("[{:?}] Rejecting. Cost overflows: {:?} + {:?} * {:?}", tx.hash(), tx.value, tx.gas, max_gas_price);
+        return Err(errors::transaction("Transaction cost overflows."));
+    }
+
     Ok(tx)
 }
 
-fn minimal_gas(tx: &SignedTransaction) -> u64 {
-    // TODO [ToDr] take from schedule?
-    const TX_CREATE_GAS: u64 = 53_000;
-    const TX_GAS: u64 = 21_000;
-    const TX_DATA_ZERO_GAS: u64 = 4;
-    const TX_DATA_NON_ZERO_GAS: u64 = 68;
+/// Returns the highest gas price given transaction may pay.
+///
+/// That's `maxFeePerGas` for type-2 transactions (read from the raw payload,
+/// whatever price the decoder reported) and the gas price otherwise.
+fn worst_case_gas_price(raw: &[u8], tx: &UnverifiedTransaction) -> Result<U256, Error> {
+    Ok(match dynamic_fees(raw)? {
+        Some((_, max_fee)) => ::std::cmp::max(max_fee, tx.gas_price),
+        None => tx.gas_price,
+    })
+}
+
+/// Returns `(maxPriorityFeePerGas, maxFeePerGas)` of type-2 transactions (read from the raw payload)
+/// and `None` for other transaction types.
+fn dynamic_fees(raw: &[u8]) -> Result<Option<(U256, U256)>, Error> {
+    if TransactionKind::classify(raw) != Some(TransactionKind::DynamicFee) {
+        return Ok(None);
+    }
 
+    // [chain_id, nonce, max_priority_fee_per_gas, max_fee_per_gas, gas_limit, ...]
+    let rlp = UntrustedRlp::new(&raw[1..]);
+    let max_priority_fee = rlp.val_at(2).map_err(errors::rlp)?;
+    let max_fee = rlp.val_at(3).map_err(errors::rlp)?;
+    Ok(Some((max_priority_fee, max_fee)))
+}
+
+/// Checks if `v` of a legacy transaction is a valid recovery id for given chain id.
+///
+/// Accepts `27`/`28` (no replay protection) and `chain_id * 2 + 35`/`36` (EIP-155).
+fn check_recovery_id(v: u64, chain_id: u64) -> Result<(), Error> {
+    let protected = chain_id.saturating_mul(2).saturating_add(35);
+    if v == 27 || v == 28 || v == protected || v == protected.saturating_add(1) {
+        return Ok(());
+    }
+
+    debug!("Rejecting. Invalid recovery id: {} (chain: {})", v, chain_id);
+    Err(errors::transaction(format!("Invalid signature: recovery id {} is not valid for chain {}.", v, chain_id)))
+}
+
+/// Rejects calls to the zero address (if enabled).
+fn verify_recipient(tx: &SignedTransaction, options: &Options) -> Result<(), Error> {
+    match tx.action {
+        Action::Call(to) if options.reject_zero_address && to.is_zero() => {
+            debug!("[{:?}] Rejecting. Call to zero address.", tx.hash());
+            Err(errors::transaction("Call to zero address."))
+        },
+        Action::Call(to) if options.reject_precompiles && is_precompile(&to) => {
+            debug!("[{:?}] Rejecting. Call to precompile: {:?}", tx.hash(), to);
+            Err(errors::transaction("Call to precompile."))
+        },
+        _ => Ok(()),
+    }
+}
+
+/// Returns true if given address is one of the precompiled contracts (`0x1..0x9`).
+fn is_precompile(address: &Address) -> bool {
+    address[..19].iter().all(|byte| *byte == 0) && address[19] >= 1 && address[19] <= 9
+}
+
+/// Checks if the transaction leaves at least `min_tip` on top of the `base_fee`.
+///
+/// For type-2 transactions (`fees` being `(maxPriorityFeePerGas, maxFeePerGas)`)
+/// the tip is `min(maxPriorityFeePerGas, maxFeePerGas - base_fee)`,
+/// otherwise it's whatever the gas price leaves on top of the base fee.
+fn check_tip(gas_price: U256, fees: Option<(U256, U256)>, base_fee: U256, min_tip: U256, hash: H256) -> Result<(), Error> {
+    match fees {
+        Some((max_priority_fee, max_fee)) => {
+            let tip = ::std::cmp::min(max_priority_fee, max_fee.saturating_sub(base_fee));
+            if tip < min_tip {
+                debug!("[{:?}] Rejecting. Tip too low: min({:?}, {:?} - {:?}) < {:?}", hash, max_priority_fee, max_fee, base_fee, min_tip);
+                return Err(errors::transaction(format!("Priority fee is too low. Required: {} wei (tip on top of base fee)", min_tip)));
+            }
+        },
+        None => {
+            let required = base_fee.saturating_add(min_tip);
+            if gas_price < required {
+                debug!("[{:?}] Rejecting. Tip too low: {:?} < {:?} + {:?}", hash, gas_price, base_fee, min_tip);
+                return Err(errors::transaction(format!("Gas price is too low. Required: {} wei (base fee + tip)", required)));
+            }
+        },
+    }
+    Ok(())
+}
+
+/// Returns true if `max_fee` covers the `base_fee` after given number of `blocks`,
+/// assuming the base fee raises as fast as possible (12.5% per block).
+fn covers_base_fee(max_fee: U256, base_fee: U256, blocks: u64) -> bool {
+    let mut base_fee = base_fee;
+    for _ in 0..blocks {
+        if base_fee > max_fee {
+            return false;
+        }
+        let increase = ::std::cmp::max(base_fee / U256::from(8), U256::from(1));
+        base_fee = base_fee.saturating_add(increase);
+    }
+    base_fee <= max_fee
+}
+
+/// Returns maximal cost of the transaction (`value + gas * gas_price`)
+/// or `None` in case the computation overflows.
+fn transaction_cost(value: U256, gas: U256, gas_price: U256) -> Option<U256> {
+    let (gas_cost, mul_overflow) = gas.overflowing_mul(gas_price);
+    let (cost, add_overflow) = value.overflowing_add(gas_cost);
+    if mul_overflow || add_overflow {
+        None
+    } else {
+        Some(cost)
+    }
+}
+
+fn minimal_gas(tx: &SignedTransaction, schedule: &GasSchedule) -> u64 {
     let is_create = match tx.action {
         Action::Create => true,
         Action::Call(_) => false,
     };
 
 	tx.data.iter().fold(
-        if is_create { TX_CREATE_GAS } else { TX_GAS },
-		|acc, b| acc + if *b == 0 { TX_DATA_ZERO_GAS } else { TX_DATA_NON_ZERO_GAS },
+        if is_create { schedule.tx_create } else { schedule.tx },
+		|acc, b| acc.saturating_add(if *b == 0 { schedule.tx_data_zero } else { schedule.tx_data_non_zero }),
     )
 }
+
+#[cfg(test)]
+pub mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use ethcore::transaction::Transaction as UnsignedTransaction;
+    use futures::Stream;
+    use tempdir::TempDir;
+    use types::tests::{raw, tx};
+    use super::*;
+
+    pub fn options() -> Options {
+        Options {
+            chain_id: 1,
+            max_gas: 1_000_000,
+            max_transaction_size: 128 * 1024,
+            min_gas_price: 1_000_000_000,
+            min_gas_price_percentile: None,
+            min_tip: None,
+            max_schedule_block: 70_000,
+            max_schedule_seconds: 700_000,
+            min_block_slack: 0,
+            min_lead_blocks: 0,
+            submit_earlier: 0,
+            balance_margin: Default::default(),
+            strict_nonce: false,
+            max_nonce_ahead: None,
+            rpc_listen_address: "127.0.0.1:3001".parse().unwrap(),
+            rpc_server_threads: 1,
+            processing_threads: 1,
+            admin_token: None,
+            reject_underpriced: false,
+            warn_underpriced: false,
+            reject_zero_address: false,
+            reject_precompiles: false,
+            reject_contract_senders: false,
+            database_fail_open: false,
+            gas_price_exempt: vec![],
+            min_gas_per_target: Default::default(),
+            gas_schedule: Default::default(),
+            soft_queue_limit: None,
+            retry_after: 30,
+            max_requests_per_second: None,
+            min_lead_seconds: 0,
+        }
+    }
+
+    fn rejection(err: Error) -> Value {
+        err.data.expect("Rejections contain the reason.")
+    }
+
+    #[test]
+    fn should_accept_legacy_transactions() {
+        let verified = verify_transaction(&raw(0), &Default::default(), &options()).unwrap();
+        assert_eq!(verified.sender(), *tx(0).sender());
+    }
+
+    #[test]
+    fn should_submit_original_bytes() {
+        for id in 0..4 {
+            let tx = verify_transaction(&raw(id), &Default::default(), &options()).unwrap();
+            assert_eq!(Transaction::from_verified(&tx, raw(id)).rlp(), &*raw(id));
+        }
+    }
+
+    #[test]
+    fn should_reject_invalid_recovery_id() {
+        // Offset of `v` in the RLP of the test transaction.
+        const V: usize = 35;
+        assert_eq!(raw(0)[V], 37);
+
+        for &(v, chain_id) in &[(29u8, 1u64), (39, 1), (37, 2)] {
+            let mut transaction = raw(0);
+            transaction[V] = v;
+            let err = verify_transaction(&transaction, &Default::default(), &Options { chain_id, ..options() }).unwrap_err();
+            assert_eq!(
+                rejection(err),
+                Value::String(format!("Invalid signature: recovery id {} is not valid for chain {}.", v, chain_id))
+            );
+        }
+
+        assert!(check_recovery_id(27, 1).is_ok());
+        assert!(check_recovery_id(38, 1).is_ok());
+    }
+
+    /// Returns decoders recovering legacy transactions wrapped in type `0x7f`
+    /// together with the number of times the decoder was invoked.
+    fn counting_decoders() -> (Arc<AtomicUsize>, Decoders) {
+        let decoded = Arc::new(AtomicUsize::new(0));
+        let decoded2 = decoded.clone();
+        let mut decoders = Decoders::default();
+        decoders.register(0x7f, Box::new(move |raw: &[u8]| {
+            decoded2.fetch_add(1, Ordering::SeqCst);
+            let rlp = UntrustedRlp::new(&raw[1..]).as_val().map_err(errors::rlp)?;
+            SignedTransaction::new(rlp).map_err(errors::transaction)
+        }));
+        (decoded, decoders)
+    }
+
+    #[test]
+    fn should_reject_oversized_transactions_before_decoding() {
+        let (decoded, decoders) = counting_decoders();
+        let mut transaction = vec![0x7f];
+        transaction.extend(raw(0));
+        transaction.resize(128 * 1024 + 1, 0);
+
+        let err = verify_transaction(&transaction, &decoders, &options()).unwrap_err();
+        assert_eq!(rejection(err), Value::String("Raw transaction too large.".into()));
+        assert_eq!(decoded.load(Ordering::SeqCst), 0);
+
+        // A limit below the size of a valid transaction rejects it as well.
+        let err = verify_transaction(&raw(0), &Default::default(), &Options { max_transaction_size: 10, ..options() }).unwrap_err();
+        assert_eq!(rejection(err), Value::String("Raw transaction too large.".into()));
+    }
+
+    #[test]
+    fn should_exempt_privileged_senders_from_gas_price_floor() {
+        let options = Options {
+            min_gas_price: 30_000_000_000,
+            gas_price_exempt: vec![*tx(0).sender()],
+            ..options()
+        };
+
+        assert!(verify_transaction(&raw(0), &Default::default(), &options).is_ok());
+        assert_eq!(
+            rejection(verify_transaction(&raw(1), &Default::default(), &options).unwrap_err()),
+            Value::String("Gas price is too low. Required: 30000000000 wei".into())
+        );
+    }
+
+    #[test]
+    fn should_require_minimal_gas_of_target() {
+        let target: Address = "3535353535353535353535353535353535353535".parse().unwrap();
+        let mut options = options();
+        options.min_gas_per_target.insert(target, 30_000);
+
+        assert_eq!(
+            rejection(verify_transaction(&raw(0), &Default::default(), &options).unwrap_err()),
+            Value::String("Gas is too low for the target. Required: 30000".into())
+        );
+        // 42k gas
+        assert!(verify_transaction(&raw(1), &Default::default(), &options).is_ok());
+        // Other targets are not affected.
+        options.min_gas_per_target = vec![(Address::from(1), 30_000)].into_iter().collect();
+        assert!(verify_transaction(&raw(0), &Default::default(), &options).is_ok());
+    }
+
+    #[test]
+    fn should_compute_minimal_gas_with_custom_schedule() {
+        let transaction = |action| UnsignedTransaction {
+            nonce: 0.into(),
+            gas_price: 1_000_000_000.into(),
+            gas: 100_000.into(),
+            action,
+            value: 0.into(),
+            data: vec![0, 1, 2],
+        }.fake_sign(Address::from(1));
+        let call = transaction(Action::Call(Address::from(2)));
+        let create = transaction(Action::Create);
+        let schedule = GasSchedule { tx: 1_000, tx_create: 2_000, tx_data_zero: 1, tx_data_non_zero: 10 };
+
+        assert_eq!(minimal_gas(&call, &GasSchedule::default()), 21_000 + 4 + 2 * 68);
+        assert_eq!(minimal_gas(&call, &schedule), 1_021);
+        assert_eq!(minimal_gas(&create, &schedule), 2_021);
+    }
+
+    #[test]
+    fn should_reject_typed_transactions() {
+        for typ in &[0x01u8, 0x03] {
+            let err = verify_transaction(&[*typ, 0xc0], &Default::default(), &options()).unwrap_err();
+            assert_eq!(rejection(err), Value::String("Typed transactions are not supported.".into()));
+        }
+        let gwei = U256::from(1_000_000_000);
+        let (typed, _) = type_2(gwei, gwei);
+        let err = verify_transaction(&typed, &Default::default(), &options()).unwrap_err();
+        assert_eq!(rejection(err), Value::String("Typed transactions are not supported.".into()));
+    }
+
+    /// Returns a type-2 payload with given fees (other fields taken from `tx(0)`)
+    /// and decoders turning it back into `tx(0)`.
+    fn type_2(max_priority_fee: U256, max_fee: U256) -> (Vec<u8>, Decoders) {
+        use rlp::RlpStream;
+
+        let tx = decode(&tx(0)).unwrap();
+        let mut stream = RlpStream::new_list(12);
+        stream
+            .append(&1u64).append(&tx.nonce).append(&max_priority_fee).append(&max_fee)
+            .append(&tx.gas).append(&Address::from(1)).append(&tx.value).append(&Vec::<u8>::new());
+        stream.begin_list(0);
+        stream.append(&0u8).append(&0u8).append(&0u8);
+        let mut typed = vec![0x02];
+        typed.extend_from_slice(stream.as_raw());
+
+        let mut decoders = Decoders::default();
+        decoders.register(0x02, Box::new(|_: &[u8]| {
+            let rlp = UntrustedRlp::new(&raw(0)).as_val().map_err(errors::rlp)?;
+            SignedTransaction::new(rlp).map_err(errors::transaction)
+        }));
+        (typed, decoders)
+    }
+
+    #[test]
+    fn should_reject_priority_fee_above_max_fee() {
+        let gwei = U256::from(1_000_000_000);
+
+        // Checked before decoding, so it doesn't depend on the registered decoders.
+        let (typed, _) = type_2(gwei * U256::from(3), gwei * U256::from(2));
+        let err = verify_transaction(&typed, &Default::default(), &options()).unwrap_err();
+        assert_eq!(rejection(err), Value::String("Priority fee exceeds max fee.".into()));
+
+        let (typed, decoders) = type_2(gwei * U256::from(2), gwei * U256::from(2));
+        assert_eq!(verify_transaction(&typed, &decoders, &options()).unwrap().sender(), *tx(0).sender());
+    }
+
+    #[test]
+    fn should_decode_typed_transactions_with_registered_decoder() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        let invoked = Arc::new(AtomicBool::new(false));
+        let invoked2 = invoked.clone();
+        let mut decoders = Decoders::default();
+        decoders.register(0x7f, Box::new(move |raw: &[u8]| {
+            invoked2.store(true, Ordering::SeqCst);
+            let rlp = UntrustedRlp::new(&raw[1..]).as_val().map_err(errors::rlp)?;
+            SignedTransaction::new(rlp).map_err(errors::transaction)
+        }));
+
+        let mut typed = vec![0x7f];
+        typed.extend(raw(0));
+        assert_eq!(decoders.decode(&typed).unwrap().sender(), *tx(0).sender());
+        assert!(invoked.load(Ordering::SeqCst));
+        // Other types are still rejected.
+        assert!(decoders.decode(&[0x7e, 0xc0]).is_err());
+    }
+
+    #[test]
+    fn should_expose_effective_config() {
+        let dir = TempDir::new("verifier").unwrap();
+        let blockchain = Arc::new(Blockchain::new("http://127.0.0.1:8545", None).unwrap());
+        let database = Arc::new(Database::open(dir.path(), 1).unwrap());
+        let options = Options {
+            chain_id: 42,
+            min_gas_price: 5_000_000_000,
+            max_schedule_block: 100,
+            reject_precompiles: true,
+            ..options()
+        };
+        let verifier = Verifier::new_timestamp(blockchain, database, options.clone());
+
+        assert_eq!(verifier.config(), &options);
+        assert_eq!(verifier.config().chain_id, 42);
+        assert!(verifier.config().reject_precompiles);
+    }
+
+    #[test]
+    fn should_verify_encoding_only() {
+        let dir = TempDir::new("verifier").unwrap();
+        let blockchain = Arc::new(Blockchain::new("http://127.0.0.1:8545", None).unwrap());
+        let database = Arc::new(Database::open(dir.path(), 1).unwrap());
+        let verifier = Verifier::new_block(blockchain, database, options());
+
+        assert_eq!(verifier.verify_encoding(Bytes::new(raw(0))).unwrap(), tx(0));
+
+        let mut truncated = raw(0);
+        truncated.pop();
+        let err = verifier.verify_encoding(Bytes::new(truncated)).unwrap_err();
+        assert_eq!(err.message, "Invalid RLP.");
+    }
+
+    #[test]
+    fn should_ask_to_retry_later_over_soft_limit() {
+        let dir = TempDir::new("verifier").unwrap();
+        let blockchain = Arc::new(Blockchain::new("http://127.0.0.1:8545", None).unwrap());
+        let database = Arc::new(Database::open(dir.path(), 1).unwrap());
+        let verifier = Verifier::new_block(blockchain, database.clone(), Options {
+            soft_queue_limit: Some(2),
+            ..options()
+        });
+
+        database.insert(5, tx(0)).unwrap();
+        assert!(verifier.verify_capacity().is_ok());
+
+        database.insert(5, tx(1)).unwrap();
+        let err = verifier.verify(5, Bytes::new(raw(2))).wait().unwrap_err();
+        assert_eq!(err, errors::retry_later("Too many scheduled transactions.", 30));
+        assert_eq!(err.data.unwrap()["retryAfter"], Value::from(30));
+    }
+
+    #[test]
+    fn should_limit_rate_of_requests() {
+        let dir = TempDir::new("verifier").unwrap();
+        let blockchain = Arc::new(Blockchain::new("http://127.0.0.1:8545", None).unwrap());
+        let database = Arc::new(Database::open(dir.path(), 1).unwrap());
+        let bucket = Arc::new(TokenBucket::new(3));
+        let verifier = Verifier::new_block(blockchain, database, options()).with_rate_limit(bucket.clone());
+
+        // Rejected for other reasons, but still counted.
+        for _ in 0..3 {
+            let err = verifier.verify(1_000_000, Bytes::new(raw(0))).wait().unwrap_err();
+            assert_eq!(err.message, "Invalid block number.");
+        }
+        let err = verifier.verify(1_000_000, Bytes::new(raw(0))).wait().unwrap_err();
+        assert_eq!(err, errors::retry_later("Too many requests.", 1));
+
+        // Refilled after a second.
+        let now = Instant::now() + ::std::time::Duration::from_secs(1);
+        assert!(bucket.take(now));
+        assert!(bucket.take(now));
+        assert!(bucket.take(now));
+        assert!(!bucket.take(now));
+    }
+
+    #[test]
+    fn should_apply_custom_policy() {
+        let dir = TempDir::new("verifier").unwrap();
+        let blockchain = Arc::new(Blockchain::new("http://127.0.0.1:8545", None).unwrap());
+        let database = Arc::new(Database::open(dir.path(), 1).unwrap());
+        for id in 0..2 {
+            blockchain.mock_balance_and_nonce(*tx(id).sender(), U256::from(10).pow(18.into()), 0.into());
+        }
+        let verifier = Verifier::new_timestamp(blockchain, database, options())
+            .with_policy(Policy::new(|transaction| {
+                let value = decode(transaction).map_err(|err| reason(&err))?.value;
+                if !value.is_zero() {
+                    return Err(format!("Value is too high: {}", value));
+                }
+                Ok(())
+            }));
+        let time = ::time::now_utc().to_timespec().sec as u64 + 100;
+
+        assert_eq!(
+            verifier.verify(time, Bytes::new(raw(0))).wait().unwrap(),
+            ScheduledTransaction { tx: tx(0), when: ScheduleKey::Timestamp(time) }
+        );
+        assert_eq!(
+            rejection(verifier.verify(time, Bytes::new(raw(1))).wait().unwrap_err()),
+            Value::String("Value is too high: 1".into())
+        );
+    }
+
+    #[test]
+    fn should_not_query_certifier_for_warmed_up_senders() {
+        use blockchain::tests::MockNode;
+
+        // Everyone is certified.
+        let node = MockNode::start(|method, _| match method {
+            "eth_call" => Some(Value::String(format!("0x{:064x}", 1))),
+            _ => None,
+        });
+        let dir = TempDir::new("verifier").unwrap();
+        let blockchain = Arc::new(Blockchain::new(node.url(), Some(Address::from(3))).unwrap()
+            .with_certification_warmup(vec![*tx(0).sender()]));
+        blockchain.warm_up_certification().wait().unwrap();
+        assert_eq!(node.calls("eth_call"), 1);
+
+        // Warmed up status survives new blocks.
+        blockchain.mock_latest_block(1);
+        blockchain.mock_balance_and_nonce(*tx(0).sender(), U256::from(10).pow(18.into()), 0.into());
+        blockchain.mock_balance_and_nonce(*tx(1).sender(), U256::from(10).pow(18.into()), 0.into());
+        let database = Arc::new(Database::open(dir.path(), 1).unwrap());
+        let verifier = Verifier::new_block(blockchain, database, options());
+
+        verifier.verify(5, Bytes::new(raw(0))).wait().unwrap();
+        assert_eq!(node.calls("eth_call"), 1);
+
+        // Other senders are still queried.
+        verifier.verify(5, Bytes::new(raw(1))).wait().unwrap();
+        assert_eq!(node.calls("eth_call"), 2);
+    }
+
+    #[test]
+    fn should_carry_block_schedule_key() {
+        let dir = TempDir::new("verifier").unwrap();
+        let blockchain = Arc::new(Blockchain::new("http://127.0.0.1:8545", None).unwrap());
+        let database = Arc::new(Database::open(dir.path(), 1).unwrap());
+        blockchain.mock_balance_and_nonce(*tx(0).sender(), U256::from(10).pow(18.into()), 0.into());
+        let verifier = Verifier::new_block(blockchain, database, Options { submit_earlier: 1, ..options() });
+
+        let scheduled = verifier.verify(5, Bytes::new(raw(0))).wait().unwrap();
+
+        assert_eq!(scheduled.when, ScheduleKey::Block(4));
+        assert!(!scheduled.when.is_timestamp());
+        assert_eq!(scheduled.tx, tx(0));
+    }
+
+    #[test]
+    fn should_retry_state_queries_during_verification() {
+        use std::time::Duration;
+        use blockchain::RetryPolicy;
+        use blockchain::tests::MockNode;
+
+        let dir = TempDir::new("verifier").unwrap();
+        // Every query fails.
+        let node = MockNode::start(|_, _| None);
+        let blockchain = Arc::new(Blockchain::new(node.url(), None).unwrap()
+            .with_retry(RetryPolicy { attempts: 3, backoff: Duration::from_millis(1) }));
+        let database = Arc::new(Database::open(dir.path(), 1).unwrap());
+        let verifier = Verifier::new_block(blockchain, database, options());
+
+        verifier.verify(5, Bytes::new(raw(0))).wait().unwrap_err();
+        assert_eq!(node.calls("eth_getBalance"), 3);
+    }
+
+    #[test]
+    fn should_recover_sender_once() {
+        let dir = TempDir::new("verifier").unwrap();
+        let blockchain = Arc::new(Blockchain::new("http://127.0.0.1:8545", None).unwrap());
+        let database = Arc::new(Database::open(dir.path(), 1).unwrap());
+        let (sink, rejections) = mpsc::channel(16);
+        let (decoded, decoders) = counting_decoders();
+        let verifier = Verifier::new_block(blockchain, database, Options {
+            max_gas: 20_000,
+            ..options()
+        }).with_decoders(Arc::new(decoders)).with_rejections(sink);
+
+        // Gas is too high (checked after the sender is recovered).
+        // Only the registered decoder can recover the sender of the typed payload,
+        // so the reported sender has to be the one recovered during verification.
+        let mut typed = vec![0x7f];
+        typed.extend(raw(0));
+        verifier.verify(5, Bytes::new(typed)).wait().unwrap_err();
+        assert_eq!(decoded.load(Ordering::SeqCst), 1);
+
+        drop(verifier);
+        let rejections = rejections.collect().wait().unwrap();
+        assert_eq!(rejections[0].sender, Some(*tx(0).sender()));
+    }
+
+    #[test]
+    fn should_read_chain_id() {
+        assert_eq!(chain_id(&Bytes::new(raw(0))).unwrap(), Some(1));
+        assert!(chain_id(&Bytes::new(vec![0x80])).is_err());
+    }
+
+    #[test]
+    fn should_tolerate_blocks_within_slack() {
+        let mut options = options();
+        assert_eq!(check_block_number(101, 100, &options).unwrap().number(), 101);
+        // Scheduled for the next block.
+        assert_eq!(check_block_number(100, 100, &options).unwrap().number(), 101);
+        assert_eq!(
+            rejection(check_block_number(99, 100, &options).unwrap_err()),
+            Value::String("Block number is too low: 99 (latest: 100)".into())
+        );
+
+        options.min_block_slack = 2;
+        assert_eq!(check_block_number(100, 100, &options).unwrap().number(), 101);
+        assert_eq!(check_block_number(99, 100, &options).unwrap().number(), 101);
+        assert_eq!(check_block_number(98, 100, &options).unwrap().number(), 101);
+        assert!(check_block_number(97, 100, &options).is_err());
+        assert!(check_block_number(70_100, 100, &options).is_ok());
+        assert!(check_block_number(70_101, 100, &options).is_err());
+    }
+
+    #[test]
+    fn should_reject_blocks_too_soon() {
+        let mut options = options();
+        options.min_lead_blocks = 3;
+
+        assert_eq!(
+            rejection(check_block_number(102, 100, &options).unwrap_err()),
+            Value::String("Target block too soon.".into())
+        );
+        assert!(check_block_number(103, 100, &options).is_ok());
+    }
+
+    #[test]
+    fn should_require_lead_for_timestamps() {
+        let options = Options { min_lead_seconds: 10, ..options() };
+        assert!(check_timestamp(1_010, 1_000, &options).is_ok());
+        assert_eq!(
+            rejection(check_timestamp(1_009, 1_000, &options).unwrap_err()),
+            Value::String("Timestamp is too soon: 1009 (required at least 10 seconds ahead of 1000)".into())
+        );
+        assert!(check_timestamp(1_000 + 700_000, 1_000, &options).is_ok());
+        assert!(check_timestamp(1_001 + 700_000, 1_000, &options).is_err());
+    }
+
+    #[test]
+    fn should_store_transaction_under_given_timestamp() {
+        let dir = TempDir::new("verifier").unwrap();
+        let blockchain = Arc::new(Blockchain::new("http://127.0.0.1:8545", None).unwrap());
+        let database = Arc::new(Database::open(dir.path(), 1).unwrap());
+        database.insert(5, tx(1)).unwrap();
+        let verifier = Verifier::new_timestamp(blockchain, database.clone(), Options { min_lead_seconds: 60, ..options() });
+        let now = ::time::now_utc().to_timespec().sec as u64;
+
+        assert!(verifier.reschedule(tx(1).sender(), 1.into(), now + 30).is_err());
+        assert_eq!(verifier.reschedule(tx(1).sender(), 1.into(), now + 120).unwrap(), (now + 120, *tx(1).hash()));
+        assert_eq!(database.find(|_| true).unwrap(), Some((now + 120, tx(1))));
+    }
+
+    #[test]
+    fn should_verify_state() {
+        let tx = decode(&tx(1)).unwrap();
+        let cost = transaction_cost(tx.value, tx.gas, tx.gas_price).unwrap();
+        let margin = BalanceMargin::default();
+
+        assert!(verify_state(&tx, cost, 1.into(), &margin, Some(0)).is_ok());
+        assert!(verify_state(&tx, cost, 0.into(), &margin, None).is_ok());
+        assert_eq!(
+            rejection(verify_state(&tx, cost - U256::from(1), 1.into(), &margin, None).unwrap_err()),
+            Value::String(format!("Insufficient balance (required: {}, got: {})", cost, cost - U256::from(1)))
+        );
+        assert_eq!(
+            rejection(verify_state(&tx, cost, 2.into(), &margin, None).unwrap_err()),
+            Value::String("Nonce already used on-chain (required at least: 2, got: 1)".into())
+        );
+        assert!(verify_state(&tx, cost, 0.into(), &margin, Some(0)).is_err());
+    }
+
+    #[test]
+    fn should_validate_nonce_sequence() {
+        use types::tests::signed;
+
+        let (sender, other) = (Address::from(1), Address::from(2));
+        let scheduled = vec![signed(sender, 6), signed(other, 0), signed(sender, 5)];
+
+        let mut extended = scheduled.clone();
+        extended.push(signed(sender, 7));
+        assert!(validate_sequence(&sender, 5.into(), &extended).is_ok());
+
+        let mut gap = scheduled.clone();
+        gap.push(signed(sender, 8));
+        assert_eq!(
+            rejection(validate_sequence(&sender, 5.into(), &gap).unwrap_err()),
+            Value::String("Nonce sequence is not contiguous (expected: 7, got: 8)".into())
+        );
+        assert_eq!(
+            rejection(validate_sequence(&sender, 4.into(), &extended).unwrap_err()),
+            Value::String("Nonce sequence is not contiguous (expected: 4, got: 5)".into())
+        );
+    }
+
+    #[test]
+    fn should_validate_nonce_window() {
+        // In range
+        assert_eq!(validate_nonce(5.into(), 5.into(), None), Ok(()));
+        assert_eq!(validate_nonce(5.into(), 1_000.into(), None), Ok(()));
+        assert_eq!(validate_nonce(5.into(), 7.into(), Some(3)), Ok(()));
+        // At both bounds
+        assert_eq!(validate_nonce(5.into(), 5.into(), Some(3)), Ok(()));
+        assert_eq!(validate_nonce(5.into(), 8.into(), Some(3)), Ok(()));
+        assert_eq!(validate_nonce(5.into(), 4.into(), Some(3)), Err(NonceError::TooLow { min: 5.into() }));
+        assert_eq!(validate_nonce(5.into(), 9.into(), Some(3)), Err(NonceError::TooHigh { max: 8.into() }));
+        // Strict
+        assert_eq!(validate_nonce(5.into(), 6.into(), Some(0)), Err(NonceError::TooHigh { max: 5.into() }));
+
+        let tx = decode(&tx(1)).unwrap();
+        let cost = transaction_cost(tx.value, tx.gas, tx.gas_price).unwrap();
+        assert_eq!(
+            rejection(verify_state(&tx, cost, 0.into(), &Default::default(), Some(0)).unwrap_err()),
+            Value::String("Nonce is too high (required: 0, got: 1)".into())
+        );
+        assert!(verify_state(&tx, cost, 0.into(), &Default::default(), Some(1)).is_ok());
+        assert_eq!(
+            rejection(verify_state(&tx, cost, 2.into(), &Default::default(), Some(1)).unwrap_err()),
+            Value::String("Nonce already used on-chain (required at least: 2, got: 1)".into())
+        );
+    }
+
+    #[test]
+    fn should_distinguish_used_nonces_from_nonces_ahead() {
+        let tx = decode(&tx(1)).unwrap();
+        let cost = transaction_cost(tx.value, tx.gas, tx.gas_price).unwrap();
+
+        // Strict
+        assert_eq!(
+            rejection(verify_state(&tx, cost, 2.into(), &Default::default(), Some(0)).unwrap_err()),
+            Value::String("Nonce already used on-chain (required at least: 2, got: 1)".into())
+        );
+        assert_eq!(
+            rejection(verify_state(&tx, cost, 0.into(), &Default::default(), Some(0)).unwrap_err()),
+            Value::String("Nonce is too high (required: 0, got: 1)".into())
+        );
+        // Unbounded
+        assert_eq!(
+            rejection(verify_state(&tx, cost, 2.into(), &Default::default(), None).unwrap_err()),
+            Value::String("Nonce already used on-chain (required at least: 2, got: 1)".into())
+        );
+        assert!(verify_state(&tx, cost, 0.into(), &Default::default(), None).is_ok());
+    }
+
+    #[test]
+    fn should_require_balance_margin() {
+        let tx = decode(&tx(1)).unwrap();
+        let cost = transaction_cost(tx.value, tx.gas, tx.gas_price).unwrap();
+        let margin = BalanceMargin { wei: 0, percent: 10 };
+        let required = cost + cost / U256::from(10);
+
+        assert_eq!(margin.required(cost), required);
+        assert_eq!(
+            rejection(verify_state(&tx, cost, 1.into(), &margin, None).unwrap_err()),
+            Value::String(format!("Insufficient balance (required: {}, got: {})", required, cost))
+        );
+        assert!(verify_state(&tx, required, 1.into(), &margin, None).is_ok());
+
+        let margin = BalanceMargin { wei: 5, percent: 0 };
+        assert_eq!(margin.required(cost), cost + U256::from(5));
+    }
+
+    #[test]
+    fn should_replace_transaction_cancelled_by_self_transfer() {
+        let dir = TempDir::new("verifier").unwrap();
+        let database = Database::open(dir.path(), 1).unwrap();
+        database.insert(5, tx(0)).unwrap();
+        database.insert(7, tx(1)).unwrap();
+        let original = decode(&tx(0)).unwrap();
+        let sender = *tx(0).sender();
+        let cancellation = |gas_price: U256, to: Address| UnsignedTransaction {
+            nonce: original.nonce,
+            gas_price,
+            gas: 21_000.into(),
+            action: Action::Call(to),
+            value: 0.into(),
+            data: vec![],
+        }.fake_sign(sender);
+
+        let higher = original.gas_price + original.gas_price / U256::from(10);
+        assert_eq!(find_replaced(&database, &cancellation(higher, sender), false).unwrap(), Some((5, *tx(0).hash())));
+        assert_eq!(
+            rejection(find_replaced(&database, &cancellation(higher - U256::from(1), sender), false).unwrap_err()),
+            Value::String(format!("Cancellation gas price is too low. Required: {} wei", higher))
+        );
+        // Not a transfer to self.
+        assert_eq!(find_replaced(&database, &cancellation(higher, *tx(1).sender()), false).unwrap(), None);
+    }
+
+    #[test]
+    fn should_reschedule_transaction() {
+        let dir = TempDir::new("verifier").unwrap();
+        let blockchain = Arc::new(Blockchain::new("http://127.0.0.1:8545", None).unwrap());
+        let database = Arc::new(Database::open(dir.path(), 1).unwrap());
+        database.insert(5, tx(1)).unwrap();
+        let verifier = Verifier::new_block(blockchain, database.clone(), options());
+
+        assert_eq!(verifier.reschedule(tx(1).sender(), 1.into(), 10).unwrap(), (10, *tx(1).hash()));
+        assert!(!database.has(&5));
+        assert_eq!(database.find(|_| true).unwrap(), Some((10, tx(1))));
+    }
+
+    #[test]
+    fn should_not_reschedule_out_of_range() {
+        let dir = TempDir::new("verifier").unwrap();
+        let blockchain = Arc::new(Blockchain::new("http://127.0.0.1:8545", None).unwrap());
+        let database = Arc::new(Database::open(dir.path(), 1).unwrap());
+        database.insert(5, tx(1)).unwrap();
+        let verifier = Verifier::new_block(blockchain, database.clone(), options());
+
+        // Latest block is 0
+        assert_eq!(
+            rejection(verifier.reschedule(tx(1).sender(), 1.into(), 1_000_000).unwrap_err()),
+            Value::String("Block number is too high: 1000000 > 70000".into())
+        );
+        assert_eq!(
+            rejection(verifier.reschedule(tx(1).sender(), 2.into(), 10).unwrap_err()),
+            Value::String("Transaction not found.".into())
+        );
+        assert_eq!(database.find(|_| true).unwrap(), Some((5, tx(1))));
+    }
+
+    #[test]
+    fn should_reject_calls_to_zero_address_if_enabled() {
+        let call = |to: Address| UnsignedTransaction {
+            nonce: 0.into(),
+            gas_price: 1_000_000_000.into(),
+            gas: 21_000.into(),
+            action: Action::Call(to),
+            value: 0.into(),
+            data: vec![],
+        }.fake_sign(*tx(0).sender());
+        let mut options = options();
+
+        assert!(verify_recipient(&call(Address::zero()), &options).is_ok());
+
+        options.reject_zero_address = true;
+        assert_eq!(
+            rejection(verify_recipient(&call(Address::zero()), &options).unwrap_err()),
+            Value::String("Call to zero address.".into())
+        );
+        assert!(verify_recipient(&call(*tx(0).sender()), &options).is_ok());
+    }
+
+    #[test]
+    fn should_reject_calls_to_precompiles_if_enabled() {
+        let call = |to: Address| UnsignedTransaction {
+            nonce: 0.into(),
+            gas_price: 1_000_000_000.into(),
+            gas: 21_000.into(),
+            action: Action::Call(to),
+            value: 0.into(),
+            data: vec![],
+        }.fake_sign(*tx(0).sender());
+        let mut options = options();
+
+        assert!(verify_recipient(&call(Address::from(1)), &options).is_ok());
+
+        options.reject_precompiles = true;
+        assert_eq!(
+            rejection(verify_recipient(&call(Address::from(1)), &options).unwrap_err()),
+            Value::String("Call to precompile.".into())
+        );
+        assert!(verify_recipient(&call(Address::from(10)), &options).is_ok());
+        assert!(verify_recipient(&call(*tx(0).sender()), &options).is_ok());
+    }
+
+    #[test]
+    fn should_apply_database_failure_policy() {
+        let dir = TempDir::new("db").unwrap();
+        let database = Database::open(dir.path(), 1).unwrap();
+        // Make the database fail.
+        ::std::fs::remove_dir_all(dir.path()).unwrap();
+        let failure = || database.insert(5, tx(0)).map(|_| None::<(u64, Transaction)>);
+
+        // Fails closed by default.
+        let err = database_read(failure(), false).unwrap_err();
+        assert_eq!(err.message, "Scheduler unavailable.");
+        // Fails open if configured.
+        assert_eq!(database_read(failure(), true).unwrap(), None);
+        assert_eq!(database_read(Ok(Some((5, tx(0)))), false).unwrap(), Some((5, tx(0))));
+    }
+
+    #[test]
+    fn should_reject_contract_senders_if_enabled() {
+        let dir = TempDir::new("verifier").unwrap();
+        let blockchain = Arc::new(Blockchain::new("http://127.0.0.1:8545", None).unwrap());
+        let database = Arc::new(Database::open(dir.path(), 1).unwrap());
+        let (contract, account) = (*tx(0).sender(), *tx(1).sender());
+        blockchain.mock_code(contract, true);
+        blockchain.mock_code(account, false);
+        let hash = *tx(0).hash();
+
+        let verifier = Verifier::new_block(blockchain.clone(), database.clone(), options());
+        assert!(verifier.verify_sender_code(contract, hash).wait().is_ok());
+
+        let verifier = Verifier::new_block(blockchain, database, Options { reject_contract_senders: true, ..options() });
+        assert_eq!(
+            rejection(verifier.verify_sender_code(contract, hash).wait().unwrap_err()),
+            Value::String("Sender is a contract.".into())
+        );
+        assert!(verifier.verify_sender_code(account, hash).wait().is_ok());
+    }
+
+    #[test]
+    fn should_reject_senders_without_balance() {
+        let blockchain = Blockchain::new("http://127.0.0.1:8545", None).unwrap();
+        let sender = *tx(1).sender();
+        let tx = decode(&tx(1)).unwrap();
+        blockchain.mock_balance_and_nonce(sender, 0.into(), 0.into());
+
+        let (balance, nonce) = blockchain.balance_and_nonce(sender).wait().unwrap();
+        assert_eq!(
+            rejection(verify_state(&tx, balance, nonce, &Default::default(), None).unwrap_err()),
+            Value::String("Sender has no balance.".into())
+        );
+    }
+
+    #[test]
+    fn should_reject_gas_cost_exceeding_balance() {
+        let tx = decode(&tx(1)).unwrap();
+        let gas_cost = transaction_cost(0.into(), tx.gas, tx.gas_price).unwrap();
+        assert!(!tx.value.is_zero());
+
+        assert_eq!(
+            rejection(verify_state(&tx, gas_cost - U256::from(1), 0.into(), &Default::default(), None).unwrap_err()),
+            Value::String(format!("Gas cost exceeds balance (required: {}, got: {})", gas_cost, gas_cost - U256::from(1)))
+        );
+        // Gas is covered, but the value isn't.
+        assert_eq!(
+            rejection(verify_state(&tx, gas_cost, 0.into(), &Default::default(), None).unwrap_err()),
+            Value::String(format!("Insufficient balance (required: {}, got: {})", gas_cost + tx.value, gas_cost))
+        );
+    }
+
+    #[test]
+    fn should_verify_balance_of_type_2_transactions_at_max_fee() {
+        use rlp::RlpStream;
+
+        let tx = decode(&tx(0)).unwrap();
+        let gwei = U256::from(1_000_000_000);
+        let mut stream = RlpStream::new_list(12);
+        stream
+            .append(&1u64).append(&tx.nonce).append(&gwei).append(&(gwei * U256::from(100)))
+            .append(&tx.gas).append(&Address::from(1)).append(&tx.value).append(&Vec::<u8>::new());
+        stream.begin_list(0);
+        stream.append(&0u8).append(&0u8).append(&0u8);
+        let mut typed = vec![0x02];
+        typed.extend_from_slice(stream.as_raw());
+
+        // Affordable at the price reported by the decoder, but not at the max fee.
+        let balance = transaction_cost(tx.value, tx.gas, tx.gas_price).unwrap();
+        let max_gas_price = worst_case_gas_price(&typed, &tx).unwrap();
+        assert_eq!(max_gas_price, gwei * U256::from(100));
+        assert!(verify_state(&tx, balance, 0.into(), &Default::default(), None).is_ok());
+        assert_eq!(
+            rejection(verify_state_at(&tx, max_gas_price, balance, 0.into(), &Default::default(), None).unwrap_err()),
+            Value::String(format!("Gas cost exceeds balance (required: {}, got: {})", tx.gas * max_gas_price, balance))
+        );
+        // Legacy transactions pay their gas price.
+        assert_eq!(worst_case_gas_price(&raw(0), &tx).unwrap(), tx.gas_price);
+    }
+
+    #[test]
+    fn should_compute_transaction_cost() {
+        assert_eq!(transaction_cost(5.into(), 21_000.into(), 2.into()), Some(42_005.into()));
+    }
+
+    #[test]
+    fn should_detect_cost_overflow() {
+        let half = U256::max_value() / U256::from(2) + U256::from(1);
+        assert_eq!(transaction_cost(0.into(), half, 2.into()), None);
+        assert_eq!(transaction_cost(U256::max_value(), 1.into(), 1.into()), None);
+
+        // Stored transactions are re-verified without going through `verify_transaction`.
+        let tx = UnsignedTransaction {
+            nonce: 0.into(),
+            gas_price: 2.into(),
+            gas: half,
+            action: Action::Create,
+            value: 0.into(),
+            data: vec![],
+        }.fake_sign(Address::from(1));
+        let err = verify_state(&tx, U256::max_value(), 0.into(), &Default::default(), None).unwrap_err();
+        assert_eq!(rejection(err), Value::String("Transaction cost overflows.".into()));
+    }
+
+    #[test]
+    fn should_require_tip_on_top_of_base_fee() {
+        let gwei = U256::from(1_000_000_000);
+        let (base_fee, min_tip) = (gwei * U256::from(30), gwei * U256::from(2));
+        let hash = *tx(0).hash();
+
+        assert!(check_tip(gwei * U256::from(32), None, base_fee, min_tip, hash).is_ok());
+        assert_eq!(
+            rejection(check_tip(gwei * U256::from(31), None, base_fee, min_tip, hash).unwrap_err()),
+            Value::String(format!("Gas price is too low. Required: {} wei (base fee + tip)", gwei * U256::from(32)))
+        );
+    }
+
+    #[test]
+    fn should_require_tip_of_type_2_transactions() {
+        let gwei = U256::from(1_000_000_000);
+        let (base_fee, min_tip) = (gwei * U256::from(30), gwei * U256::from(2));
+        let hash = *tx(0).hash();
+        let tip = |max_priority_fee: u64, max_fee: u64| {
+            let (typed, _) = type_2(gwei * U256::from(max_priority_fee), gwei * U256::from(max_fee));
+            let fees = dynamic_fees(&typed).unwrap();
+            // The gas price reported by the decoder doesn't matter for type-2 transactions.
+            check_tip(gwei * U256::from(1_000), fees, base_fee, min_tip, hash)
+        };
+
+        assert!(tip(2, 32).is_ok());
+        assert!(tip(5, 40).is_ok());
+        // priority fee below the tip
+        assert_eq!(
+            rejection(tip(1, 40).unwrap_err()),
+            Value::String(format!("Priority fee is too low. Required: {} wei (tip on top of base fee)", min_tip))
+        );
+        // max fee leaves less than the tip on top of the base fee
+        assert!(tip(5, 31).is_err());
+        assert!(tip(5, 20).is_err());
+    }
+
+    #[test]
+    fn should_project_base_fee() {
+        let gwei = U256::from(1_000_000_000);
+        assert!(covers_base_fee(gwei * U256::from(20), gwei * U256::from(10), 0));
+        assert!(covers_base_fee(gwei * U256::from(20), gwei * U256::from(10), 5));
+        // 10 * 1.125^6 > 20
+        assert!(!covers_base_fee(gwei * U256::from(20), gwei * U256::from(10), 6));
+        assert!(!covers_base_fee(gwei * U256::from(20), gwei * U256::from(10), 70_000));
+        assert!(!covers_base_fee(gwei, gwei * U256::from(2), 0));
+    }
+
+    #[test]
+    fn should_reject_underpriced_transactions_if_enabled() {
+        let dir = TempDir::new("verifier").unwrap();
+        let blockchain = Arc::new(Blockchain::new("http://127.0.0.1:8545", None).unwrap());
+        let database = Arc::new(Database::open(dir.path(), 1).unwrap());
+        let gwei = U256::from(1_000_000_000);
+        blockchain.mock_base_fee(gwei * U256::from(10));
+        let hash = *tx(0).hash();
+
+        let verifier = Verifier::new_block(blockchain.clone(), database.clone(), Options { reject_underpriced: true, ..options() });
+        assert!(verifier.verify_base_fee(5, gwei * U256::from(20), hash).wait().is_ok());
+        assert_eq!(
+            rejection(verifier.verify_base_fee(70_000, gwei * U256::from(20), hash).wait().unwrap_err()),
+            Value::String("Gas price may not cover the base fee at target block.".into())
+        );
+
+        // Only warns otherwise.
+        let verifier = Verifier::new_block(blockchain, database, Options { warn_underpriced: true, ..options() });
+        assert!(verifier.verify_base_fee(70_000, gwei * U256::from(20), hash).wait().is_ok());
+    }
+
+    #[test]
+    fn should_report_rejections() {
+        let dir = TempDir::new("verifier").unwrap();
+        let blockchain = Arc::new(Blockchain::new("http://127.0.0.1:8545", None).unwrap());
+        let database = Arc::new(Database::open(dir.path(), 1).unwrap());
+        let (sink, rejections) = mpsc::channel(16);
+        let verifier = Verifier::new_block(blockchain, database, options()).with_rejections(sink);
+
+        // Block is too high (latest block is 0)
+        verifier.verify(1_000_000, Bytes::new(raw(0))).wait().unwrap_err();
+        drop(verifier);
+
+        assert_eq!(rejections.collect().wait().unwrap(), vec![Rejection {
+            sender: Some(*tx(0).sender()),
+            reason: "Block number is too high: 1000000 > 70000".into(),
+            num: 1_000_000,
+        }]);
+    }
+}